@@ -0,0 +1,414 @@
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use crate::connection::{non_zero, Phase};
+use crate::context::parser::{request_line_version, RequestParser, DEFAULT_HOST};
+use crate::listen::BoundAddr;
+use crate::context::{Context, HttpResponseType, HttpVersion, Request, Response};
+use crate::{proxy, threadpool, HttpListener, OverloadPolicy, ServerError, ServerHandle, Settings};
+use crate::{ACCEPT_BACKOFF_MAX, ACCEPT_BACKOFF_MIN};
+
+/// An async request handler, as registered with `HttpListener::route_async`.
+pub type AsyncHandler = Arc<dyn Fn(Request) -> Pin<Box<dyn Future<Output = Response> + Send>> + Send + Sync>;
+
+impl HttpListener {
+    /// Routes paths matching the regular expression `pattern` to an async handler.
+    ///
+    /// Async routes are only served by `serve_async`, where they are matched before the
+    /// routes registered with `route`.
+    pub fn route_async<F, Fut>(&mut self, pattern: &str, handler: F)
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static {
+        let handler: AsyncHandler = Arc::new(move |request| Box::pin(handler(request)));
+        self.async_routes.insert(String::from(pattern), handler);
+    }
+
+    /// Serves connections on the current tokio runtime until the server is shut down
+    /// through its `ServerHandle`, then returns that handle.
+    ///
+    /// Each connection is a task rather than a pooled thread, so idle keep-alive
+    /// connections cost no thread. Routes registered with `route` and static files still
+    /// run their blocking code, on tokio's blocking threads. The timeouts, connection
+    /// limits and overload policy apply as they do for `start`.
+    ///
+    /// Some features are only served by `start` and `spawn`: TLS (a listener with TLS
+    /// configured is refused), HTTP/2, PROXY protocol headers, WebSocket and event stream
+    /// routes, and `expect_continue` checks. Here requests to WebSocket and event stream
+    /// paths fall through to the ordinary routes, and `Expect: 100-continue` gets no
+    /// interim response.
+    ///
+    /// ```no_run
+    /// # use rweblet::{HttpListener, Request, Response};
+    /// async fn hello(request: Request) -> Response {
+    ///     Response::ok_text(format!("Hello {}", request.path).as_str())
+    /// }
+    ///
+    /// let mut listener = HttpListener::new();
+    /// listener.route_async("^/hello", hello);
+    /// let runtime = tokio::runtime::Runtime::new().unwrap();
+    /// runtime.block_on(listener.serve_async("0.0.0.0:8080")).unwrap();
+    /// ```
+    pub async fn serve_async(&self, uri: &str) -> Result<ServerHandle, ServerError> {
+        self.check_routes()?;
+        #[cfg(feature = "tls")]
+        {
+            if self.tls.is_some() {
+                return Err(ServerError::Config(String::from("serve_async does not support TLS, use start or spawn")));
+            }
+        }
+        let listener = match TcpListener::bind(uri).await {
+            Ok(listener) => listener,
+            Err(e) => return Err(ServerError::Bind(String::from(uri), e)),
+        };
+        let local_addr = listener.local_addr().map_err(ServerError::Io)?;
+        let id = self.handle.started(vec![BoundAddr::Tcp(local_addr)]);
+
+        accept_loop(id, listener, self.server_settings()).await;
+        Ok(self.handle())
+    }
+}
+
+async fn accept_loop(id: usize, listener: TcpListener, settings: Arc<Settings>) {
+    let handle = settings.server.clone();
+    let slots = Arc::new(Semaphore::new(settings.max_connections));
+    let mut connections = JoinSet::new();
+    let mut backoff = ACCEPT_BACKOFF_MIN;
+
+    loop {
+        //A shutdown may have been requested before this listener started
+        if handle.is_shutting_down() {
+            break;
+        }
+        //When blocking, wait for room before accepting so new clients stay in the backlog
+        let reserved = match settings.overload {
+            OverloadPolicy::Block => match acquire(&slots, &handle).await {
+                Some(permit) => Some(permit),
+                None => break,
+            },
+            OverloadPolicy::Reject(_) => None,
+        };
+        let accepted = listener.accept().await;
+        if handle.is_shutting_down() {
+            break;
+        }
+        let stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                HttpListener::log(format!("Failed to accept connection: {}", e).as_str());
+                tokio::time::sleep(backoff).await;
+                backoff = std::cmp::min(backoff * 2, ACCEPT_BACKOFF_MAX);
+                continue;
+            }
+        };
+        backoff = ACCEPT_BACKOFF_MIN;
+
+        let permit = match (reserved, settings.overload) {
+            (Some(permit), _) => permit,
+            (None, OverloadPolicy::Reject(retry_after)) => match Arc::clone(&slots).try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    HttpListener::log("Server overloaded, rejecting connection");
+                    connections.spawn(reject(stream, retry_after));
+                    continue;
+                }
+            },
+            (None, OverloadPolicy::Block) => continue,
+        };
+
+        let settings = Arc::clone(&settings);
+        connections.spawn(async move {
+            serve(stream, settings).await;
+            drop(permit);
+        });
+        while connections.try_join_next().is_some() {}
+    }
+
+    //Close the port, then wait for the open connections to finish
+    drop(listener);
+    while connections.join_next().await.is_some() {}
+    if let Some(executor) = settings.executor.clone() {
+        let _ = tokio::task::spawn_blocking(move || executor.shutdown()).await;
+    }
+    handle.stopped(id);
+}
+
+/// Waits for a connection slot, giving up with `None` once the server is shutting down.
+async fn acquire(slots: &Arc<Semaphore>, handle: &ServerHandle) -> Option<OwnedSemaphorePermit> {
+    loop {
+        if handle.is_shutting_down() {
+            return None;
+        }
+        if let Ok(Ok(permit)) = tokio::time::timeout(Duration::from_millis(100), Arc::clone(slots).acquire_owned()).await {
+            return Some(permit);
+        }
+    }
+}
+
+/// Serves every request sent on one client connection, as `connection::serve` does for
+/// the blocking backend.
+async fn serve(stream: TcpStream, settings: Arc<Settings>) {
+    let default_host = match stream.local_addr() {
+        Ok(addr) => addr.to_string(),
+        Err(_) => String::from(DEFAULT_HOST),
+    };
+    //Shutdown closes idle connections through a blocking handle on the same socket
+    let stream = match stream.into_std() {
+        Ok(stream) => stream,
+        Err(_) => return,
+    };
+    let connection = match settings.server.register(&stream) {
+        Some(connection) => connection,
+        None => return,
+    };
+    let mut stream = match TcpStream::from_std(stream) {
+        Ok(stream) => stream,
+        Err(_) => return,
+    };
+
+    let peer = stream.peer_addr().ok();
+    let mut parser = RequestParser::new(&default_host);
+    let mut buffer = vec![0; 8192];
+    let mut served: usize = 0;
+    let mut phase = Phase::Idle;
+    let mut phase_started = Instant::now();
+
+    loop {
+        match parser.next_request() {
+            Ok(Some(mut request)) => {
+                proxy::resolve(&mut request, peer, &settings.trusted_proxies);
+                served += 1;
+                let keep_alive = request.keep_alive
+                    && served < settings.max_requests
+                    && settings.keep_alive_timeout.as_millis() > 0;
+
+                let (keep_alive, output) = respond(request, peer, keep_alive, &settings, served).await;
+                if !write(&mut stream, &output, settings.write_timeout).await || !keep_alive {
+                    return;
+                }
+                phase = Phase::Idle;
+                phase_started = Instant::now();
+                continue;
+            },
+            Ok(None) => (),
+            Err(e) => {
+                //The stream cannot be resynchronised after a parse error, so answer and close
+                HttpListener::log(format!("{}", e).as_str());
+                write_error(&mut stream, e.response(), parser.pending_version(), &settings).await;
+                return;
+            },
+        }
+
+        let remaining = match phase.timeout(served, &settings).checked_sub(phase_started.elapsed()) {
+            Some(remaining) if remaining.as_millis() > 0 => remaining,
+            _ => {
+                if phase != Phase::Idle {
+                    HttpListener::log("Timed out reading request");
+                    write_error(&mut stream, Response::error(HttpResponseType::RequestTimeout), parser.pending_version(), &settings).await;
+                } else {
+                    HttpListener::log("Closing idle connection");
+                }
+                return;
+            }
+        };
+        //Between requests the connection may be closed by a shutdown
+        if phase == Phase::Idle && !connection.set_idle(true) {
+            return;
+        }
+
+        match tokio::time::timeout(remaining, stream.read(&mut buffer)).await {
+            Err(_) => (),
+            Ok(Ok(0)) => return,
+            Ok(Ok(read_size)) => {
+                connection.set_idle(false);
+                parser.feed(&buffer[0..read_size]);
+                let current = if parser.awaiting_body() { Phase::Body } else { Phase::Head };
+                if current != phase {
+                    if phase.restarts_deadline(current, served) {
+                        phase_started = Instant::now();
+                    }
+                    phase = current;
+                }
+            },
+            Ok(Err(ref e)) if e.kind() == ErrorKind::Interrupted => (),
+            Ok(Err(_)) => { HttpListener::log("Failed to read from stream"); return; },
+        }
+    }
+}
+
+/// Runs the handler for `request` and returns the encoded response, along with whether
+/// the connection stays open after it.
+async fn respond(request: Request, peer: Option<SocketAddr>, keep_alive: bool, settings: &Arc<Settings>, served: usize) -> (bool, Vec<u8>) {
+    let handler = settings.async_routes.find(&request.path).map(Arc::clone);
+
+    if let Some(handler) = handler {
+        let path = request.path.clone();
+        //The handler takes the request, so the context only keeps the version to answer in
+        let mut context = Context::buffered(Request::empty());
+        context.request.version = request.version;
+        context.keep_alive = keep_alive;
+        context.server = Some(settings.server.clone());
+        context.peer_addr = peer;
+
+        //A panicking handler answers 500, as for blocking handlers
+        let response = match tokio::spawn(handler(request)).await {
+            Ok(response) => response,
+            Err(e) => {
+                if let Ok(payload) = e.try_into_panic() {
+                    HttpListener::log_error(format!("Handler for {} panicked: {}", path, threadpool::panic_message(&payload)).as_str());
+                }
+                context.keep_alive = false;
+                Response::internal_error()
+            }
+        };
+        context.write_response(response);
+        return (context.keep_alive, context.into_output());
+    }
+
+    let settings = Arc::clone(settings);
+    let blocking = tokio::task::spawn_blocking(move || {
+        let mut context = Context::buffered(request);
+        context.keep_alive = keep_alive;
+        context.peer_addr = peer;
+        context.server = Some(settings.server.clone());
+        context.executor = settings.executor.clone();
+        HttpListener::process(&mut context, settings, served);
+        (context.keep_alive, context.into_output())
+    });
+    blocking.await.unwrap_or((false, Vec::new()))
+}
+
+/// Writes `data`, returning false if the connection failed or the write timed out.
+async fn write(stream: &mut TcpStream, data: &[u8], timeout: Duration) -> bool {
+    let written = match non_zero(timeout) {
+        Some(timeout) => match tokio::time::timeout(timeout, stream.write_all(data)).await {
+            Ok(result) => result,
+            Err(_) => return false,
+        },
+        None => stream.write_all(data).await,
+    };
+    written.is_ok()
+}
+
+/// Answers a request that could not be served, in the version its client used, and
+/// lets the connection close.
+async fn write_error(stream: &mut TcpStream, response: Response, version: HttpVersion, settings: &Settings) {
+    let mut context = Context::buffered(Request::empty());
+    context.request.version = version;
+    context.write_response(response);
+    write(stream, &context.into_output(), settings.write_timeout).await;
+}
+
+/// Answers a connection the server has no room for without reading its request.
+async fn reject(mut stream: TcpStream, retry_after: Duration) {
+    //Closing with unread data would reset the connection and lose the response
+    let mut discard = [0; 4096];
+    let mut version = None;
+    while let Ok(read_size) = stream.try_read(&mut discard) {
+        if read_size == 0 {
+            break;
+        }
+        version.get_or_insert_with(|| request_line_version(&discard[..read_size]));
+    }
+    let mut context = Context::buffered(Request::empty());
+    context.request.version = version.unwrap_or(HttpVersion::Http11);
+    context.write_response(Response::service_unavailable(retry_after));
+    write(&mut stream, &context.into_output(), Duration::from_millis(100)).await;
+    let _ = stream.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::prelude::*;
+    use std::net::SocketAddr;
+    use std::thread;
+
+    async fn hello(request: Request) -> Response {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        Response::ok_text(format!("async {}", request.path).as_str())
+    }
+    async fn broken(_request: Request) -> Response {
+        panic!("async handler bug");
+    }
+    fn blocking(_context: &Context) -> Response {
+        Response::ok_text("blocking")
+    }
+
+    fn start() -> (tokio::runtime::Runtime, ServerHandle, SocketAddr) {
+        let mut listener = HttpListener::new();
+        listener.route_async("^/hello", hello);
+        listener.route_async("^/broken", broken);
+        listener.route("^/blocking", blocking);
+        let handle = listener.handle();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(async move { listener.serve_async("127.0.0.1:0").await });
+        let started = Instant::now();
+        while handle.local_addr().is_none() && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let addr = handle.local_addr().unwrap();
+        (runtime, handle, addr)
+    }
+
+    fn get(addr: SocketAddr, request: &str) -> String {
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_async_and_blocking_routes() {
+        let (_runtime, handle, addr) = start();
+
+        let response = get(addr, "GET /hello HTTP/1.1\r\nHost: a\r\n\r\nGET /blocking HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.find("async /hello").unwrap() < response.find("blocking").unwrap());
+        assert!(get(addr, "GET /missing HTTP/1.0\r\n\r\n").starts_with("HTTP/1.0 404"));
+        handle.shutdown();
+    }
+    #[test]
+    fn test_http10_async_route() {
+        let (_runtime, handle, addr) = start();
+
+        let response = get(addr, "GET /hello HTTP/1.0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.0 200 OK"));
+        assert!(response.ends_with("async /hello"));
+        assert!(get(addr, "GET /broken HTTP/1.0\r\n\r\n").starts_with("HTTP/1.0 500"));
+        assert!(get(addr, "GET /hello HTTP/1.0\r\nContent-Length: x\r\n\r\n").starts_with("HTTP/1.0 400"));
+        handle.shutdown();
+    }
+    #[test]
+    fn test_async_handler_panic_answers_500() {
+        let (_runtime, handle, addr) = start();
+
+        let response = get(addr, "GET /broken HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 500"));
+        assert!(response.contains("Connection: close"));
+        handle.shutdown();
+    }
+    #[test]
+    fn test_shutdown_closes_idle_connections() {
+        let (_runtime, handle, addr) = start();
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /hello HTTP/1.1\r\n\r\n").unwrap();
+        let mut buffer = [0; 1024];
+        assert!(client.read(&mut buffer).unwrap() > 0);
+
+        let started = Instant::now();
+        handle.shutdown();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(std::net::TcpStream::connect(addr).is_err());
+    }
+}
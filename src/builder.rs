@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::time::Duration;
+use crate::{Context, HttpListener, OverloadPolicy, Response, ServerError, ServerHandle, WebSocketHandler, EventStreamHandler, ExpectHandler};
+use crate::proxy::Network;
+#[cfg(feature = "tls")]
+use crate::TlsConfig;
+
+/// Configures an `HttpListener`, checking the whole configuration when it is built.
+///
+/// ```no_run
+/// # use rweblet::{HttpListener, Context, Response};
+/// fn index(_context: &Context) -> Response { Response::ok_text("Hello") }
+///
+/// let server = HttpListener::builder()
+///     .threads(8)
+///     .webroot("www")
+///     .route("^/$", index)
+///     .bind("0.0.0.0:8080")
+///     .unwrap();
+/// server.wait();
+/// ```
+pub struct HttpListenerBuilder {
+    listener: HttpListener,
+    routes: HashMap<String, fn(&Context) -> Response>,
+    trusted_proxies: Vec<String>,
+}
+
+impl HttpListenerBuilder {
+    pub(crate) fn new() -> HttpListenerBuilder {
+        HttpListenerBuilder {
+            listener: HttpListener::new(),
+            routes: HashMap::new(),
+            trusted_proxies: Vec::new(),
+        }
+    }
+
+    /// Number of worker threads serving connections. Must be at least 1.
+    pub fn threads(mut self, thread_count: usize) -> Self {
+        self.listener.min_threads = thread_count;
+        self.listener.max_threads = thread_count;
+        self
+    }
+    /// See `HttpListener::thread_range`. `max` must be at least 1 and not below `min`.
+    pub fn thread_range(mut self, min: usize, max: usize) -> Self {
+        self.listener.min_threads = min;
+        self.listener.max_threads = max;
+        self
+    }
+    /// See `HttpListener::background_threads`. Must be at least 1.
+    pub fn background_threads(mut self, count: usize) -> Self {
+        self.listener.background_threads = count;
+        self
+    }
+    /// See `HttpListener::thread_idle_timeout`.
+    pub fn thread_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.listener.thread_idle_timeout = timeout;
+        self
+    }
+    /// Directory static files are served from. Must exist.
+    pub fn webroot(mut self, webroot: &str) -> Self {
+        self.listener.webroot = String::from(webroot);
+        self
+    }
+    /// See `HttpListener::keep_alive`.
+    pub fn keep_alive(mut self, timeout: Duration) -> Self {
+        self.listener.keep_alive_timeout = timeout;
+        self
+    }
+    /// See `HttpListener::header_timeout`. Must not be zero.
+    pub fn header_timeout(mut self, timeout: Duration) -> Self {
+        self.listener.header_timeout = timeout;
+        self
+    }
+    /// See `HttpListener::body_timeout`. Must not be zero.
+    pub fn body_timeout(mut self, timeout: Duration) -> Self {
+        self.listener.body_timeout = timeout;
+        self
+    }
+    /// See `HttpListener::write_timeout`.
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.listener.write_timeout = timeout;
+        self
+    }
+    /// See `HttpListener::max_requests`. Must be at least 1.
+    pub fn max_requests(mut self, count: usize) -> Self {
+        self.listener.max_requests = count;
+        self
+    }
+    /// See `HttpListener::max_connections`. Must be at least 1.
+    pub fn max_connections(mut self, count: usize) -> Self {
+        self.listener.max_connections = count;
+        self
+    }
+    /// See `HttpListener::queue_size`. Must be at least 1.
+    pub fn queue_size(mut self, size: usize) -> Self {
+        self.listener.queue_size = size;
+        self
+    }
+    /// See `HttpListener::overload`.
+    pub fn overload(mut self, policy: OverloadPolicy) -> Self {
+        self.listener.overload = policy;
+        self
+    }
+    /// See `HttpListener::unix_socket_mode`.
+    #[cfg(unix)]
+    pub fn unix_socket_mode(mut self, mode: u32) -> Self {
+        self.listener.unix_socket_mode(mode);
+        self
+    }
+    /// See `HttpListener::remove_stale_socket`.
+    #[cfg(unix)]
+    pub fn remove_stale_socket(mut self, remove: bool) -> Self {
+        self.listener.remove_stale_socket(remove);
+        self
+    }
+    /// See `HttpListener::tls`.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: TlsConfig) -> Self {
+        self.listener.tls(config);
+        self
+    }
+    /// See `HttpListener::http2`.
+    #[cfg(feature = "http2")]
+    pub fn http2(mut self, enabled: bool) -> Self {
+        self.listener.http2(enabled);
+        self
+    }
+    /// See `HttpListener::hsts`.
+    pub fn hsts(mut self, max_age: Duration, include_subdomains: bool) -> Self {
+        self.listener.hsts(max_age, include_subdomains);
+        self
+    }
+    /// Serves under an existing server's handle, see `HttpListener::share_handle`.
+    pub fn handle(mut self, handle: &ServerHandle) -> Self {
+        self.listener.share_handle(handle);
+        self
+    }
+    /// Routes paths matching the regular expression `pattern` to `callback`.
+    pub fn route(mut self, pattern: &str, callback: fn(request: &Context) -> Response) -> Self {
+        self.routes.insert(String::from(pattern), callback);
+        self
+    }
+    /// Serves WebSockets on paths matching `pattern`, see `HttpListener::websocket`.
+    pub fn websocket(mut self, pattern: &str, handler: WebSocketHandler) -> Self {
+        self.listener.websocket(pattern, handler);
+        self
+    }
+    /// See `HttpListener::websocket_max_message_size`. Must be at least 1.
+    pub fn websocket_max_message_size(mut self, bytes: usize) -> Self {
+        self.listener.websocket_max_message_size = bytes;
+        self
+    }
+    /// Serves Server-Sent Events on paths matching `pattern`, see `HttpListener::event_stream`.
+    pub fn event_stream(mut self, pattern: &str, handler: EventStreamHandler) -> Self {
+        self.listener.event_stream(pattern, handler);
+        self
+    }
+    /// See `HttpListener::event_stream_keep_alive`. Must not be zero.
+    pub fn event_stream_keep_alive(mut self, interval: Duration) -> Self {
+        self.listener.event_stream_keep_alive = interval;
+        self
+    }
+    /// See `HttpListener::proxy_protocol`.
+    pub fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.listener.proxy_protocol(enabled);
+        self
+    }
+    /// See `HttpListener::trusted_proxies`. Each entry must be an address or CIDR network.
+    pub fn trusted_proxies(mut self, proxies: &[&str]) -> Self {
+        self.trusted_proxies = proxies.iter().map(|proxy| String::from(*proxy)).collect();
+        self
+    }
+    /// Checks uploads to paths matching `pattern` before reading their body, see
+    /// `HttpListener::expect_continue`.
+    pub fn expect_continue(mut self, pattern: &str, check: ExpectHandler) -> Self {
+        self.listener.expect_continue(pattern, check);
+        self
+    }
+    /// Routes paths matching `pattern` to an async handler, see `HttpListener::route_async`.
+    #[cfg(feature = "tokio")]
+    pub fn route_async<F, Fut>(mut self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(crate::Request) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Response> + Send + 'static {
+        self.listener.route_async(pattern, handler);
+        self
+    }
+
+    /// Checks the configuration and returns the listener, ready for `start` or `spawn`.
+    pub fn build(self) -> Result<HttpListener, ServerError> {
+        let mut listener = self.listener;
+
+        if listener.max_threads == 0 {
+            return Err(ServerError::Config(String::from("threads must be at least 1")));
+        }
+        if listener.min_threads > listener.max_threads {
+            return Err(ServerError::Config(String::from("thread_range minimum is above the maximum")));
+        }
+        if listener.background_threads == 0 {
+            return Err(ServerError::Config(String::from("background_threads must be at least 1")));
+        }
+        if listener.max_requests == 0 {
+            return Err(ServerError::Config(String::from("max_requests must be at least 1")));
+        }
+        if listener.max_connections == 0 {
+            return Err(ServerError::Config(String::from("max_connections must be at least 1")));
+        }
+        if listener.queue_size == 0 {
+            return Err(ServerError::Config(String::from("queue_size must be at least 1")));
+        }
+        if listener.websocket_max_message_size == 0 {
+            return Err(ServerError::Config(String::from("websocket_max_message_size must be at least 1")));
+        }
+        if listener.event_stream_keep_alive.as_millis() == 0 {
+            return Err(ServerError::Config(String::from("event_stream_keep_alive must not be zero")));
+        }
+        if listener.header_timeout.as_millis() == 0 {
+            return Err(ServerError::Config(String::from("header_timeout must not be zero")));
+        }
+        if listener.body_timeout.as_millis() == 0 {
+            return Err(ServerError::Config(String::from("body_timeout must not be zero")));
+        }
+        if !listener.webroot.is_empty() && !Path::new(&listener.webroot).is_dir() {
+            return Err(ServerError::Config(format!("webroot {} is not a directory", listener.webroot)));
+        }
+        if let Some(proxy) = self.trusted_proxies.iter().find(|proxy| Network::parse(proxy).is_none()) {
+            return Err(ServerError::Config(format!("trusted_proxies entry {} is not an address or network", proxy)));
+        }
+        let proxies: Vec<&str> = self.trusted_proxies.iter().map(String::as_str).collect();
+        listener.trusted_proxies(&proxies);
+        for (pattern, callback) in self.routes {
+            listener.route(&pattern, callback);
+        }
+        listener.check_routes()?;
+        Ok(listener)
+    }
+
+    /// Builds the listener and serves `uri` from a background thread, as `HttpListener::spawn`.
+    pub fn bind(self, uri: &str) -> Result<ServerHandle, ServerError> {
+        self.bind_all(&[uri])
+    }
+    /// Builds the listener and serves every address in `uris`, as `HttpListener::spawn_all`.
+    pub fn bind_all(self, uris: &[&str]) -> Result<ServerHandle, ServerError> {
+        for uri in uris {
+            if !uri.starts_with(crate::listen::UNIX_PREFIX) && uri.to_socket_addrs().is_err() {
+                return Err(ServerError::Config(format!("{} is not a valid address", uri)));
+            }
+        }
+        self.build()?.spawn_all(uris)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(_context: &Context) -> Response {
+        Response::ok_text("index")
+    }
+
+    fn config_error(builder: HttpListenerBuilder) -> String {
+        match builder.build() {
+            Err(ServerError::Config(message)) => message,
+            _ => panic!("expected a configuration error"),
+        }
+    }
+
+    #[test]
+    fn test_build_validates_configuration() {
+        assert!(config_error(HttpListener::builder().threads(0)).contains("threads"));
+        assert!(config_error(HttpListener::builder().thread_range(4, 2)).contains("thread_range"));
+        assert!(config_error(HttpListener::builder().background_threads(0)).contains("background_threads"));
+        assert!(config_error(HttpListener::builder().max_requests(0)).contains("max_requests"));
+        assert!(config_error(HttpListener::builder().max_connections(0)).contains("max_connections"));
+        assert!(config_error(HttpListener::builder().queue_size(0)).contains("queue_size"));
+        assert!(config_error(HttpListener::builder().header_timeout(Duration::from_secs(0))).contains("header_timeout"));
+        assert!(config_error(HttpListener::builder().webroot("/no/such/webroot")).contains("webroot"));
+        assert!(config_error(HttpListener::builder().route("^/(unclosed", index)).contains("route"));
+        assert!(config_error(HttpListener::builder().trusted_proxies(&["10.0.0.0/8", "lb.internal"])).contains("trusted_proxies"));
+    }
+    #[test]
+    fn test_build_applies_configuration() {
+        let listener = HttpListener::builder()
+            .threads(3)
+            .max_requests(7)
+            .route("^/$", index)
+            .build()
+            .unwrap();
+        assert_eq!((listener.min_threads, listener.max_threads), (3, 3));
+        assert_eq!(listener.max_requests, 7);
+        assert_eq!(listener.routing_table.len(), 1);
+    }
+    #[test]
+    fn test_bind_rejects_invalid_address() {
+        assert!(matches!(HttpListener::builder().bind("not an address"), Err(ServerError::Config(_))));
+    }
+    #[test]
+    fn test_spawn_rejects_invalid_route() {
+        let mut listener = HttpListener::new();
+        listener.route("^/(unclosed", index);
+        assert!(matches!(listener.spawn("127.0.0.1:0"), Err(ServerError::Config(_))));
+    }
+    #[test]
+    fn test_bind_serves_in_background() {
+        let handle = HttpListener::builder().threads(1).route("^/$", index).bind("127.0.0.1:0").unwrap();
+        assert_ne!(handle.local_addr().unwrap().port(), 0);
+        handle.shutdown();
+    }
+    #[test]
+    fn test_pool_metrics_while_running() {
+        let handle = HttpListener::builder().thread_range(1, 4).route("^/$", index).bind("127.0.0.1:0").unwrap();
+        let metrics = handle.pool_metrics().unwrap();
+        assert_eq!(metrics.workers, 1);
+        assert_eq!(metrics.queued, 0);
+        handle.shutdown();
+        assert!(handle.pool_metrics().is_none());
+    }
+}
@@ -0,0 +1,424 @@
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::context::{Context, HttpVersion, Request, Response, HttpResponseType};
+use crate::context::parser::{request_line_version, RequestParser, DEFAULT_HOST};
+use crate::transport::Transport;
+use crate::{event_stream, proxy, websocket};
+use crate::expect::{self, Expectation};
+#[cfg(feature = "http2")]
+use crate::http2::{self, Preface};
+use crate::{HttpListener, Settings};
+
+/// Longest the accept thread may spend writing a rejection.
+const REJECT_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+/// Longest a connection stays open after refusing a request whose body may still be
+/// on its way, to let the client read the response.
+const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What the connection is currently waiting for, which decides the read timeout.
+#[derive(PartialEq, Clone, Copy)]
+pub(crate) enum Phase {
+    /// Waiting for the first byte of the next request.
+    Idle,
+    /// Part of a request line or header section has arrived.
+    Head,
+    /// The headers are complete and the body is still arriving.
+    Body,
+}
+
+impl Phase {
+    /// How long the connection may stay in this phase, once `served` requests are done.
+    pub(crate) fn timeout(self, served: usize, settings: &Settings) -> Duration {
+        match self {
+            Phase::Idle if served > 0 => settings.keep_alive_timeout,
+            Phase::Idle | Phase::Head => settings.header_timeout,
+            Phase::Body => settings.body_timeout,
+        }
+    }
+
+    /// Whether moving on to `next` starts a new deadline. The first request's head has to
+    /// arrive within `header_timeout` of connecting, so its first byte does not restart it.
+    pub(crate) fn restarts_deadline(self, next: Phase, served: usize) -> bool {
+        !(self == Phase::Idle && next == Phase::Head && served == 0)
+    }
+}
+
+/// Serves every request sent on one client connection, after reading its PROXY protocol
+/// header and terminating TLS as the listener is configured to.
+///
+/// Requests are parsed out of the connection as they arrive, so several pipelined
+/// requests in one segment are answered in order. The connection stays open until the
+/// client asks for `Connection: close` (or uses HTTP/1.0 without keep-alive), the
+/// per-connection request limit is reached, or a timeout expires.
+///
+/// The header and body timeouts are deadlines for the whole phase rather than per read,
+/// so a client trickling in a byte at a time (slowloris) cannot hold a worker forever.
+/// Reads block until data or the deadline, so a waiting connection costs no CPU.
+pub(crate) fn serve(stream: Box<dyn Transport>, settings: Arc<Settings>) {
+    //Requests without a Host header are resolved against the listener address
+    let default_host = match stream.local_addr() {
+        Some(addr) => addr.to_string(),
+        None => String::from(DEFAULT_HOST),
+    };
+    if stream.set_write_timeout(non_zero(settings.write_timeout)).is_err() {
+        return;
+    }
+    let connection = match settings.server.register(stream.as_ref()) {
+        Some(connection) => connection,
+        None => return,
+    };
+    //Registered before the preamble, so a shutdown can close a client that stalls in it
+    if !connection.set_idle(true) {
+        return;
+    }
+    let mut stream = match settings.accept_transport(stream) {
+        Some(stream) => stream,
+        None => return,
+    };
+    connection.set_idle(false);
+    let secure = stream.secure();
+    let peer = stream.peer_addr();
+    let mut parser = RequestParser::new(&default_host).secure(secure);
+    let mut buffer = [0; 8192];
+    let mut served: usize = 0;
+    let mut phase = Phase::Idle;
+    let mut phase_started = Instant::now();
+    //Whether the pending request's Expect header has been dealt with
+    let mut expectation_checked = false;
+
+    loop {
+        //A client speaking HTTP/2 says so before anything else
+        #[cfg(feature = "http2")]
+        let parsed = if settings.http2 && served == 0 {
+            match http2::preface(parser.peek()) {
+                Preface::Complete => {
+                    http2::serve(stream, parser.take_buffered(), &settings, &default_host);
+                    return;
+                },
+                Preface::Partial => Ok(None),
+                Preface::Absent => parser.next_request(),
+            }
+        } else {
+            parser.next_request()
+        };
+        #[cfg(not(feature = "http2"))]
+        let parsed = parser.next_request();
+
+        match parsed {
+            Ok(Some(mut request)) => {
+                proxy::resolve(&mut request, peer, &settings.trusted_proxies);
+                //A request that arrived whole, body and all, is checked before it is routed
+                let rejection = if expectation_checked {
+                    None
+                } else {
+                    match expect::expectation(&request, &settings) {
+                        Expectation::Reject(response) => Some(response),
+                        Expectation::None | Expectation::Continue => None,
+                    }
+                };
+                //The connection is handed over for good, so nothing else is served on it
+                if rejection.is_none() {
+                    if let Some(handler) = settings.websocket_routes.find(&request.path).copied() {
+                        websocket::serve(stream, request, parser.take_buffered(), handler, &settings);
+                        return;
+                    }
+                    if let Some(handler) = settings.event_stream_routes.find(&request.path).copied() {
+                        event_stream::serve(stream, request, handler, &settings);
+                        return;
+                    }
+                }
+                served += 1;
+                let keep_alive = request.keep_alive
+                    && served < settings.max_requests
+                    && settings.keep_alive_timeout.as_millis() > 0;
+
+                let mut context = Context::with_transport(stream, request);
+                context.keep_alive = keep_alive;
+                context.server = Some(settings.server.clone());
+                context.executor = settings.executor.clone();
+                if secure {
+                    context.hsts = settings.hsts.clone();
+                }
+                match rejection {
+                    Some(response) => context.write_response(response),
+                    None => HttpListener::process(&mut context, Arc::clone(&settings), served),
+                }
+
+                //Writing the response may have decided to close after all
+                let keep_alive = context.keep_alive;
+                stream = match context.into_transport() {
+                    Some(stream) => stream,
+                    None => return,
+                };
+                if !keep_alive {
+                    return;
+                }
+                phase = Phase::Idle;
+                phase_started = Instant::now();
+                expectation_checked = false;
+                continue;
+            },
+            Ok(None) if parser.awaiting_body() && !expectation_checked => {
+                expectation_checked = true;
+                if let Some(mut request) = parser.pending_head() {
+                    proxy::resolve(&mut request, peer, &settings.trusted_proxies);
+                    match expect::expectation(&request, &settings) {
+                        Expectation::None => (),
+                        Expectation::Continue => {
+                            if stream.write_all(expect::CONTINUE).and_then(|_| stream.flush()).is_err() {
+                                HttpListener::log("Failed writing 100 Continue");
+                                return;
+                            }
+                        },
+                        Expectation::Reject(response) => {
+                            //The body may be on its way regardless, so the connection cannot be reused
+                            let mut context = Context::with_transport(stream, request);
+                            context.keep_alive = false;
+                            if secure {
+                                context.hsts = settings.hsts.clone();
+                            }
+                            context.write_response(response);
+                            if let Some(stream) = context.into_transport() {
+                                linger(stream);
+                            }
+                            return;
+                        },
+                    }
+                }
+            },
+            Ok(None) => (),
+            Err(e) => {
+                //The stream cannot be resynchronised after a parse error, so answer and close
+                HttpListener::log(format!("{}", e).as_str());
+                write_error(stream, e.response(), parser.pending_version());
+                return;
+            },
+        }
+
+        let remaining = match phase.timeout(served, &settings).checked_sub(phase_started.elapsed()) {
+            Some(remaining) if remaining.as_millis() > 0 => remaining,
+            _ => {
+                if phase != Phase::Idle {
+                    HttpListener::log("Timed out reading request");
+                    write_error(stream, Response::error(HttpResponseType::RequestTimeout), parser.pending_version());
+                } else {
+                    HttpListener::log("Closing idle connection");
+                }
+                return;
+            }
+        };
+        if stream.set_read_timeout(Some(remaining)).is_err() {
+            return;
+        }
+        //Between requests the connection may be closed by a shutdown
+        if phase == Phase::Idle && !connection.set_idle(true) {
+            return;
+        }
+
+        match stream.read(&mut buffer) {
+            Ok(0) => return,
+            Ok(read_size) => {
+                connection.set_idle(false);
+                parser.feed(&buffer[0..read_size]);
+                let current = if parser.awaiting_body() { Phase::Body } else { Phase::Head };
+                if current != phase {
+                    if phase.restarts_deadline(current, served) {
+                        phase_started = Instant::now();
+                    }
+                    phase = current;
+                }
+            },
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => (),
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(_) => { HttpListener::log("Failed to read from stream"); return; },
+        }
+    }
+}
+
+/// Answers a connection the server has no room for without reading its request.
+///
+/// Runs on the accept thread, so nothing here may block for long: whatever the client
+/// has already sent is discarded (closing with unread data would reset the connection
+/// and lose the response) and the write is bounded by a short timeout.
+pub(crate) fn reject(mut stream: Box<dyn Transport>, response: Response) {
+    let mut discard = [0; 4096];
+    let mut version = None;
+    if stream.set_nonblocking(true).is_ok() {
+        while let Ok(read_size) = stream.read(&mut discard) {
+            if read_size == 0 {
+                break;
+            }
+            version.get_or_insert_with(|| request_line_version(&discard[..read_size]));
+        }
+    }
+    if stream.set_nonblocking(false).is_err() || stream.set_write_timeout(Some(REJECT_WRITE_TIMEOUT)).is_err() {
+        return;
+    }
+    write_error(stream, response, version.unwrap_or(HttpVersion::Http11));
+}
+
+/// Discards whatever the client still sends until it closes the connection or
+/// `LINGER_TIMEOUT` passes. Closing with unread data would reset the connection, and the
+/// client could lose the response written just before.
+fn linger(mut stream: Box<dyn Transport>) {
+    let started = Instant::now();
+    let mut discard = [0; 8192];
+    loop {
+        let remaining = match LINGER_TIMEOUT.checked_sub(started.elapsed()) {
+            Some(remaining) if remaining.as_millis() > 0 => remaining,
+            _ => return,
+        };
+        if stream.set_read_timeout(Some(remaining)).is_err() {
+            return;
+        }
+        match stream.read(&mut discard) {
+            Ok(0) => return,
+            Ok(_) => (),
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(_) => return,
+        }
+    }
+}
+
+/// Answers a request that could not be served, in the version its client used, and
+/// lets the connection close.
+fn write_error(stream: Box<dyn Transport>, response: Response, version: HttpVersion) {
+    let mut context = Context::with_transport(stream, Request::empty());
+    context.request.version = version;
+    context.write_response(response);
+}
+
+/// Socket timeouts reject a zero duration, which here means no timeout.
+pub(crate) fn non_zero(duration: Duration) -> Option<Duration> {
+    if duration.as_millis() > 0 { Some(duration) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn echo_path(context: &Context) -> Response {
+        Response::ok_text(context.request.path.as_str())
+    }
+
+    /// Serves a single connection on a fresh port and returns the client end.
+    fn connect(max_requests: usize) -> TcpStream {
+        connect_with(|settings| settings.max_requests = max_requests)
+    }
+    fn connect_with<F: FnOnce(&mut Settings)>(configure: F) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut routing: HashMap<String, fn(&Context) -> Response> = HashMap::new();
+        routing.insert(String::from("^/"), echo_path);
+        let mut settings = Settings::new("", routing);
+        settings.keep_alive_timeout = Duration::from_millis(500);
+        configure(&mut settings);
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve(Box::new(stream), Arc::new(settings));
+        });
+        TcpStream::connect(addr).unwrap()
+    }
+
+    fn read_to_close(stream: &mut TcpStream) -> String {
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_pipelined_requests_are_all_answered() {
+        let mut client = connect(100);
+        client.write_all(b"GET /one HTTP/1.1\r\nHost: a\r\n\r\nGET /two HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+
+        let response = read_to_close(&mut client);
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2);
+        assert!(response.find("/one").unwrap() < response.find("/two").unwrap());
+        assert!(response.contains("Connection: keep-alive"));
+        assert!(response.ends_with("/two"));
+        assert!(response.contains("Connection: close"));
+    }
+    #[test]
+    fn test_http10_closes_by_default() {
+        let mut client = connect(100);
+        client.write_all(b"GET /old HTTP/1.0\r\n\r\n").unwrap();
+
+        let response = read_to_close(&mut client);
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("Connection: close"));
+        assert!(response.ends_with("/old"));
+    }
+    #[test]
+    fn test_http10_keep_alive_and_unsupported_version() {
+        let mut client = connect(100);
+        client.write_all(b"GET /old HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /new HTTP/2.0\r\n\r\n").unwrap();
+
+        let response = read_to_close(&mut client);
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\nConnection: keep-alive\r\n"));
+        assert!(response.contains("HTTP/1.1 505 HTTP Version Not Supported"));
+    }
+    #[test]
+    fn test_max_requests_per_connection() {
+        let mut client = connect(2);
+        client.write_all(b"GET /1 HTTP/1.1\r\n\r\nGET /2 HTTP/1.1\r\n\r\nGET /3 HTTP/1.1\r\n\r\n").unwrap();
+
+        let response = read_to_close(&mut client);
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2);
+        assert!(!response.contains("/3"));
+    }
+    #[test]
+    fn test_idle_connection_is_closed() {
+        let mut client = connect(100);
+        client.write_all(b"GET /1 HTTP/1.1\r\n\r\n").unwrap();
+
+        let started = Instant::now();
+        let response = read_to_close(&mut client);
+        assert!(response.ends_with("/1"));
+        assert!(started.elapsed() >= Duration::from_millis(400));
+    }
+    #[test]
+    fn test_slow_header_times_out() {
+        let mut client = connect_with(|settings| settings.header_timeout = Duration::from_millis(300));
+        let started = Instant::now();
+        //Trickle the request in faster than any per-read timeout would notice
+        for byte in b"GET / HTTP/1.1\r\nX-Slow: aaaaaaaaaaaaaaaa".iter() {
+            if client.write_all(&[*byte]).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+            if started.elapsed() > Duration::from_millis(600) {
+                break;
+            }
+        }
+
+        let response = read_to_close(&mut client);
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout"));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+    #[test]
+    fn test_header_timeout_counts_from_connecting() {
+        let mut client = connect_with(|settings| settings.header_timeout = Duration::from_millis(300));
+        thread::sleep(Duration::from_millis(200));
+        client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        thread::sleep(Duration::from_millis(200));
+        let _ = client.write_all(b"Host: a\r\n\r\n");
+
+        let response = read_to_close(&mut client);
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout"));
+    }
+    #[test]
+    fn test_slow_body_times_out() {
+        let mut client = connect_with(|settings| settings.body_timeout = Duration::from_millis(200));
+        client.write_all(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc").unwrap();
+
+        let response = read_to_close(&mut client);
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout"));
+    }
+}
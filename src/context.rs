@@ -1,10 +1,14 @@
 
-use std::net::TcpStream;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use crate::transport::Transport;
 use std::io::prelude::*;
 use std::collections::HashMap;
 use crate::HttpListener;
+use crate::server::ServerHandle;
+use crate::executor::Executor;
 pub mod response; //Include context/response.rs
 pub mod request; //include context/request.rs
+pub mod parser; //include context/parser.rs
 
 pub enum HttpMethod {
     UNKNOWN,
@@ -13,6 +17,7 @@ pub enum HttpMethod {
     POST
 }
 impl HttpMethod {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(method: &str) -> HttpMethod {
         match method {
             "GET" => HttpMethod::GET,
@@ -22,35 +27,217 @@ impl HttpMethod {
         }
     }
 }
+/// The protocol version a request was sent with, which its response is written in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpVersion {
+    Http10,
+    Http11,
+    Http2,
+}
+impl HttpVersion {
+    /// The version as a request or status line spells it, e.g. `HTTP/1.1`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpVersion::Http10 => "HTTP/1.0",
+            HttpVersion::Http11 => "HTTP/1.1",
+            HttpVersion::Http2 => "HTTP/2",
+        }
+    }
+}
 pub enum HttpResponseType {
     Ok,
+    MovedPermanently,
+    PermanentRedirect,
+    BadRequest,
     NotFound,
+    RequestTimeout,
+    PayloadTooLarge,
+    UriTooLong,
+    ExpectationFailed,
+    UpgradeRequired,
+    RequestHeaderFieldsTooLarge,
     InternalError,
+    NotImplemented,
+    ServiceUnavailable,
+    HttpVersionNotSupported,
     None,
 }
 impl HttpResponseType {
     pub fn code(&self) -> u16 {
         match self {
             HttpResponseType::Ok => 200,
+            HttpResponseType::MovedPermanently => 301,
+            HttpResponseType::PermanentRedirect => 308,
+            HttpResponseType::BadRequest => 400,
             HttpResponseType::NotFound => 404,
+            HttpResponseType::RequestTimeout => 408,
+            HttpResponseType::PayloadTooLarge => 413,
+            HttpResponseType::UriTooLong => 414,
+            HttpResponseType::ExpectationFailed => 417,
+            HttpResponseType::UpgradeRequired => 426,
+            HttpResponseType::RequestHeaderFieldsTooLarge => 431,
             HttpResponseType::InternalError => 500,
+            HttpResponseType::NotImplemented => 501,
+            HttpResponseType::ServiceUnavailable => 503,
+            HttpResponseType::HttpVersionNotSupported => 505,
             HttpResponseType::None => 0,
         }
         
     }
+    /// The standard reason phrase for the status code.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            HttpResponseType::Ok => "OK",
+            HttpResponseType::MovedPermanently => "Moved Permanently",
+            HttpResponseType::PermanentRedirect => "Permanent Redirect",
+            HttpResponseType::BadRequest => "Bad Request",
+            HttpResponseType::NotFound => "Not Found",
+            HttpResponseType::RequestTimeout => "Request Timeout",
+            HttpResponseType::PayloadTooLarge => "Payload Too Large",
+            HttpResponseType::UriTooLong => "URI Too Long",
+            HttpResponseType::ExpectationFailed => "Expectation Failed",
+            HttpResponseType::UpgradeRequired => "Upgrade Required",
+            HttpResponseType::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            HttpResponseType::InternalError => "Internal Server Error",
+            HttpResponseType::NotImplemented => "Not Implemented",
+            HttpResponseType::ServiceUnavailable => "Service Unavailable",
+            HttpResponseType::HttpVersionNotSupported => "HTTP Version Not Supported",
+            HttpResponseType::None => "",
+        }
+    }
+}
+
+
+/// Where a context writes its response.
+enum Output {
+    Transport(Box<dyn Transport>),
+    /// Collected for a backend that writes the response itself, such as the async one.
+    #[cfg_attr(not(feature = "tokio"), allow(dead_code))]
+    Buffer(Vec<u8>),
+    /// Kept apart for a protocol that frames the response itself, such as HTTP/2.
+    #[cfg(feature = "http2")]
+    Captured(Option<CapturedResponse>),
+}
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Output::Transport(transport) => transport.write(buf),
+            Output::Buffer(buffer) => buffer.write(buf),
+            #[cfg(feature = "http2")]
+            Output::Captured(_) => Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "captured responses are not written as text")),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::Transport(transport) => transport.flush(),
+            Output::Buffer(_) => Ok(()),
+            #[cfg(feature = "http2")]
+            Output::Captured(_) => Ok(()),
+        }
+    }
 }
 
+/// A response as written by a handler, before it is framed for the connection.
+#[cfg(feature = "http2")]
+pub(crate) struct CapturedResponse {
+    pub(crate) status: u16,
+    /// Every header HTTP/1.1 would send, apart from Connection.
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
 
 pub struct Context {
-    stream: TcpStream,
+    stream: Output,
     pub request: Request,
+    /// Whether the connection stays open after this response.
+    pub(crate) keep_alive: bool,
+    /// The server this connection belongs to; a response written during shutdown closes it.
+    pub(crate) server: Option<ServerHandle>,
+    pub(crate) executor: Option<Executor>,
+    /// Strict-Transport-Security value added to responses sent over HTTPS.
+    pub(crate) hsts: Option<String>,
+    pub(crate) peer_addr: Option<SocketAddr>,
 }
 
 impl Context {
     pub fn new(stream: TcpStream, request: Request) -> Context {
+        Context::with_transport(Box::new(stream), request)
+    }
+    /// A context that writes its response to any transport, such as a TLS session or an
+    /// in-memory connection in tests.
+    pub fn with_transport(transport: Box<dyn Transport>, request: Request) -> Context {
+        let keep_alive = request.keep_alive;
+        let peer_addr = transport.peer_addr();
         Context {
-            stream: stream,
-            request: request,
+            stream: Output::Transport(transport),
+            request,
+            keep_alive,
+            server: None,
+            executor: None,
+            hsts: None,
+            peer_addr,
+        }
+    }
+    /// The address of the other end of the connection, when it is an IP connection. With
+    /// the PROXY protocol this is the client the load balancer reported; a proxy speaking
+    /// HTTP is the peer itself, and `Request::client_ip` is who it forwarded for.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+    /// Runs jobs in the background after the response has gone out. Only contexts
+    /// created by a running server have one; its jobs finish before the server stops.
+    pub fn executor(&self) -> Option<&Executor> {
+        self.executor.as_ref()
+    }
+    /// Hands the connection back once the response has been written.
+    pub(crate) fn into_transport(self) -> Option<Box<dyn Transport>> {
+        match self.stream {
+            Output::Transport(transport) => Some(transport),
+            _ => None,
+        }
+    }
+    /// A context that keeps the response apart for the connection to frame it.
+    #[cfg(feature = "http2")]
+    pub(crate) fn captured(request: Request) -> Context {
+        let keep_alive = request.keep_alive;
+        Context {
+            stream: Output::Captured(None),
+            request,
+            keep_alive,
+            server: None,
+            executor: None,
+            hsts: None,
+            peer_addr: None,
+        }
+    }
+    /// The response kept by a `captured` context, if one was written.
+    #[cfg(feature = "http2")]
+    pub(crate) fn into_captured(self) -> Option<CapturedResponse> {
+        match self.stream {
+            Output::Captured(response) => response,
+            _ => None,
+        }
+    }
+    /// A context that collects the response in memory instead of writing it out.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn buffered(request: Request) -> Context {
+        let keep_alive = request.keep_alive;
+        Context {
+            stream: Output::Buffer(Vec::new()),
+            request,
+            keep_alive,
+            server: None,
+            executor: None,
+            hsts: None,
+            peer_addr: None,
+        }
+    }
+    /// The response collected by a `buffered` context.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn into_output(self) -> Vec<u8> {
+        match self.stream {
+            Output::Buffer(buffer) => buffer,
+            _ => Vec::new(),
         }
     }
 
@@ -62,29 +249,54 @@ impl Context {
     }
     fn write_flush(&mut self, response: Response, mime: &str) 
     {
-        let mut mime_string = String::new();
-        if mime.len() > 0 {
-            mime_string = format!("Content-Type: {}\r\n", mime);
+        let mut headers: Vec<(String, String)> = Vec::new();
+        if !mime.is_empty() {
+            headers.push((String::from("Content-Type"), String::from(mime)));
+        }
+        headers.extend(response.headers.iter().cloned());
+        if let Some(hsts) = &self.hsts {
+            if !response.headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("Strict-Transport-Security")) {
+                headers.push((String::from("Strict-Transport-Security"), hsts.clone()));
+            }
         }
 
-        let response_string: String = format!("HTTP/1.1 {} {}\r\nConnection: keep-alive\r\nContent-Length: {}\r\n{}\r\n", response.http_type.code(), response.text, response.data.len(), mime_string);
+        #[cfg(feature = "http2")]
+        {
+            if let Output::Captured(captured) = &mut self.stream {
+                headers.insert(0, (String::from("Content-Length"), response.data.len().to_string()));
+                *captured = Some(CapturedResponse { status: response.http_type.code(), headers, body: response.data });
+                HttpListener::log("Finished request");
+                return;
+            }
+        }
+
+        if let Some(server) = &self.server {
+            if server.is_shutting_down() {
+                self.keep_alive = false;
+            }
+        }
+        let connection = if self.keep_alive { "keep-alive" } else { "close" };
+
+        let mut extra_headers = String::new();
+        for (name, value) in &headers {
+            extra_headers.push_str(format!("{}: {}\r\n", name, value).as_str());
+        }
+
+        //Responses never use chunked encoding, so HTTP/1.0 clients can read them too
+        let response_string: String = format!("{} {} {}\r\nConnection: {}\r\nContent-Length: {}\r\n{}\r\n", self.request.version.as_str(), response.http_type.code(), response.text, connection, response.data.len(), extra_headers);
         
-        let result = self.stream.write(response_string.as_bytes());
-        match result {
-            Err(_) => {HttpListener::log(format!("Failed writing headers").as_str()); return},
-            Ok(_) => (),
+        if self.stream.write_all(response_string.as_bytes()).is_err() {
+            HttpListener::log("Failed writing headers");
+            return;
         }
-        let result = self.stream.write(&response.data);
-        match result {
-            Err(_) => {HttpListener::log(format!("Failed writing data").as_str()); return},
-            Ok(_) => (),
+        if self.stream.write_all(&response.data).is_err() {
+            HttpListener::log("Failed writing data");
+            return;
         }
-        println!("Finished request");
+        HttpListener::log("Finished request");
         
-        let result = self.stream.flush();
-        match result {
-            Err(_) => {HttpListener::log(format!("Failed flushing stream").as_str()); return},
-            Ok(_) => (),
+        if self.stream.flush().is_err() {
+            HttpListener::log("Failed flushing stream");
         }
     }
 
@@ -115,6 +327,8 @@ impl Context {
 pub struct Request {
     pub method: HttpMethod,
     pub protocol: String,
+    /// The HTTP version from the request line; the response is written in the same one.
+    pub version: HttpVersion,
     pub user: String,
     pub password: String,
     pub url: String,
@@ -124,7 +338,13 @@ pub struct Request {
     pub get: HashMap<String,String>,
     pub post: HashMap<String,String>,
     pub put: HashMap<String,String>,
+    pub body: Vec<u8>,
     pub ready: bool,
+    /// Whether the client allows the connection to be reused after this request.
+    pub(crate) keep_alive: bool,
+    /// The client's address: the connection peer, or the client a trusted proxy
+    /// forwarded the request for. See `HttpListener::trusted_proxies`.
+    pub client_ip: Option<IpAddr>,
 }
 
 pub struct Response {
@@ -132,7 +352,8 @@ pub struct Response {
     pub text: String,
     pub data: Vec<u8>,
     pub mime: String,
-    
+    /// Extra headers written after the standard ones.
+    pub headers: Vec<(String, String)>,
 }
 
 #[cfg(test)]
@@ -0,0 +1,507 @@
+use crate::context::{HttpMethod, HttpVersion, Request};
+use crate::context::request::{ParseError, MAX_URI_LENGTH, MAX_HEADER_SIZE, MAX_BODY_SIZE};
+
+/// The version named at the end of the first line of `data`: HTTP/1.0 if it says so,
+/// otherwise HTTP/1.1, including when the line is incomplete or malformed.
+pub(crate) fn request_line_version(data: &[u8]) -> HttpVersion {
+    let start = data.iter().take_while(|b| b.is_ascii_whitespace()).count();
+    let line = match data[start..].iter().position(|b| *b == b'\n') {
+        Some(end) => &data[start..start + end],
+        None => return HttpVersion::Http11,
+    };
+    if line.strip_suffix(b"\r").unwrap_or(line).ends_with(b" HTTP/1.0") {
+        HttpVersion::Http10
+    } else {
+        HttpVersion::Http11
+    }
+}
+
+/// Reads a Content-Length value, which is decimal digits only. `str::parse` would also
+/// take a leading `+`, and a front-end reading the value differently could be led to
+/// frame the request differently.
+pub(crate) fn content_length_value(value: &str) -> Option<usize> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse::<usize>().ok()
+}
+
+/// How the end of a request body is found.
+enum Framing {
+    /// Content-Length, or an empty body when there is none.
+    Length(usize),
+    /// Transfer-Encoding: chunked.
+    Chunked,
+}
+
+/// Authority used to resolve requests that carry no Host header and no listener address.
+pub const DEFAULT_HOST: &str = "localhost";
+
+/// Incremental HTTP/1.x request parser.
+///
+/// Bytes are fed in as they arrive from the connection and complete requests are taken
+/// out with `next_request`, so a request split over several reads, or several requests
+/// sharing one read, parse the same way. `Request::from_request_data` runs the same
+/// parser over a complete buffer.
+pub struct RequestParser {
+    buffer: Vec<u8>,
+    default_host: String,
+    secure: bool,
+}
+
+impl RequestParser {
+    /// `default_host` resolves requests without a Host header, normally the listener address.
+    pub fn new(default_host: &str) -> RequestParser {
+        RequestParser {
+            buffer: Vec::new(),
+            default_host: String::from(default_host),
+            secure: false,
+        }
+    }
+
+    /// Marks the connection as encrypted, so its requests get the `https` protocol.
+    pub fn secure(mut self, secure: bool) -> RequestParser {
+        self.secure = secure;
+        self
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Number of bytes received but not yet consumed by a request.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// The bytes received but not yet consumed by a request.
+    pub fn peek(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Takes out everything received after the last request, for a connection that
+    /// switches to another protocol.
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+
+    /// Whether the buffered request has all its headers and is waiting for the body.
+    pub fn awaiting_body(&self) -> bool {
+        self.head_end().is_some()
+    }
+
+    /// The request line and headers of a request whose body is still arriving, parsed
+    /// without taking anything out of the buffer. Any error in them is returned by
+    /// `next_request` instead.
+    pub fn pending_head(&self) -> Option<Request> {
+        let head_end = self.head_end()?;
+        let head = String::from_utf8_lossy(&self.buffer[..head_end]);
+        RequestParser::parse_head(&head, &self.default_host, self.secure).ok().map(|(request, _)| request)
+    }
+
+    /// The version on the request line buffered so far, for answering a request that
+    /// cannot be parsed or completed in the version its client speaks.
+    pub fn pending_version(&self) -> HttpVersion {
+        request_line_version(&self.buffer)
+    }
+
+    /// Takes the next complete request out of the buffer, or `None` until more data arrives.
+    pub fn next_request(&mut self) -> Result<Option<Request>, ParseError> {
+        self.parse(false)
+    }
+
+    /// Parses everything buffered as one request, treating the end of the data as the
+    /// end of the headers and body.
+    pub fn finish(&mut self) -> Result<Request, ParseError> {
+        match self.parse(true)? {
+            Some(request) => Ok(request),
+            None => Err(ParseError::Empty),
+        }
+    }
+
+    fn parse(&mut self, at_end: bool) -> Result<Option<Request>, ParseError> {
+        //Blank lines before a request line are ignored (e.g. a stray CRLF after a body)
+        let skip = self.buffer.iter().take_while(|b| b.is_ascii_whitespace()).count();
+        self.buffer.drain(..skip);
+        if self.buffer.is_empty() {
+            return if at_end { Err(ParseError::Empty) } else { Ok(None) };
+        }
+
+        let head_end = match self.head_end() {
+            Some(end) => end,
+            None if at_end => self.buffer.len(),
+            None => {
+                self.check_incomplete_head()?;
+                return Ok(None);
+            }
+        };
+
+        let head = String::from_utf8_lossy(&self.buffer[..head_end]).into_owned();
+        let (mut request, framing) = RequestParser::parse_head(&head, &self.default_host, self.secure)?;
+
+        let body_end = match framing {
+            Framing::Length(content_length) => {
+                let body_end = head_end + content_length;
+                if self.buffer.len() < body_end && !at_end {
+                    return Ok(None);
+                }
+                let body_end = body_end.min(self.buffer.len());
+                request.body = self.buffer[head_end..body_end].to_vec();
+                body_end
+            },
+            Framing::Chunked => match RequestParser::decode_chunked(&self.buffer[head_end..])? {
+                Some((body, length)) => {
+                    request.body = body;
+                    head_end + length
+                },
+                None if at_end => return Err(ParseError::BadChunk),
+                None => return Ok(None),
+            },
+        };
+        self.buffer.drain(..body_end);
+
+        RequestParser::parse_body(&mut request);
+        request.ready = true;
+        Ok(Some(request))
+    }
+
+    /// Byte offset just past the blank line that ends the header section.
+    fn head_end(&self) -> Option<usize> {
+        let mut start = 0;
+        for (idx, byte) in self.buffer.iter().enumerate() {
+            if *byte != b'\n' {
+                continue;
+            }
+            let line = &self.buffer[start..idx];
+            if start > 0 && line.iter().all(|b| *b == b' ' || *b == b'\t' || *b == b'\r') {
+                return Some(idx + 1);
+            }
+            start = idx + 1;
+        }
+        None
+    }
+
+    /// Rejects a header section that has grown past the limits without being terminated.
+    fn check_incomplete_head(&self) -> Result<(), ParseError> {
+        match self.buffer.iter().position(|b| *b == b'\n') {
+            None if self.buffer.len() > MAX_URI_LENGTH + 32 => Err(ParseError::UriTooLong),
+            Some(line_end) if self.buffer.len() - line_end > MAX_HEADER_SIZE => Err(ParseError::HeadersTooLarge),
+            _ => Ok(()),
+        }
+    }
+
+    /// Parses the request line and headers, returning the request and how its body is framed.
+    fn parse_head(head: &str, default_host: &str, secure: bool) -> Result<(Request, Framing), ParseError> {
+        let mut request = Request::empty();
+        let mut lines = head.lines();
+
+        //Analyze first line
+        let first_line = lines.next().unwrap_or("");
+        let words: Vec<&str> = first_line.trim_end().split(' ').collect();
+        if words.len() != 3 || words[0].is_empty() || words[1].is_empty() {
+            return Err(ParseError::MalformedRequestLine);
+        }
+        request.version = RequestParser::parse_version(words[2])?;
+        if words[1].len() > MAX_URI_LENGTH {
+            return Err(ParseError::UriTooLong);
+        }
+
+        //Load all header data into request.header
+        let mut content_length: Option<usize> = None;
+        let mut transfer_encodings = 0;
+        let mut header_size = 0;
+        for line in lines {
+            if line.trim().is_empty() {
+                break;
+            }
+
+            header_size += line.len() + 2;
+            if header_size > MAX_HEADER_SIZE {
+                return Err(ParseError::HeadersTooLarge);
+            }
+
+            let (key, value) = RequestParser::parse_header_line(line)?;
+            if key.eq_ignore_ascii_case("Content-Length") {
+                let length = match content_length_value(&value) {
+                    None => return Err(ParseError::BadContentLength),
+                    Some(v) => v,
+                };
+                if content_length.is_some_and(|previous| previous != length) {
+                    return Err(ParseError::AmbiguousLength);
+                }
+                if length > MAX_BODY_SIZE {
+                    return Err(ParseError::BodyTooLarge);
+                }
+                content_length = Some(length);
+            } else if key.eq_ignore_ascii_case("Transfer-Encoding") {
+                transfer_encodings += 1;
+            }
+
+            request.header.insert(key, value);
+        }
+
+        //HTTP/1.1 connections persist unless closed, HTTP/1.0 ones only when asked to
+        request.keep_alive = match request.version {
+            HttpVersion::Http10 => RequestParser::has_connection_token(&request, "keep-alive"),
+            _ => !RequestParser::has_connection_token(&request, "close"),
+        };
+        //Only one way of finding the end of the body is accepted, so nothing in front
+        //of the server can read the request differently
+        let framing = match request.header_value("Transfer-Encoding") {
+            None => Framing::Length(content_length.unwrap_or(0)),
+            Some(_) if request.version == HttpVersion::Http10 => return Err(ParseError::TransferEncodingInHttp10),
+            Some(_) if content_length.is_some() || transfer_encodings > 1 => return Err(ParseError::AmbiguousLength),
+            Some(encoding) if encoding.trim().eq_ignore_ascii_case("chunked") => Framing::Chunked,
+            Some(_) => return Err(ParseError::UnsupportedTransferEncoding),
+        };
+
+        //Check request method
+        request.method = HttpMethod::from_str(words[0]);
+
+        request.protocol = String::from(if secure { "https" } else { "http" });
+        request.parse_url(words[1], default_host)?;
+
+        Ok((request, framing))
+    }
+
+    /// Decodes a chunked body from the start of `data`, returning it along with the
+    /// number of bytes it took up, trailers included, or `None` until all of it arrived.
+    /// Chunk extensions and trailer fields are skipped.
+    fn decode_chunked(data: &[u8]) -> Result<Option<(Vec<u8>, usize)>, ParseError> {
+        let mut body = Vec::new();
+        let mut pos = 0;
+        loop {
+            let line_end = match RequestParser::line_end(&data[pos..]) {
+                Some(line_end) => pos + line_end,
+                None if data.len() - pos > 1024 => return Err(ParseError::BadChunk),
+                None => return Ok(None),
+            };
+            let line = std::str::from_utf8(&data[pos..line_end]).map_err(|_| ParseError::BadChunk)?;
+            let size = line.split(';').next().unwrap_or("").trim();
+            //from_str_radix would also take a sign, which other parsers may not
+            if size.is_empty() || size.len() > 8 || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(ParseError::BadChunk);
+            }
+            let size = usize::from_str_radix(size, 16).map_err(|_| ParseError::BadChunk)?;
+            pos = line_end + 2;
+
+            if size == 0 {
+                //Trailer fields run up to an empty line
+                loop {
+                    let line_end = match RequestParser::line_end(&data[pos..]) {
+                        Some(line_end) => pos + line_end,
+                        None if data.len() - pos > MAX_HEADER_SIZE => return Err(ParseError::HeadersTooLarge),
+                        None => return Ok(None),
+                    };
+                    let empty = line_end == pos;
+                    pos = line_end + 2;
+                    if empty {
+                        return Ok(Some((body, pos)));
+                    }
+                }
+            }
+            if body.len() + size > MAX_BODY_SIZE {
+                return Err(ParseError::BodyTooLarge);
+            }
+            if data.len() < pos + size + 2 {
+                return Ok(None);
+            }
+            if &data[pos + size..pos + size + 2] != b"\r\n" {
+                return Err(ParseError::BadChunk);
+            }
+            body.extend_from_slice(&data[pos..pos + size]);
+            pos += size + 2;
+        }
+    }
+
+    /// Offset of the CRLF ending the first line of `data`.
+    fn line_end(data: &[u8]) -> Option<usize> {
+        data.windows(2).position(|pair| pair == b"\r\n")
+    }
+
+    /// Reads the `HTTP/x.y` word of the request line. Minor versions above 1.1 are
+    /// served as HTTP/1.1, which they are compatible with; other major versions are
+    /// not supported.
+    fn parse_version(word: &str) -> Result<HttpVersion, ParseError> {
+        let digits = match word.strip_prefix("HTTP/") {
+            Some(digits) => digits.as_bytes(),
+            None => return Err(ParseError::MalformedRequestLine),
+        };
+        match digits {
+            [b'1', b'.', b'0'] => Ok(HttpVersion::Http10),
+            [b'1', b'.', minor] if minor.is_ascii_digit() => Ok(HttpVersion::Http11),
+            [major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit() => Err(ParseError::UnsupportedVersion),
+            _ => Err(ParseError::MalformedRequestLine),
+        }
+    }
+
+    fn has_connection_token(request: &Request, token: &str) -> bool {
+        match request.header_value("Connection") {
+            Some(value) => value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)),
+            None => false,
+        }
+    }
+
+    fn parse_header_line(line: &str) -> Result<(String, String), ParseError> {
+        let idx = match line.find(':') {
+            Some(idx) => idx,
+            None => return Err(ParseError::BadHeader),
+        };
+        let key = line[..idx].trim();
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(ParseError::BadHeader);
+        }
+        let value = line[idx+1..].trim();
+        Ok((key.to_string(), value.to_string()))
+    }
+
+    /// Decodes url-encoded form bodies into `post` or `put`.
+    fn parse_body(request: &mut Request) {
+        if request.body.is_empty() {
+            return;
+        }
+        let form = match request.header_value("Content-Type") {
+            None => true,
+            Some(content_type) => content_type.trim_start().starts_with("application/x-www-form-urlencoded"),
+        };
+        if !form {
+            return;
+        }
+
+        let fields = match request.method {
+            HttpMethod::POST => &mut request.post,
+            HttpMethod::PUT => &mut request.put,
+            _ => return,
+        };
+        let body = String::from_utf8_lossy(&request.body).into_owned();
+        for kv in url::form_urlencoded::parse(body.trim().as_bytes()) {
+            fields.insert(kv.0.to_string(), kv.1.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_request() {
+        let mut parser = RequestParser::new(DEFAULT_HOST);
+        parser.feed(b"POST /form HTTP/1.1\r\nHost: local");
+        assert!(parser.next_request().unwrap().is_none());
+        parser.feed(b"host\r\nContent-Length: 7\r\n\r\na=1");
+        assert!(parser.next_request().unwrap().is_none());
+        parser.feed(b"&b=2");
+
+        let r = parser.next_request().unwrap().unwrap();
+        assert_eq!(r.path, "/form");
+        assert_eq!(r.post["a"], "1");
+        assert_eq!(r.post["b"], "2");
+        assert_eq!(parser.buffered(), 0);
+    }
+    #[test]
+    fn test_pipelined_requests() {
+        let mut parser = RequestParser::new(DEFAULT_HOST);
+        parser.feed(b"GET /one HTTP/1.1\r\n\r\nPUT /two HTTP/1.1\r\nContent-Length: 3\r\n\r\nx=1GET /three HTTP/1.1\r\n\r\n");
+
+        assert_eq!(parser.next_request().unwrap().unwrap().path, "/one");
+        let r = parser.next_request().unwrap().unwrap();
+        assert_eq!(r.path, "/two");
+        assert_eq!(r.put["x"], "1");
+        assert_eq!(parser.next_request().unwrap().unwrap().path, "/three");
+        assert!(parser.next_request().unwrap().is_none());
+    }
+    #[test]
+    fn test_keep_alive() {
+        let parse = |data: &str| {
+            let mut parser = RequestParser::new(DEFAULT_HOST);
+            parser.feed(data.as_bytes());
+            parser.next_request().unwrap().unwrap().keep_alive
+        };
+        assert!(parse("GET / HTTP/1.1\r\n\r\n"));
+        assert!(!parse("GET / HTTP/1.1\r\nConnection: Close\r\n\r\n"));
+        assert!(!parse("GET / HTTP/1.0\r\n\r\n"));
+        assert!(parse("GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n"));
+    }
+    #[test]
+    fn test_versions() {
+        let parse = |data: &str| {
+            let mut parser = RequestParser::new(DEFAULT_HOST);
+            parser.feed(data.as_bytes());
+            parser.next_request().map(|r| r.unwrap().version)
+        };
+        assert_eq!(parse("GET / HTTP/1.0\r\n\r\n"), Ok(HttpVersion::Http10));
+        assert_eq!(parse("GET / HTTP/1.1\r\n\r\n"), Ok(HttpVersion::Http11));
+        assert_eq!(parse("GET / HTTP/1.2\r\n\r\n"), Ok(HttpVersion::Http11));
+        assert_eq!(parse("GET / HTTP/3.0\r\n\r\n").err(), Some(ParseError::UnsupportedVersion));
+        assert_eq!(parse("GET / HTTP/1\r\n\r\n").err(), Some(ParseError::MalformedRequestLine));
+        assert_eq!(parse("POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n").err(), Some(ParseError::TransferEncodingInHttp10));
+
+        assert_eq!(request_line_version(b"\r\nPOST / HTTP/1.0\r\nContent-Length: 5"), HttpVersion::Http10);
+        assert_eq!(request_line_version(b"GET / HTTP/1.1\r\n"), HttpVersion::Http11);
+        assert_eq!(request_line_version(b"GET / HTTP/1.0"), HttpVersion::Http11);
+    }
+    #[test]
+    fn test_ambiguous_length() {
+        let parse = |data: &str| {
+            let mut parser = RequestParser::new(DEFAULT_HOST);
+            parser.feed(data.as_bytes());
+            parser.next_request().map(|r| r.unwrap().body)
+        };
+        assert_eq!(parse("POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\nabc"), Ok(b"abc".to_vec()));
+        assert_eq!(parse("POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 30\r\n\r\nabc").err(), Some(ParseError::AmbiguousLength));
+        assert_eq!(parse("POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n").err(), Some(ParseError::AmbiguousLength));
+        assert_eq!(parse("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\ntransfer-encoding: chunked\r\n\r\n0\r\n\r\n").err(), Some(ParseError::AmbiguousLength));
+        assert_eq!(parse("POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n").err(), Some(ParseError::UnsupportedTransferEncoding));
+        assert_eq!(ParseError::UnsupportedTransferEncoding.response().http_type.code(), 501);
+        assert_eq!(parse("POST / HTTP/1.1\r\nContent-Length: +3\r\n\r\nabc").err(), Some(ParseError::BadContentLength));
+        assert_eq!(parse("POST / HTTP/1.1\r\nContent-Length: 3 3\r\n\r\nabc").err(), Some(ParseError::BadContentLength));
+        assert_eq!(parse("POST / HTTP/1.1\r\nContent-Length: -0\r\n\r\n").err(), Some(ParseError::BadContentLength));
+    }
+    #[test]
+    fn test_chunked_body() {
+        let mut parser = RequestParser::new(DEFAULT_HOST);
+        parser.feed(b"POST /form HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3;ext=1\r\na=1\r\n");
+        assert!(parser.next_request().unwrap().is_none());
+        parser.feed(b"4\r\n&b=2\r\n0\r\nX-Trailer: yes\r\n\r\nGET /next HTTP/1.1\r\n\r\n");
+
+        let r = parser.next_request().unwrap().unwrap();
+        assert_eq!(r.body, b"a=1&b=2");
+        assert_eq!(r.post["b"], "2");
+        //The chunk data is never mistaken for the next request
+        assert_eq!(parser.next_request().unwrap().unwrap().path, "/next");
+        assert!(parser.next_request().unwrap().is_none());
+
+        let mut parser = RequestParser::new(DEFAULT_HOST);
+        parser.feed(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n");
+        assert_eq!(parser.next_request().err(), Some(ParseError::BadChunk));
+        let mut parser = RequestParser::new(DEFAULT_HOST);
+        parser.feed(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n+a\r\n0123456789\r\n0\r\n\r\n");
+        assert_eq!(parser.next_request().err(), Some(ParseError::BadChunk));
+        let mut parser = RequestParser::new(DEFAULT_HOST);
+        parser.feed(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabcd\r\n");
+        assert_eq!(parser.next_request().err(), Some(ParseError::BadChunk));
+        let mut parser = RequestParser::new(DEFAULT_HOST);
+        parser.feed(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n200000\r\n");
+        assert_eq!(parser.next_request().err(), Some(ParseError::BodyTooLarge));
+    }
+    #[test]
+    fn test_body_is_not_form_decoded_for_other_types() {
+        let mut parser = RequestParser::new(DEFAULT_HOST);
+        parser.feed(b"POST / HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 7\r\n\r\n{\"a\":1}");
+
+        let r = parser.next_request().unwrap().unwrap();
+        assert!(r.post.is_empty());
+        assert_eq!(r.body, b"{\"a\":1}");
+    }
+    #[test]
+    fn test_unterminated_head_limits() {
+        let mut parser = RequestParser::new(DEFAULT_HOST);
+        parser.feed(format!("GET /{}", "a".repeat(MAX_URI_LENGTH + 64)).as_bytes());
+        assert_eq!(parser.next_request().err(), Some(ParseError::UriTooLong));
+
+        let mut parser = RequestParser::new(DEFAULT_HOST);
+        parser.feed(format!("GET / HTTP/1.1\r\nX-Big: {}", "a".repeat(MAX_HEADER_SIZE + 1)).as_bytes());
+        assert_eq!(parser.next_request().err(), Some(ParseError::HeadersTooLarge));
+    }
+}
@@ -1,146 +1,100 @@
 use std::fmt::Display;
 use std::collections::HashMap;
-use crate::context::{HttpMethod, Request, Context};
-use crate::HttpListener;
-use crate::Settings;
+use crate::context::{HttpMethod, HttpResponseType, HttpVersion, Request, Response};
+use crate::context::parser::{RequestParser, DEFAULT_HOST};
 use url::Url;
-use std::net::{TcpStream};
-use std::sync::Arc;
 
-impl Request {
-    pub fn handle_request(request_header: &str, stream: TcpStream, settings: Arc<Settings>) {
-        
-        let mut request = Request {
-            method: HttpMethod::GET,
-            protocol: String::new(),
-            user: String::new(),
-            password: String::new(),
-            url: String::new(),
-            path: String::new(),
-            querystring: String::new(),
-            header: HashMap::new(),
-            get: HashMap::new(),
-            post: HashMap::new(),
-            put: HashMap::new(),
-            ready: false,
-        };
-        //Break up lines
-        let lines: Vec<&str> = request_header.lines().collect();
-        
-        if lines.len() == 0 {
-            //let msg: &str = request_header;
-            //let msg2: &str = format!("Bad request: '{}'",msg);
-            println!("Error: Request header contains no lines");
-            //return Err(request_header);
-            return;
-        }
-
-        //Analyze first line
-        let words: Vec<&str> = lines[0].split(" ").collect();
-        if words.len() < 3 {
-            println!("First line does not contain 3 words");
-            println!("Error: Request first line does not contain 3 words");
-            return;// Err("Bad request: empty request");
-        }
-
-        //headers
-        let mut content_length: usize = 0;
-
-        //Load all header data into request.header
-        let mut current_line = 1;
-        for i in current_line..lines.len() {
-            //println!("{}:\t{}\t{}\t{}",lines[i].len(), lines[i], lines[i] == "",content_length);
-            let opt = lines[i].find(':');
-            if lines[i] == "" && content_length == 0 {
-                //No further data to be read. We have all the headers we need.
-                request.ready = true;
-                println!("Request ready!");
-                break;
-            }
-
-            if opt.is_none() { break; }
-
-            let idx = opt.unwrap();
-            let key: String = lines[i].chars().take(idx).collect();
-            let value: String = lines[i].chars().skip(idx+1).collect();
-            let value = value.as_str().trim().to_string();
-            
-            if key == "Content-Length" {
-                content_length = match value.parse::<usize>() {
-                    Err(_) => 0,
-                    Ok(v) => v,
-                };
-                
-            }
-
-            request.header.insert(key, value);
-            current_line += 1;
-        }
-
-        
-
-        //Check request method
-        request.method = HttpMethod::from_str(words[0]);
-
-        //Fast forward to line that is not empty
-        if current_line < lines.len() {
-            for i in current_line..lines.len() {
-                if lines[i].trim().is_empty() {
-                    current_line += 1;
-                } else {
-                    break;
-                }
-            }
-        }
-
-        //If it is a post - look at posted data
-        if current_line+1 <= lines.len() {
-            match request.method {
-                HttpMethod::POST => {
-                    let decoded = url::form_urlencoded::parse(lines[current_line].as_bytes());
-                    for kv in decoded {
-                        request.post.insert(kv.0.to_string(), kv.1.to_string());
-                    }
-                },
-                _ => (),
-            }
-        }
-
-        request.url = words[1].to_string();
-        request.protocol = String::from("http");
-
-        let result = Url::parse(format!("{}://{}{}",request.protocol, request.header["Host"], words[1]).as_str());
-        if result.is_err() {
-            HttpListener::log(format!("Cannot parse {}",words[1]).as_str());
-            return; //Err("Failed to parse url");
-        }
-        let url = result.unwrap();
-        request.path = url.path().to_string();
-        
-        let query = url.query();
-        match query {
-            Some(q) => {
-                request.querystring = q.to_string();
-                let decoded = url::form_urlencoded::parse(request.querystring.as_bytes());
-                for kv in decoded {
-                    request.get.insert(kv.0.to_string(), kv.1.to_string());
-                }
-            },
-            _ => ()
+/// Longest request target accepted before answering 414 URI Too Long.
+pub const MAX_URI_LENGTH: usize = 8192;
+/// Largest header section accepted before answering 431 Request Header Fields Too Large.
+pub const MAX_HEADER_SIZE: usize = 8192;
+/// Largest Content-Length accepted before answering 413 Payload Too Large.
+pub const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Reasons a request could not be parsed. Each maps onto the response sent to the client.
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /// Nothing but whitespace was received.
+    Empty,
+    /// The request line is not `METHOD target HTTP/x.y`.
+    MalformedRequestLine,
+    /// A header line has no colon, an empty name or whitespace inside the name.
+    BadHeader,
+    /// Content-Length is not a number.
+    BadContentLength,
+    /// The body length is given in ways that disagree: differing Content-Length
+    /// headers, Content-Length alongside Transfer-Encoding, or Transfer-Encoding twice.
+    /// A proxy in front may frame such a request differently, so it is refused.
+    AmbiguousLength,
+    /// Transfer-Encoding is something other than `chunked`.
+    UnsupportedTransferEncoding,
+    /// A chunked body has a malformed chunk size or is missing a line break.
+    BadChunk,
+    /// The Host header is not a bare authority.
+    InvalidHost,
+    /// The request target is neither origin-form nor absolute-form.
+    InvalidTarget,
+    BodyTooLarge,
+    UriTooLong,
+    HeadersTooLarge,
+    /// The request line names a major HTTP version other than 1.
+    UnsupportedVersion,
+    /// An HTTP/1.0 request carries Transfer-Encoding, which HTTP/1.0 does not have, so
+    /// where its body ends cannot be trusted.
+    TransferEncodingInHttp10,
+}
+impl ParseError {
+    pub fn http_type(&self) -> HttpResponseType {
+        match self {
+            ParseError::BodyTooLarge => HttpResponseType::PayloadTooLarge,
+            ParseError::UriTooLong => HttpResponseType::UriTooLong,
+            ParseError::HeadersTooLarge => HttpResponseType::RequestHeaderFieldsTooLarge,
+            ParseError::UnsupportedVersion => HttpResponseType::HttpVersionNotSupported,
+            ParseError::UnsupportedTransferEncoding => HttpResponseType::NotImplemented,
+            _ => HttpResponseType::BadRequest,
         }
+    }
+    pub fn response(&self) -> Response {
+        Response::error(self.http_type())
+    }
+}
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            ParseError::Empty => "empty request",
+            ParseError::MalformedRequestLine => "malformed request line",
+            ParseError::BadHeader => "malformed header line",
+            ParseError::BadContentLength => "invalid Content-Length",
+            ParseError::AmbiguousLength => "conflicting Content-Length or Transfer-Encoding",
+            ParseError::UnsupportedTransferEncoding => "unsupported Transfer-Encoding",
+            ParseError::BadChunk => "malformed chunked body",
+            ParseError::InvalidHost => "invalid Host header",
+            ParseError::InvalidTarget => "invalid request target",
+            ParseError::BodyTooLarge => "request body too large",
+            ParseError::UriTooLong => "request target too long",
+            ParseError::HeadersTooLarge => "request headers too large",
+            ParseError::UnsupportedVersion => "unsupported HTTP version",
+            ParseError::TransferEncodingInHttp10 => "Transfer-Encoding in an HTTP/1.0 request",
+        };
+        write!(f, "Bad request: {}", message)
+    }
+}
+impl std::error::Error for ParseError {}
 
-        if request.ready {
-            let context_stream = stream.try_clone().expect("Unable to clone stream failed");
-            let mut context = Context::new(context_stream,request);
-    
-            HttpListener::process(&mut context, Arc::clone(&settings), 0);
-        }
+impl Request {
+    /// Parses a complete request, as received from a client, using the same
+    /// `RequestParser` the server runs on each connection.
+    pub fn from_request_data(request_data: &str) -> Result<Request, ParseError> {
+        let mut parser = RequestParser::new(DEFAULT_HOST);
+        parser.feed(request_data.as_bytes());
+        parser.finish()
     }
-    pub fn from_request_data(request_header: &str) -> Result<Request, &str> {
-        
-        let mut request = Request {
+
+    pub(crate) fn empty() -> Request {
+        Request {
             method: HttpMethod::GET,
             protocol: String::new(),
+            version: HttpVersion::Http11,
             user: String::new(),
             password: String::new(),
             url: String::new(),
@@ -150,122 +104,70 @@ impl Request {
             get: HashMap::new(),
             post: HashMap::new(),
             put: HashMap::new(),
+            body: Vec::new(),
             ready: false,
-        };
-        //Break up lines
-        let lines: Vec<&str> = request_header.lines().collect();
-        
-        if lines.len() == 0 {
-            //let msg: &str = request_header;
-            //let msg2: &str = format!("Bad request: '{}'",msg);
-            println!("Error: Request header contains no lines");
-            return Err(request_header);
+            keep_alive: false,
+            client_ip: None,
         }
+    }
 
-        //Analyze first line
-        let words: Vec<&str> = lines[0].split(" ").collect();
-        if words.len() < 3 {
-            println!("First line does not contain 3 words");
-            println!("Error: Request first line does not contain 3 words");
-            
-            return Err("Bad request: empty request");
-        }
-
-        //headers
-        let mut content_length: usize = 0;
-
-        //Load all header data into request.header
-        let mut current_line = 1;
-        for i in current_line..lines.len() {
-            //println!("{}:\t{}\t{}\t{}",lines[i].len(), lines[i], lines[i] == "",content_length);
-            let opt = lines[i].find(':');
-            if lines[i] == "" && content_length == 0 {
-                //No further data to be read. We have all the headers we need.
-                request.ready = true;
-                println!("Request ready!");
-                break;
-            }
-
-            if opt.is_none() { break; }
+    /// Looks up a header by name, ignoring ASCII case as HTTP requires.
+    pub fn header_value(&self, name: &str) -> Option<&str> {
+        self.header.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
 
-            let idx = opt.unwrap();
-            let key: String = lines[i].chars().take(idx).collect();
-            let value: String = lines[i].chars().skip(idx+1).collect();
-            let value = value.as_str().trim().to_string();
-            
-            if key == "Content-Length" {
-                content_length = match value.parse::<usize>() {
-                    Err(_) => 0,
-                    Ok(v) => v,
-                };
-                
-            }
+    /// Resolves the request target into `url`, `path`, `querystring` and `get`.
+    ///
+    /// The authority comes from the Host header, or `default_host` when the client
+    /// did not send one (HTTP/1.0). A malformed Host or target is an error rather
+    /// than a panic, since both come straight from the client.
+    pub(crate) fn parse_url(&mut self, target: &str, default_host: &str) -> Result<(), ParseError> {
+        let host = match self.header_value("Host") {
+            Some(host) if !host.is_empty() => host.to_string(),
+            _ => String::from(default_host),
+        };
 
-            request.header.insert(key, value);
-            current_line += 1;
+        //The host must be a bare authority; anything that spills into path, query or userinfo is rejected
+        let mut url = match Url::parse(format!("{}://{}/", self.protocol, host).as_str()) {
+            Ok(url) => url,
+            Err(_) => return Err(ParseError::InvalidHost),
+        };
+        if url.path() != "/" || url.query().is_some() || url.fragment().is_some()
+            || !url.username().is_empty() || url.password().is_some() {
+            return Err(ParseError::InvalidHost);
         }
 
-
-        //Check request method
-        request.method = HttpMethod::from_str(words[0]);
-
-        //Fast forward to line that is not empty
-        if current_line < lines.len() {
-            for i in current_line..lines.len() {
-                if lines[i].trim().is_empty() {
-                    current_line += 1;
-                } else {
-                    break;
-                }
-            }
+        let target = target.split('#').next().unwrap_or("");
+        if target.starts_with('/') {
+            let (path, query) = match target.find('?') {
+                Some(idx) => (&target[..idx], Some(&target[idx+1..])),
+                None => (target, None),
+            };
+            url.set_path(path);
+            url.set_query(query);
+        } else if target.starts_with("http://") || target.starts_with("https://") {
+            //Absolute-form, as sent to proxies
+            url = match Url::parse(target) {
+                Ok(url) => url,
+                Err(_) => return Err(ParseError::InvalidTarget),
+            };
+        } else {
+            return Err(ParseError::InvalidTarget);
         }
 
-        //If it is a post - look at posted data
-        if current_line+1 <= lines.len() {
-            match request.method {
-                HttpMethod::POST => {
-                    let decoded = url::form_urlencoded::parse(lines[current_line].as_bytes());
-                    for kv in decoded {
-                        request.post.insert(kv.0.to_string(), kv.1.to_string());
-                    }
-                },
-                _ => (),
-            }
-        }
-        //If it is a put - look at putted data
-        if current_line+1 <= lines.len() {
-            match request.method {
-                HttpMethod::PUT => {
-                    let decoded = url::form_urlencoded::parse(lines[current_line].as_bytes());
-                    for kv in decoded {
-                        request.put.insert(kv.0.to_string(), kv.1.to_string());
-                    }
-                },
-                _ => (),
-            }
-        }
+        self.url = target.to_string();
+        self.path = url.path().to_string();
 
-        request.protocol = String::from("http");
-        let result = Url::parse(format!("{}://{}{}",request.protocol, request.header["Host"], words[1]).as_str());
-        if result.is_err() {
-            HttpListener::log(format!("Cannot parse {}",words[1]).as_str());
-            return Err("Failed to parse url");
-        }
-        let url = result.unwrap();
-        request.path = url.path().to_string();
-        
-        let query = url.query();
-        match query {
-            Some(q) => {
-                request.querystring = q.to_string();
-                let decoded = url::form_urlencoded::parse(request.querystring.as_bytes());
-                for kv in decoded {
-                    request.get.insert(kv.0.to_string(), kv.1.to_string());
-                }
-            },
-            _ => ()
+        if let Some(q) = url.query() {
+            self.querystring = q.to_string();
+            let decoded = url::form_urlencoded::parse(self.querystring.as_bytes());
+            for kv in decoded {
+                self.get.insert(kv.0.to_string(), kv.1.to_string());
+            }
         }
-        Ok(request)
+        Ok(())
     }
 }
 
@@ -303,8 +205,8 @@ impl KeyValue {
         let value: String = text.chars().skip(idx+1).collect();
         let value = value.as_str().trim().to_string();
         KeyValue {
-            key: key,
-            value: value
+            key,
+            value
         }
     }
 }
@@ -393,7 +295,51 @@ mod tests {
     fn test_request_from_data_empty() {
         let r = Request::from_request_data("");
 
-        assert!(matches!(r, Result::Err("Bad request")));
+        assert!(matches!(r, Result::Err(ParseError::Empty)));
+    }
+    #[test]
+    fn test_request_from_data_errors() {
+        let err = |data: &str| Request::from_request_data(data).err().unwrap();
+        assert_eq!(err("GET /\r\n\r\n"), ParseError::MalformedRequestLine);
+        assert_eq!(err("GET / HTTP/2.0\r\n\r\n"), ParseError::UnsupportedVersion);
+        assert_eq!(err("GET / HTTP/1.1\r\nHost localhost\r\n\r\n"), ParseError::BadHeader);
+        assert_eq!(err("POST / HTTP/1.1\r\nContent-Length: ten\r\n\r\n"), ParseError::BadContentLength);
+        assert_eq!(err("POST / HTTP/1.1\r\nContent-Length: 999999999\r\n\r\n"), ParseError::BodyTooLarge);
+        assert_eq!(err(format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_URI_LENGTH)).as_str()), ParseError::UriTooLong);
+        assert_eq!(err(format!("GET / HTTP/1.1\r\nX-Big: {}\r\n\r\n", "a".repeat(MAX_HEADER_SIZE)).as_str()), ParseError::HeadersTooLarge);
+    }
+    #[test]
+    fn test_parse_error_status() {
+        assert_eq!(ParseError::MalformedRequestLine.response().http_type.code(), 400);
+        assert_eq!(ParseError::BodyTooLarge.response().http_type.code(), 413);
+        assert_eq!(ParseError::UriTooLong.response().http_type.code(), 414);
+        assert_eq!(ParseError::HeadersTooLarge.response().http_type.code(), 431);
+        assert_eq!(ParseError::UnsupportedVersion.response().http_type.code(), 505);
+    }
+    #[test]
+    fn test_request_without_host() {
+        let r = Request::from_request_data("GET /index.html?a=b HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(r.path, "/index.html");
+        assert_eq!(r.get["a"], "b");
+    }
+    #[test]
+    fn test_request_host_case_insensitive() {
+        let r = Request::from_request_data("GET / HTTP/1.1\r\nhost: example.com\r\n\r\n").unwrap();
+        assert_eq!(r.header_value("HOST"), Some("example.com"));
+        assert_eq!(r.path, "/");
+    }
+    #[test]
+    fn test_request_invalid_host() {
+        let err = |data: &str| Request::from_request_data(data).err().unwrap();
+        assert_eq!(err("GET / HTTP/1.1\r\nHost: evil.com/x\r\n\r\n"), ParseError::InvalidHost);
+        assert_eq!(err("GET / HTTP/1.1\r\nHost: user@evil.com\r\n\r\n"), ParseError::InvalidHost);
+        assert_eq!(err("GET / HTTP/1.1\r\nHost: localhost:99999\r\n\r\n"), ParseError::InvalidHost);
+    }
+    #[test]
+    fn test_request_invalid_target() {
+        assert_eq!(Request::from_request_data("GET index.html HTTP/1.1\r\nHost: localhost\r\n\r\n").err(), Some(ParseError::InvalidTarget));
+        let r = Request::from_request_data("GET /a/../../etc/passwd HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert_eq!(r.path, "/etc/passwd");
     }
     fn get_request_path(method: &str, path: &str) -> String {
         format!("{} {} HTTP/1.1
@@ -1,3 +1,4 @@
+use std::time::Duration;
 use crate::context::{Response, HttpResponseType};
 impl Response {
     pub fn ok_text(response_html: &str) -> Response {
@@ -32,6 +33,20 @@ impl Response {
             "text/html"
         )
     }
+    pub fn bad_request() -> Response {
+        Response::error(HttpResponseType::BadRequest)
+    }
+    /// A short html error page for the given status, using its standard reason phrase.
+    pub fn error(http_type: HttpResponseType) -> Response {
+        let status = format!("{} {}", http_type.code(), http_type.reason());
+        let reason = http_type.reason();
+        Response::new(
+            http_type, 
+            reason, 
+            format!("<!DOCTYPE html><html><head><title>{}</title></head><body><h1>{}</h1></body></html>", status, status).into_bytes(),
+            "text/html"
+        )
+    }
     pub fn none() -> Response {
         Response::new(
             HttpResponseType::None, 
@@ -50,18 +65,38 @@ impl Response {
     }
     pub fn internal_error() -> Response {
         Response::new(
-            HttpResponseType::NotFound, 
+            HttpResponseType::InternalError, 
             "Internal server error", 
             String::from("<!DOCTYPE html><html><head><title>500 Internal server error</title></head><body><h1>500 Internal server error</h1></body></html>").into_bytes(),
             "text/html"
         )
     }
+    /// 503 telling the client to try again after `retry_after`.
+    pub fn service_unavailable(retry_after: Duration) -> Response {
+        Response::error(HttpResponseType::ServiceUnavailable)
+            .with_header("Retry-After", std::cmp::max(retry_after.as_secs(), 1).to_string().as_str())
+    }
+    /// 301 sending the client to `location`. Clients may repeat a POST there as a GET;
+    /// use `permanent_redirect` to keep the method.
+    pub fn moved_permanently(location: &str) -> Response {
+        Response::error(HttpResponseType::MovedPermanently).with_header("Location", location)
+    }
+    /// 308 sending the client to `location` with the same method and body.
+    pub fn permanent_redirect(location: &str) -> Response {
+        Response::error(HttpResponseType::PermanentRedirect).with_header("Location", location)
+    }
+    /// Adds a header to the response.
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((String::from(name), String::from(value)));
+        self
+    }
     pub fn new(http_type: HttpResponseType, text: &str, data: Vec<u8>, mime: &str) -> Response {
         Response {
-            http_type : http_type,
+            http_type,
             text : String::from(text),
-            data : data,
+            data,
             mime : String::from(mime),
+            headers : Vec::new(),
         }
 
     }
@@ -0,0 +1,292 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::context::Request;
+use crate::transport::Transport;
+use crate::{threadpool, HttpListener, ServerHandle, Settings};
+
+/// How long an event stream may go without sending anything before a keep-alive comment,
+/// unless configured otherwise. Well under the idle timeouts of common proxies.
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// How often `EventStream::wait` checks whether the client has gone or the server is
+/// shutting down.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A Server-Sent Events handler, as registered with `HttpListener::event_stream`. It
+/// runs on the connection's worker for as long as it sends events; returning ends the stream.
+pub type EventStreamHandler = fn(request: &Request, stream: &mut EventStream);
+
+/// One Server-Sent Event.
+///
+/// ```
+/// # use rweblet::event_stream::Event;
+/// # use std::time::Duration;
+/// let event = Event::new("{\"cpu\": 12}").event("stats").id("42").retry(Duration::from_secs(5));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    data: String,
+    id: Option<String>,
+    event: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    /// An event carrying `data`, which may span several lines.
+    pub fn new(data: &str) -> Event {
+        Event { data: String::from(data), id: None, event: None, retry: None }
+    }
+    /// The id the client sends back as `Last-Event-ID` when it reconnects.
+    pub fn id(mut self, id: &str) -> Event {
+        self.id = Some(single_line(id));
+        self
+    }
+    /// The event type, which browsers dispatch to `addEventListener(type, ...)` instead
+    /// of `onmessage`.
+    pub fn event(mut self, event: &str) -> Event {
+        self.event = Some(single_line(event));
+        self
+    }
+    /// How long the client waits before reconnecting if the stream drops.
+    pub fn retry(mut self, retry: Duration) -> Event {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// The event in `text/event-stream` format, ending with the blank line that dispatches it.
+    fn encode(&self) -> String {
+        let mut encoded = String::new();
+        if let Some(event) = &self.event {
+            encoded.push_str(format!("event: {}\n", event).as_str());
+        }
+        if let Some(id) = &self.id {
+            encoded.push_str(format!("id: {}\n", id).as_str());
+        }
+        if let Some(retry) = self.retry {
+            encoded.push_str(format!("retry: {}\n", retry.as_millis()).as_str());
+        }
+        //Each line is its own data field; the client joins them with newlines again
+        for line in self.data.split('\n') {
+            encoded.push_str(format!("data: {}\n", line.trim_end_matches('\r')).as_str());
+        }
+        encoded.push('\n');
+        encoded
+    }
+}
+
+/// Field values other than data cannot contain line breaks, which would start a new field.
+fn single_line(value: &str) -> String {
+    value.chars().filter(|c| *c != '\n' && *c != '\r').collect()
+}
+
+/// An open `text/event-stream` response, handed to an `EventStreamHandler`.
+///
+/// ```no_run
+/// # use rweblet::{HttpListener, Request};
+/// # use rweblet::event_stream::{Event, EventStream};
+/// # use std::time::Duration;
+/// fn clock(_request: &Request, stream: &mut EventStream) {
+///     //Resume the count where a reconnecting client left off
+///     let mut tick: u64 = stream.last_event_id().and_then(|id| id.parse().ok()).unwrap_or(0);
+///     loop {
+///         tick += 1;
+///         let event = Event::new(&tick.to_string()).id(&tick.to_string());
+///         if stream.send(&event).is_err() || stream.wait(Duration::from_secs(1)).is_err() {
+///             break;
+///         }
+///     }
+/// }
+///
+/// let mut listener = HttpListener::new();
+/// listener.event_stream("^/clock$", clock);
+/// listener.start("0.0.0.0:8080").unwrap();
+/// ```
+pub struct EventStream {
+    transport: Box<dyn Transport>,
+    last_event_id: Option<String>,
+    keep_alive: Duration,
+    last_write: Instant,
+    server: Option<ServerHandle>,
+}
+
+impl EventStream {
+    fn new(transport: Box<dyn Transport>, request: &Request, keep_alive: Duration, server: Option<ServerHandle>) -> EventStream {
+        EventStream {
+            transport,
+            last_event_id: request.header_value("Last-Event-ID").map(String::from),
+            keep_alive,
+            last_write: Instant::now(),
+            server,
+        }
+    }
+
+    /// The id of the last event a reconnecting client received, so the handler can
+    /// send only what it missed.
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+
+    /// Sends `event`. Fails once the client has gone or the server is shutting down,
+    /// which is the handler's cue to return.
+    pub fn send(&mut self, event: &Event) -> io::Result<()> {
+        self.write(event.encode().as_bytes())
+    }
+
+    /// Waits for `duration` between events, sending keep-alive comments so proxies do
+    /// not close the idle connection. Fails early if the client has gone or the server
+    /// is shutting down.
+    pub fn wait(&mut self, duration: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + duration;
+        loop {
+            self.check_running()?;
+            self.check_connected()?;
+            if self.last_write.elapsed() >= self.keep_alive {
+                self.write(b": keep-alive\n\n")?;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            let next_keep_alive = (self.last_write + self.keep_alive).saturating_duration_since(now);
+            thread::sleep((deadline - now).min(POLL_INTERVAL).min(next_keep_alive));
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.check_running()?;
+        self.transport.write_all(data)?;
+        self.transport.flush()?;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+
+    /// Notices a client that hung up without waiting for the next write to fail. The
+    /// client has nothing to send on an event stream, so anything it does is discarded.
+    fn check_connected(&mut self) -> io::Result<()> {
+        self.transport.set_nonblocking(true)?;
+        let mut discard = [0; 512];
+        let read = self.transport.read(&mut discard);
+        self.transport.set_nonblocking(false)?;
+        match read {
+            Ok(0) => Err(io::Error::new(ErrorKind::ConnectionAborted, "client has gone")),
+            Ok(_) => Ok(()),
+            Err(ref e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn check_running(&self) -> io::Result<()> {
+        if self.server.as_ref().is_some_and(|server| server.is_shutting_down()) {
+            return Err(io::Error::new(ErrorKind::ConnectionAborted, "server is shutting down"));
+        }
+        Ok(())
+    }
+}
+
+/// Starts the event stream response and runs `handler` until it returns. The stream is
+/// delimited by closing the connection, so nothing else is served on it.
+pub(crate) fn serve(mut transport: Box<dyn Transport>, request: Request, handler: EventStreamHandler, settings: &Settings) {
+    let head = format!("{} 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n", request.version.as_str());
+    if transport.write_all(head.as_bytes()).and_then(|_| transport.flush()).is_err() {
+        HttpListener::log("Failed writing event stream headers");
+        return;
+    }
+
+    let mut stream = EventStream::new(transport, &request, settings.event_stream_keep_alive, Some(settings.server.clone()));
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| handler(&request, &mut stream))) {
+        HttpListener::log_error(format!("Event stream handler for {} panicked: {}", request.path, threadpool::panic_message(&payload)).as_str());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_event_encoding() {
+        let event = Event::new("line one\nline two").event("up\ndate").id("7").retry(Duration::from_millis(1500));
+        assert_eq!(event.encode(), "event: update\nid: 7\nretry: 1500\ndata: line one\ndata: line two\n\n");
+        assert_eq!(Event::new("").encode(), "data: \n\n");
+    }
+
+    fn resume(_request: &Request, stream: &mut EventStream) {
+        let first: u32 = stream.last_event_id().and_then(|id| id.parse().ok()).map_or(1, |id: u32| id + 1);
+        for id in first..first + 2 {
+            stream.send(&Event::new("tick").id(&id.to_string())).unwrap();
+        }
+        let _ = stream.wait(Duration::from_millis(150));
+    }
+
+    #[test]
+    fn test_stream_resumes_and_keeps_alive() {
+        let mut listener = HttpListener::new();
+        listener.event_stream("^/events", resume);
+        listener.event_stream_keep_alive(Duration::from_millis(50));
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+
+        let mut client = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+        client.write_all(b"GET /events HTTP/1.1\r\nHost: localhost\r\nLast-Event-ID: 41\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n"));
+        assert!(response.contains("id: 42\ndata: tick\n\nid: 43\ndata: tick\n\n"));
+        assert!(response.ends_with(": keep-alive\n\n"));
+        handle.shutdown();
+    }
+
+    fn forever(_request: &Request, stream: &mut EventStream) {
+        while stream.wait(Duration::from_secs(60)).is_ok() {}
+    }
+
+    #[test]
+    fn test_shutdown_ends_streams() {
+        let mut listener = HttpListener::new();
+        listener.event_stream("^/events", forever);
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+
+        let mut client = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+        client.write_all(b"GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut head = [0; 15];
+        client.read_exact(&mut head).unwrap();
+        assert_eq!(&head, b"HTTP/1.1 200 OK");
+
+        let started = Instant::now();
+        handle.shutdown();
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    static HUNG_UP: AtomicBool = AtomicBool::new(false);
+
+    fn long_wait(_request: &Request, stream: &mut EventStream) {
+        if stream.wait(Duration::from_secs(30)).is_err() {
+            HUNG_UP.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_wait_notices_client_hanging_up() {
+        let mut listener = HttpListener::new();
+        listener.event_stream("^/events", long_wait);
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+
+        let mut client = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+        client.write_all(b"GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut head = [0; 16];
+        client.read_exact(&mut head).unwrap();
+        drop(client);
+
+        //Well before the wait or the first keep-alive would end it
+        let started = Instant::now();
+        while !HUNG_UP.load(Ordering::SeqCst) && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(HUNG_UP.load(Ordering::SeqCst));
+        handle.shutdown();
+    }
+}
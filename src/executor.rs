@@ -0,0 +1,331 @@
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::HttpListener;
+use crate::threadpool::ThreadPool;
+
+/// Runs work in the background, outside the request that started it.
+///
+/// Handlers reach the server's executor through `Context::executor` to fire and forget
+/// jobs such as sending an email, or to schedule them for later or at a fixed interval.
+/// When the server shuts down gracefully it waits for jobs already started or due to
+/// finish; delayed jobs that are not yet due and periodic tasks are cancelled.
+///
+/// ```
+/// # use rweblet::Executor;
+/// # use std::time::Duration;
+/// let executor = Executor::new(2);
+/// executor.spawn(|| println!("sent"));
+/// let task = executor.spawn_every(Duration::from_secs(60), || println!("tick"));
+/// task.cancel();
+/// executor.shutdown();
+/// ```
+#[derive(Clone)]
+pub struct Executor {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    pool: Arc<Mutex<Option<ThreadPool>>>,
+    timers: Arc<Timers>,
+    timer_thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+/// Jobs waiting for their time to run, earliest first.
+struct Timers {
+    queue: Mutex<TimerQueue>,
+    changed: Condvar,
+}
+
+struct TimerQueue {
+    entries: BinaryHeap<Timer>,
+    next_seq: u64,
+    stopped: bool,
+}
+
+struct Timer {
+    due: Instant,
+    seq: u64,
+    cancelled: Arc<AtomicBool>,
+    job: TimerJob,
+}
+
+enum TimerJob {
+    Once(Box<dyn FnOnce() + Send + 'static>),
+    Every(Duration, Arc<dyn Fn() + Send + Sync + 'static>),
+}
+
+/// A delayed or periodic job, which can be cancelled until it runs.
+#[derive(Clone)]
+pub struct ScheduledTask {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl ScheduledTask {
+    /// Stops the job from running again. A run already in progress is not interrupted.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl Executor {
+    /// Creates an executor running jobs on `threads` worker threads.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `threads` is zero.
+    pub fn new(threads: usize) -> Executor {
+        Executor {
+            inner: Arc::new(Inner {
+                pool: Arc::new(Mutex::new(Some(ThreadPool::new(threads)))),
+                timers: Arc::new(Timers {
+                    queue: Mutex::new(TimerQueue { entries: BinaryHeap::new(), next_seq: 0, stopped: false }),
+                    changed: Condvar::new(),
+                }),
+                timer_thread: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Runs `job` on the next free background thread.
+    pub fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static {
+        submit(&self.inner.pool, job);
+    }
+
+    /// Runs `job` once `delay` has passed.
+    ///
+    /// A job that is not yet due when the executor shuts down never runs; its task reads
+    /// as cancelled, and the shutdown logs how many such jobs it dropped.
+    pub fn spawn_after<F>(&self, delay: Duration, job: F) -> ScheduledTask
+    where
+        F: FnOnce() + Send + 'static {
+        self.schedule(Instant::now() + delay, TimerJob::Once(Box::new(job)))
+    }
+
+    /// Runs `job` every `period`, starting one period from now, until the returned task
+    /// is cancelled or the executor shuts down.
+    pub fn spawn_every<F>(&self, period: Duration, job: F) -> ScheduledTask
+    where
+        F: Fn() + Send + Sync + 'static {
+        assert!(period.as_millis() > 0);
+        self.schedule(Instant::now() + period, TimerJob::Every(period, Arc::new(job)))
+    }
+
+    /// Cancels everything scheduled and waits for the jobs already running or queued.
+    /// Delayed jobs that are not yet due are dropped without running.
+    pub fn shutdown(&self) {
+        self.inner.shutdown();
+    }
+
+    fn schedule(&self, due: Instant, job: TimerJob) -> ScheduledTask {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mut queue = self.inner.timers.lock();
+        if queue.stopped {
+            HttpListener::log("Executor is shut down, dropping scheduled job");
+            cancelled.store(true, Ordering::SeqCst);
+            return ScheduledTask { cancelled };
+        }
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        queue.entries.push(Timer { due, seq, cancelled: Arc::clone(&cancelled), job });
+        drop(queue);
+        self.inner.timers.changed.notify_all();
+        self.inner.start_timer_thread();
+        ScheduledTask { cancelled }
+    }
+}
+
+impl Inner {
+    /// The timer thread is only started once something is scheduled.
+    fn start_timer_thread(&self) {
+        let mut timer_thread = self.timer_thread.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if timer_thread.is_some() {
+            return;
+        }
+        let timers = Arc::clone(&self.timers);
+        let pool = Arc::clone(&self.pool);
+        let spawned = thread::Builder::new()
+            .name(String::from("rweblet-timer"))
+            .spawn(move || run_timers(&timers, &pool));
+        match spawned {
+            Ok(thread) => *timer_thread = Some(thread),
+            Err(e) => HttpListener::log(format!("Failed to start timer thread: {}", e).as_str()),
+        }
+    }
+
+    fn shutdown(&self) {
+        {
+            let mut queue = self.timers.lock();
+            queue.stopped = true;
+            let mut dropped = 0;
+            for timer in queue.entries.drain() {
+                if !timer.cancelled.swap(true, Ordering::SeqCst) && matches!(timer.job, TimerJob::Once(_)) {
+                    dropped += 1;
+                }
+            }
+            if dropped > 0 {
+                HttpListener::log_error(format!("Executor shut down before {} delayed jobs were due, dropping them", dropped).as_str());
+            }
+        }
+        self.timers.changed.notify_all();
+        let timer_thread = self.timer_thread.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+        if let Some(thread) = timer_thread {
+            let _ = thread.join();
+        }
+        //Dropping the pool waits for the queued jobs
+        let pool = self.pool.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+        drop(pool);
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl Timers {
+    fn lock(&self) -> MutexGuard<'_, TimerQueue> {
+        self.queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Hands each timer to the pool when it is due, rescheduling periodic ones.
+fn run_timers(timers: &Timers, pool: &Mutex<Option<ThreadPool>>) {
+    let mut queue = timers.lock();
+    loop {
+        if queue.stopped {
+            return;
+        }
+        let now = Instant::now();
+        let due = match queue.entries.peek() {
+            Some(timer) if timer.due <= now => true,
+            Some(timer) => {
+                let wait = timer.due - now;
+                queue = match timers.changed.wait_timeout(queue, wait) {
+                    Ok((queue, _)) => queue,
+                    Err(poisoned) => poisoned.into_inner().0,
+                };
+                false
+            },
+            None => {
+                queue = match timers.changed.wait(queue) {
+                    Ok(queue) => queue,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                false
+            },
+        };
+        if !due {
+            continue;
+        }
+
+        let timer = match queue.entries.pop() {
+            Some(timer) => timer,
+            None => continue,
+        };
+        if timer.cancelled.load(Ordering::SeqCst) {
+            continue;
+        }
+        match timer.job {
+            TimerJob::Once(job) => submit(pool, job),
+            TimerJob::Every(period, job) => {
+                let run = Arc::clone(&job);
+                submit(pool, move || run());
+                let seq = queue.next_seq;
+                queue.next_seq += 1;
+                queue.entries.push(Timer { due: timer.due + period, seq, cancelled: timer.cancelled, job: TimerJob::Every(period, job) });
+            },
+        }
+    }
+}
+
+fn submit<F>(pool: &Mutex<Option<ThreadPool>>, job: F)
+where
+    F: FnOnce() + Send + 'static {
+    match &*pool.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+        Some(pool) => pool.execute(job),
+        None => HttpListener::log("Executor is shut down, dropping job"),
+    }
+}
+
+//BinaryHeap is a max-heap, so the earliest timer compares greatest
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        other.due.cmp(&self.due).then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.due == other.due && self.seq == other.seq
+    }
+}
+impl Eq for Timer {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_spawn_after_waits_for_delay() {
+        let executor = Executor::new(1);
+        let (sender, done) = mpsc::channel();
+        let started = Instant::now();
+        executor.spawn_after(Duration::from_millis(100), move || sender.send(Instant::now()).unwrap());
+
+        let ran = done.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(ran - started >= Duration::from_millis(100));
+    }
+    #[test]
+    fn test_spawn_every_repeats_until_cancelled() {
+        let executor = Executor::new(1);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&runs);
+        let task = executor.spawn_every(Duration::from_millis(20), move || { counter.fetch_add(1, Ordering::SeqCst); });
+
+        let started = Instant::now();
+        while runs.load(Ordering::SeqCst) < 3 && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        task.cancel();
+        executor.shutdown();
+        let after_cancel = runs.load(Ordering::SeqCst);
+        assert!(after_cancel >= 3);
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(runs.load(Ordering::SeqCst), after_cancel);
+    }
+    #[test]
+    fn test_shutdown_drains_spawned_and_cancels_pending() {
+        let executor = Executor::new(1);
+        let runs = Arc::new(AtomicUsize::new(0));
+        for _ in 0..3 {
+            let runs = Arc::clone(&runs);
+            executor.spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                runs.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        let late = Arc::clone(&runs);
+        let pending = executor.spawn_after(Duration::from_secs(60), move || { late.fetch_add(100, Ordering::SeqCst); });
+
+        executor.shutdown();
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert!(pending.is_cancelled());
+        executor.spawn(|| panic!("must not run after shutdown"));
+    }
+}
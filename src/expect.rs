@@ -0,0 +1,126 @@
+use std::panic::{self, AssertUnwindSafe};
+use crate::context::{HttpResponseType, HttpVersion, Request, Response};
+use crate::{threadpool, HttpListener, Settings};
+
+/// The interim response asking the client to go ahead with its body.
+pub(crate) const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// Decides from the request line and headers alone whether a client that sent
+/// `Expect: 100-continue` may send its body, as registered with `HttpListener::expect_continue`.
+/// `Err` carries the final response sent instead, such as a 413.
+pub type ExpectHandler = fn(request: &Request) -> Result<(), Response>;
+
+/// What to do about a request whose headers have arrived but whose body has not.
+pub(crate) enum Expectation {
+    /// The client did not ask to be told, so its body is simply read.
+    None,
+    /// Send `100 Continue`, then read the body.
+    Continue,
+    /// Answer with this response without reading the body.
+    Reject(Response),
+}
+
+/// Checks the Expect header of `request`. HTTP/1.0 clients do not know interim responses,
+/// so their expectations are ignored.
+pub(crate) fn expectation(request: &Request, settings: &Settings) -> Expectation {
+    let expect = match request.header_value("Expect") {
+        Some(expect) if request.version != HttpVersion::Http10 => expect,
+        _ => return Expectation::None,
+    };
+    if !expect.trim().eq_ignore_ascii_case("100-continue") {
+        return Expectation::Reject(Response::error(HttpResponseType::ExpectationFailed));
+    }
+    let check = match settings.expect_routes.find(&request.path) {
+        Some(check) => *check,
+        None => return Expectation::Continue,
+    };
+    match panic::catch_unwind(AssertUnwindSafe(|| check(request))) {
+        Ok(Ok(())) => Expectation::Continue,
+        Ok(Err(response)) => Expectation::Reject(response),
+        Err(payload) => {
+            HttpListener::log_error(format!("Expect handler for {} panicked: {}", request.path, threadpool::panic_message(&payload)).as_str());
+            Expectation::Reject(Response::internal_error())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpStream};
+    use std::time::Duration;
+    use crate::Context;
+    use crate::transport::MemoryTransport;
+
+    fn upload(context: &Context) -> Response {
+        Response::ok_text(format!("stored {} bytes", context.request.body.len()).as_str())
+    }
+    fn small_uploads(request: &Request) -> Result<(), Response> {
+        match request.header_value("Content-Length").and_then(|length| length.parse::<usize>().ok()) {
+            Some(length) if length <= 10 => Ok(()),
+            _ => Err(Response::error(HttpResponseType::PayloadTooLarge)),
+        }
+    }
+
+    fn client() -> (TcpStream, crate::ServerHandle) {
+        let mut listener = HttpListener::new();
+        listener.route("^/upload$", upload);
+        listener.expect_continue("^/upload$", small_uploads);
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (client, handle)
+    }
+
+    #[test]
+    fn test_continue_before_body() {
+        let (mut client, handle) = client();
+        client.write_all(b"POST /upload HTTP/1.1\r\nHost: a\r\nExpect: 100-continue\r\nContent-Type: text/plain\r\nContent-Length: 5\r\nConnection: close\r\n\r\n").unwrap();
+        let mut interim = [0; 25];
+        client.read_exact(&mut interim).unwrap();
+        assert_eq!(&interim[..], CONTINUE);
+
+        client.write_all(b"hello").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("stored 5 bytes"));
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_rejected_without_reading_body() {
+        let (mut client, handle) = client();
+        //A client that sends its body without waiting still gets the response
+        client.write_all(b"POST /upload HTTP/1.1\r\nHost: a\r\nExpect: 100-continue\r\nContent-Length: 500000\r\n\r\n").unwrap();
+        client.write_all(&vec![b'x'; 500000]).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large"));
+        assert!(response.contains("Connection: close"));
+
+        let mut client = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+        client.write_all(b"POST /upload HTTP/1.1\r\nHost: a\r\nExpect: something-else\r\nContent-Length: 5\r\n\r\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 417 Expectation Failed"));
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_complete_requests_are_checked() {
+        let mut listener = HttpListener::new();
+        listener.route("^/upload$", upload);
+        listener.expect_continue("^/upload$", small_uploads);
+        let (transport, output) = MemoryTransport::new(b"POST /upload HTTP/1.1\r\nHost: a\r\nExpect: 100-continue\r\nContent-Length: 20\r\n\r\n01234567890123456789\
+            POST /upload HTTP/1.1\r\nHost: a\r\nExpect: 100-continue\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello");
+        listener.serve_transport(Box::new(transport));
+        let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(output.starts_with("HTTP/1.1 413 Payload Too Large"));
+        assert!(!output.contains("100 Continue"));
+        assert!(output.ends_with("stored 5 bytes"));
+    }
+}
@@ -0,0 +1,1108 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::connection::{non_zero, Phase};
+use crate::context::{Context, CapturedResponse, HttpResponseType, HttpVersion, Request, Response};
+use crate::context::parser::{content_length_value, RequestParser};
+use crate::context::request::{MAX_BODY_SIZE, MAX_HEADER_SIZE};
+use crate::transport::Transport;
+use crate::proxy;
+use crate::{HttpListener, Settings};
+mod hpack; //include http2/hpack.rs
+
+/// What an HTTP/2 client sends before its first frame (RFC 9113 section 3.4), whether it
+/// negotiated HTTP/2 with ALPN or assumes it by prior knowledge.
+pub(crate) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_HEADER_SIZE: usize = 9;
+/// Largest frame payload either side may send until the peer allows more.
+const DEFAULT_MAX_FRAME_SIZE: usize = 16384;
+const MAX_FRAME_SIZE_LIMIT: usize = (1 << 24) - 1;
+const DEFAULT_WINDOW_SIZE: i64 = 65535;
+const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+/// Streams a client may have open at once.
+const MAX_CONCURRENT_STREAMS: u32 = 16;
+/// Request body bytes a client may send on a connection before they are served, which
+/// bounds the bodies held in memory for its streams together. Room for one full body and
+/// then some, so a single upload can always complete.
+const CONNECTION_WINDOW: i64 = 2 * MAX_BODY_SIZE as i64;
+/// Largest header list accepted, compressed or not, before answering 431.
+const MAX_HEADER_LIST_SIZE: usize = 4 * MAX_HEADER_SIZE;
+/// How often a connection waiting for frames checks whether the server is shutting down.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+//Frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+//Frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+//Settings
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+//Error codes
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const HTTP_1_1_REQUIRED: u32 = 0xd;
+
+/// Headers that only mean something to a single HTTP/1.1 connection, which HTTP/2
+/// requests must not carry and responses leave out.
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// How much of the connection preface has been received.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Preface {
+    /// The connection speaks something else, normally HTTP/1.x.
+    Absent,
+    /// The data so far is the start of the preface.
+    Partial,
+    Complete,
+}
+
+/// Checks whether the first bytes received on a connection are the HTTP/2 preface.
+pub(crate) fn preface(data: &[u8]) -> Preface {
+    if data.len() >= PREFACE.len() {
+        if data.starts_with(PREFACE) { Preface::Complete } else { Preface::Absent }
+    } else if !data.is_empty() && PREFACE.starts_with(data) {
+        Preface::Partial
+    } else {
+        Preface::Absent
+    }
+}
+
+/// Why a connection ends before the client closes it.
+enum Error {
+    /// The transport failed, so nothing more can be sent.
+    Io,
+    /// The client broke the protocol; the code is sent in a GOAWAY frame.
+    Protocol(u32, &'static str),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(_: std::io::Error) -> Error {
+        Error::Io
+    }
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+/// The request pseudo-headers and fields of a stream, checked to be valid HTTP/2.
+struct Head {
+    method: String,
+    path: String,
+    authority: Option<String>,
+    fields: Vec<(String, String)>,
+    content_length: Option<usize>,
+}
+
+struct Stream {
+    /// `None` once the request has been queued for a response.
+    head: Option<Head>,
+    body: Vec<u8>,
+    /// Whether the client has finished sending on this stream.
+    remote_closed: bool,
+    /// How much response body the client is ready to receive.
+    send_window: i64,
+    /// How much request body the client may still send.
+    recv_window: i64,
+    /// Bytes of the request body counted against the connection window until the stream
+    /// is done with.
+    held: usize,
+}
+
+/// One HTTP/2 connection, serving its streams one at a time through the normal routes.
+struct Session<'a> {
+    transport: Box<dyn Transport>,
+    peer: Option<SocketAddr>,
+    settings: &'a Arc<Settings>,
+    default_host: String,
+    secure: bool,
+    /// Bytes received but not yet taken out as frames.
+    received: Vec<u8>,
+    decoder: hpack::Decoder,
+    streams: HashMap<u32, Stream>,
+    /// Complete requests in the order they finished arriving, or the error to answer with.
+    ready: VecDeque<(u32, Result<Request, Response>)>,
+    /// A header block still being continued: its stream, the fragments so far and its flags.
+    continuation: Option<(u32, Vec<u8>, u8)>,
+    /// The highest stream the client has opened.
+    last_stream_id: u32,
+    /// Set once either side has sent GOAWAY: the last stream that will be served.
+    going_away: Option<u32>,
+    send_window: i64,
+    initial_window: i64,
+    /// How much request body the client may still send across all streams.
+    recv_window: i64,
+    /// Connection window given back since the last WINDOW_UPDATE was sent.
+    released: usize,
+    max_frame_size: usize,
+    served: usize,
+}
+
+/// Serves an HTTP/2 connection whose preface has arrived, until the client closes it, a
+/// timeout or the request limit ends it, or the server shuts down.
+///
+/// Each request becomes a normal `Request`, routed and answered as it would be over
+/// HTTP/1.1, so handlers do not know which protocol they are serving. Streams are served
+/// one at a time in the order their requests complete, so a slow handler holds up the
+/// other streams of its connection. WebSocket and event stream routes are refused with
+/// HTTP_1_1_REQUIRED, which makes browsers retry them over HTTP/1.1.
+pub(crate) fn serve(transport: Box<dyn Transport>, mut buffered: Vec<u8>, settings: &Arc<Settings>, default_host: &str) {
+    buffered.drain(..PREFACE.len().min(buffered.len()));
+    let secure = transport.secure();
+    let peer = transport.peer_addr();
+    let mut session = Session {
+        transport,
+        peer,
+        settings,
+        default_host: String::from(default_host),
+        secure,
+        received: buffered,
+        decoder: hpack::Decoder::new(),
+        streams: HashMap::new(),
+        ready: VecDeque::new(),
+        continuation: None,
+        last_stream_id: 0,
+        going_away: None,
+        send_window: DEFAULT_WINDOW_SIZE,
+        initial_window: DEFAULT_WINDOW_SIZE,
+        recv_window: CONNECTION_WINDOW,
+        released: 0,
+        max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        served: 0,
+    };
+    match session.run() {
+        Ok(()) | Err(Error::Io) => (),
+        Err(Error::Protocol(code, message)) => {
+            HttpListener::log(format!("HTTP/2 connection error: {}", message).as_str());
+            let _ = session.go_away(code, message.as_bytes());
+        },
+    }
+    let _ = session.transport.flush();
+}
+
+impl<'a> Session<'a> {
+    fn run(&mut self) -> Result<(), Error> {
+        let mut initial = Vec::new();
+        for (id, value) in &[
+            (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_CONCURRENT_STREAMS),
+            (SETTINGS_MAX_HEADER_LIST_SIZE, MAX_HEADER_LIST_SIZE as u32),
+        ] {
+            initial.extend_from_slice(&id.to_be_bytes());
+            initial.extend_from_slice(&value.to_be_bytes());
+        }
+        self.write_frame(SETTINGS, 0, 0, &initial)?;
+        self.write_window_update(0, (CONNECTION_WINDOW - DEFAULT_WINDOW_SIZE) as usize)?;
+
+        let mut phase = Phase::Idle;
+        let mut phase_started = Instant::now();
+        loop {
+            while let Some((id, request)) = self.ready.pop_front() {
+                //The client may have reset the stream while it waited
+                if self.streams.contains_key(&id) {
+                    self.respond(id, request)?;
+                    phase_started = Instant::now();
+                }
+            }
+            if self.going_away.is_some() && self.streams.is_empty() {
+                return Ok(());
+            }
+            if self.settings.server.is_shutting_down() {
+                return self.go_away(NO_ERROR, b"");
+            }
+
+            let current = if self.streams.values().any(|stream| !stream.remote_closed) { Phase::Body } else { Phase::Idle };
+            if current != phase {
+                phase = current;
+                phase_started = Instant::now();
+            }
+            let remaining = match phase.timeout(self.served, self.settings).checked_sub(phase_started.elapsed()) {
+                Some(remaining) if remaining.as_millis() > 0 => remaining,
+                _ => {
+                    HttpListener::log(if phase == Phase::Idle { "Closing idle connection" } else { "Timed out reading request" });
+                    return self.go_away(NO_ERROR, b"");
+                }
+            };
+            if let Some(frame) = self.read_frame(remaining.min(POLL_INTERVAL))? {
+                self.handle_frame(frame)?;
+            }
+        }
+    }
+
+    /// Runs the request through the routes and sends the response on its stream.
+    fn respond(&mut self, id: u32, request: Result<Request, Response>) -> Result<(), Error> {
+        let context = match request {
+            Ok(mut request) => {
+                self.served += 1;
+                proxy::resolve(&mut request, self.peer, &self.settings.trusted_proxies);
+                let mut context = Context::captured(request);
+                context.peer_addr = self.peer;
+                context.server = Some(self.settings.server.clone());
+                context.executor = self.settings.executor.clone();
+                if self.secure {
+                    context.hsts = self.settings.hsts.clone();
+                }
+                HttpListener::process(&mut context, Arc::clone(self.settings), self.served);
+                context
+            },
+            Err(response) => {
+                let mut context = Context::captured(Request::empty());
+                context.write_response(response);
+                context
+            },
+        };
+        match context.into_captured() {
+            Some(response) => self.send_response(id, response)?,
+            None => self.reset(id, INTERNAL_ERROR)?,
+        }
+        //No more streams after this one once the connection has served its share
+        if self.served >= self.settings.max_requests && self.going_away.is_none() {
+            self.go_away(NO_ERROR, b"")?;
+        }
+        Ok(())
+    }
+
+    fn send_response(&mut self, id: u32, response: CapturedResponse) -> Result<(), Error> {
+        let headers: Vec<(String, String)> = response.headers.iter()
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.clone()))
+            .filter(|(name, _)| !CONNECTION_HEADERS.contains(&name.as_str()))
+            .collect();
+        let block = hpack::encode(response.status, &headers);
+        let body = response.body;
+
+        let mut fragments = block.chunks(self.max_frame_size).peekable();
+        let mut kind = HEADERS;
+        let mut flags = if body.is_empty() { END_STREAM } else { 0 };
+        while let Some(fragment) = fragments.next() {
+            if fragments.peek().is_none() {
+                flags |= END_HEADERS;
+            }
+            self.write_frame(kind, flags, id, fragment)?;
+            kind = CONTINUATION;
+            flags = 0;
+        }
+
+        let mut sent = 0;
+        while sent < body.len() {
+            let stream_window = match self.streams.get(&id) {
+                Some(stream) => stream.send_window,
+                //Reset by the client while waiting for its window
+                None => return Ok(()),
+            };
+            let available = self.send_window.min(stream_window).min(self.max_frame_size as i64);
+            if available <= 0 {
+                self.wait_for_window(id)?;
+                continue;
+            }
+            let end = body.len().min(sent + available as usize);
+            let flags = if end == body.len() { END_STREAM } else { 0 };
+            self.write_frame(DATA, flags, id, &body[sent..end])?;
+            let length = (end - sent) as i64;
+            self.send_window -= length;
+            if let Some(stream) = self.streams.get_mut(&id) {
+                stream.send_window -= length;
+            }
+            sent = end;
+        }
+
+        //A client still sending a body it no longer needs to is told to stop
+        if let Some(stream) = self.remove_stream(id) {
+            if !stream.remote_closed {
+                self.write_frame(RST_STREAM, 0, id, &NO_ERROR.to_be_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads and handles frames until the client opens the flow control windows for
+    /// stream `id`, giving up after the write timeout as a blocked write would.
+    fn wait_for_window(&mut self, id: u32) -> Result<(), Error> {
+        let started = Instant::now();
+        loop {
+            match self.streams.get(&id) {
+                Some(stream) if stream.send_window <= 0 || self.send_window <= 0 => (),
+                _ => return Ok(()),
+            }
+            let remaining = match non_zero(self.settings.write_timeout) {
+                Some(timeout) => match timeout.checked_sub(started.elapsed()) {
+                    Some(remaining) if remaining.as_millis() > 0 => remaining,
+                    _ => {
+                        HttpListener::log("Timed out waiting for the client to accept more data");
+                        return Err(Error::Io);
+                    }
+                },
+                None => POLL_INTERVAL,
+            };
+            if let Some(frame) = self.read_frame(remaining.min(POLL_INTERVAL))? {
+                self.handle_frame(frame)?;
+            }
+        }
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<(), Error> {
+        if let Some((id, _, _)) = &self.continuation {
+            if frame.kind != CONTINUATION || frame.stream != *id {
+                return Err(Error::Protocol(PROTOCOL_ERROR, "header block interrupted"));
+            }
+        }
+        match frame.kind {
+            DATA => self.data(frame),
+            HEADERS => self.headers(frame),
+            CONTINUATION => self.continuation(frame),
+            PRIORITY => {
+                if frame.stream == 0 {
+                    return Err(Error::Protocol(PROTOCOL_ERROR, "PRIORITY on stream 0"));
+                }
+                if frame.payload.len() != 5 {
+                    return self.reset(frame.stream, FRAME_SIZE_ERROR);
+                }
+                Ok(())
+            },
+            RST_STREAM => {
+                if frame.stream == 0 || frame.stream > self.last_stream_id {
+                    return Err(Error::Protocol(PROTOCOL_ERROR, "RST_STREAM on an idle stream"));
+                }
+                if frame.payload.len() != 4 {
+                    return Err(Error::Protocol(FRAME_SIZE_ERROR, "bad RST_STREAM size"));
+                }
+                self.remove_stream(frame.stream);
+                Ok(())
+            },
+            SETTINGS => self.apply_settings(frame),
+            PUSH_PROMISE => Err(Error::Protocol(PROTOCOL_ERROR, "PUSH_PROMISE from a client")),
+            PING => {
+                if frame.stream != 0 {
+                    return Err(Error::Protocol(PROTOCOL_ERROR, "PING on a stream"));
+                }
+                if frame.payload.len() != 8 {
+                    return Err(Error::Protocol(FRAME_SIZE_ERROR, "bad PING size"));
+                }
+                if frame.flags & ACK == 0 {
+                    self.write_frame(PING, ACK, 0, &frame.payload)?;
+                }
+                Ok(())
+            },
+            GOAWAY => {
+                if frame.stream != 0 {
+                    return Err(Error::Protocol(PROTOCOL_ERROR, "GOAWAY on a stream"));
+                }
+                //Finish what the client has sent, then close
+                self.going_away.get_or_insert(self.last_stream_id);
+                Ok(())
+            },
+            WINDOW_UPDATE => self.window_update(frame),
+            //Unknown frame types are ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn data(&mut self, frame: Frame) -> Result<(), Error> {
+        if frame.stream == 0 {
+            return Err(Error::Protocol(PROTOCOL_ERROR, "DATA on stream 0"));
+        }
+        let id = frame.stream;
+        let flow = frame.payload.len();
+        if flow as i64 > self.recv_window {
+            return Err(Error::Protocol(FLOW_CONTROL_ERROR, "DATA beyond the connection window"));
+        }
+        self.recv_window -= flow as i64;
+        let data = unpadded(&frame, 0)?;
+
+        //Data that is not kept gives the connection window its space back straight away
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) if !stream.remote_closed => stream,
+            _ if id > self.last_stream_id => return Err(Error::Protocol(PROTOCOL_ERROR, "DATA on an idle stream")),
+            _ => {
+                self.released += flow;
+                return self.reset(id, STREAM_CLOSED);
+            },
+        };
+        if flow as i64 > stream.recv_window {
+            self.released += flow;
+            return self.reset(id, FLOW_CONTROL_ERROR);
+        }
+        stream.recv_window -= flow as i64;
+        let end_stream = frame.flags & END_STREAM != 0;
+        let too_large = stream.head.is_some() && stream.body.len() + data.len() > MAX_BODY_SIZE;
+        //A body that is refused already is discarded as it arrives
+        let kept = if stream.head.is_some() && !too_large {
+            stream.body.extend_from_slice(data);
+            data.len()
+        } else {
+            0
+        };
+        stream.held += kept;
+        if end_stream {
+            stream.remote_closed = true;
+        }
+        let complete = end_stream && stream.head.is_some();
+        self.released += flow - kept;
+
+        if too_large {
+            self.refuse(id, HttpResponseType::PayloadTooLarge);
+        } else if complete {
+            self.finish(id);
+        }
+        //The stream window is reopened at once, as the connection window bounds what is held
+        if !end_stream && flow > 0 {
+            if let Some(stream) = self.streams.get_mut(&id) {
+                stream.recv_window += flow as i64;
+                self.write_window_update(id, flow)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn headers(&mut self, frame: Frame) -> Result<(), Error> {
+        if frame.stream == 0 {
+            return Err(Error::Protocol(PROTOCOL_ERROR, "HEADERS on stream 0"));
+        }
+        let skip = if frame.flags & PRIORITY_FLAG != 0 { 5 } else { 0 };
+        let fragment = unpadded(&frame, skip)?.to_vec();
+        self.continuation = Some((frame.stream, Vec::new(), frame.flags));
+        self.append_fragment(&fragment, frame.flags)
+    }
+
+    fn continuation(&mut self, frame: Frame) -> Result<(), Error> {
+        if self.continuation.is_none() {
+            return Err(Error::Protocol(PROTOCOL_ERROR, "CONTINUATION without HEADERS"));
+        }
+        self.append_fragment(&frame.payload, frame.flags)
+    }
+
+    /// Collects a header block fragment, handling the block once it is complete.
+    fn append_fragment(&mut self, fragment: &[u8], flags: u8) -> Result<(), Error> {
+        let (id, block, first_flags) = match &mut self.continuation {
+            Some(continuation) => continuation,
+            None => return Ok(()),
+        };
+        block.extend_from_slice(fragment);
+        if block.len() > MAX_HEADER_LIST_SIZE {
+            return Err(Error::Protocol(COMPRESSION_ERROR, "header block too large"));
+        }
+        if flags & END_HEADERS == 0 {
+            return Ok(());
+        }
+        let (id, block, end_stream) = (*id, std::mem::take(block), *first_flags & END_STREAM != 0);
+        self.continuation = None;
+        self.header_block(id, &block, end_stream)
+    }
+
+    fn header_block(&mut self, id: u32, block: &[u8], end_stream: bool) -> Result<(), Error> {
+        //Every block is decoded, even for streams that are refused, to keep the table in step
+        let fields = self.decoder.decode(block, MAX_HEADER_LIST_SIZE)
+            .map_err(|_| Error::Protocol(COMPRESSION_ERROR, "undecodable header block"))?;
+
+        if let Some(stream) = self.streams.get_mut(&id) {
+            //Trailers, which end the request and are not passed on
+            if stream.remote_closed {
+                return Err(Error::Protocol(STREAM_CLOSED, "HEADERS on a closed stream"));
+            }
+            if !end_stream {
+                return self.reset(id, PROTOCOL_ERROR);
+            }
+            stream.remote_closed = true;
+            if stream.head.is_some() {
+                self.finish(id);
+            }
+            return Ok(());
+        }
+        if id <= self.last_stream_id {
+            return Err(Error::Protocol(STREAM_CLOSED, "HEADERS on a closed stream"));
+        }
+        if id.is_multiple_of(2) {
+            return Err(Error::Protocol(PROTOCOL_ERROR, "stream opened with an even id"));
+        }
+        self.last_stream_id = id;
+        if self.going_away.is_some_and(|last| id > last) {
+            return Ok(());
+        }
+        if self.streams.len() >= MAX_CONCURRENT_STREAMS as usize {
+            return self.reset(id, REFUSED_STREAM);
+        }
+
+        let head = match fields.map(Head::parse) {
+            Some(Ok(head)) => head,
+            Some(Err(message)) => {
+                HttpListener::log(format!("Malformed HTTP/2 request: {}", message).as_str());
+                return self.reset(id, PROTOCOL_ERROR);
+            },
+            None => {
+                self.open(id, None, end_stream);
+                self.refuse(id, HttpResponseType::RequestHeaderFieldsTooLarge);
+                return Ok(());
+            },
+        };
+        let too_large = head.content_length.is_some_and(|length| length > MAX_BODY_SIZE);
+        self.open(id, Some(head), end_stream);
+        if too_large {
+            self.refuse(id, HttpResponseType::PayloadTooLarge);
+        } else if end_stream {
+            self.finish(id);
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, id: u32, head: Option<Head>, end_stream: bool) {
+        let stream = Stream {
+            head,
+            body: Vec::new(),
+            remote_closed: end_stream,
+            send_window: self.initial_window,
+            recv_window: DEFAULT_WINDOW_SIZE,
+            held: 0,
+        };
+        self.streams.insert(id, stream);
+    }
+
+    /// Queues an error response for a stream whose request will not be served, ignoring
+    /// any more of its body.
+    fn refuse(&mut self, id: u32, http_type: HttpResponseType) {
+        if let Some(stream) = self.streams.get_mut(&id) {
+            stream.head = None;
+            stream.body = Vec::new();
+            self.released += std::mem::take(&mut stream.held);
+        }
+        self.ready.push_back((id, Err(Response::error(http_type))));
+    }
+
+    /// Turns a stream whose request has fully arrived into a `Request` and queues it.
+    fn finish(&mut self, id: u32) {
+        let (head, body) = match self.streams.get_mut(&id) {
+            Some(stream) => match stream.head.take() {
+                Some(head) => (head, std::mem::take(&mut stream.body)),
+                None => return,
+            },
+            None => return,
+        };
+        if head.content_length.is_some_and(|length| length != body.len()) {
+            HttpListener::log("Malformed HTTP/2 request: content-length does not match the body");
+            let _ = self.reset(id, PROTOCOL_ERROR);
+            return;
+        }
+
+        //The request goes through the same parser as HTTP/1.1, so it ends up identical
+        let mut parser = RequestParser::new(&self.default_host).secure(self.secure);
+        parser.feed(head.to_http1(body.len()).as_bytes());
+        parser.feed(&body);
+        let mut request = match parser.finish() {
+            Ok(request) => request,
+            Err(e) => {
+                HttpListener::log(format!("{}", e).as_str());
+                self.ready.push_back((id, Err(e.response())));
+                return;
+            }
+        };
+        request.version = HttpVersion::Http2;
+        if self.settings.websocket_routes.find(&request.path).is_some() || self.settings.event_stream_routes.find(&request.path).is_some() {
+            let _ = self.reset(id, HTTP_1_1_REQUIRED);
+            return;
+        }
+        self.ready.push_back((id, Ok(request)));
+    }
+
+    fn apply_settings(&mut self, frame: Frame) -> Result<(), Error> {
+        if frame.stream != 0 {
+            return Err(Error::Protocol(PROTOCOL_ERROR, "SETTINGS on a stream"));
+        }
+        if frame.flags & ACK != 0 {
+            if !frame.payload.is_empty() {
+                return Err(Error::Protocol(FRAME_SIZE_ERROR, "SETTINGS acknowledgement with a payload"));
+            }
+            return Ok(());
+        }
+        if !frame.payload.len().is_multiple_of(6) {
+            return Err(Error::Protocol(FRAME_SIZE_ERROR, "bad SETTINGS size"));
+        }
+        for setting in frame.payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => return Err(Error::Protocol(PROTOCOL_ERROR, "bad SETTINGS_ENABLE_PUSH")),
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW_SIZE {
+                        return Err(Error::Protocol(FLOW_CONTROL_ERROR, "bad SETTINGS_INITIAL_WINDOW_SIZE"));
+                    }
+                    //Open streams keep what they have used of their window
+                    let delta = value - self.initial_window;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW_SIZE {
+                            return Err(Error::Protocol(FLOW_CONTROL_ERROR, "stream window too large"));
+                        }
+                    }
+                    self.initial_window = value;
+                },
+                SETTINGS_MAX_FRAME_SIZE => {
+                    let value = value as usize;
+                    if !(DEFAULT_MAX_FRAME_SIZE..=MAX_FRAME_SIZE_LIMIT).contains(&value) {
+                        return Err(Error::Protocol(PROTOCOL_ERROR, "bad SETTINGS_MAX_FRAME_SIZE"));
+                    }
+                    self.max_frame_size = value;
+                },
+                //The encoder never uses the dynamic table, so its size does not matter
+                _ => (),
+            }
+        }
+        self.write_frame(SETTINGS, ACK, 0, &[])
+    }
+
+    fn window_update(&mut self, frame: Frame) -> Result<(), Error> {
+        if frame.payload.len() != 4 {
+            return Err(Error::Protocol(FRAME_SIZE_ERROR, "bad WINDOW_UPDATE size"));
+        }
+        let increment = (u32::from_be_bytes([frame.payload[0], frame.payload[1], frame.payload[2], frame.payload[3]]) & 0x7fff_ffff) as i64;
+        if frame.stream == 0 {
+            if increment == 0 {
+                return Err(Error::Protocol(PROTOCOL_ERROR, "empty WINDOW_UPDATE"));
+            }
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW_SIZE {
+                return Err(Error::Protocol(FLOW_CONTROL_ERROR, "connection window too large"));
+            }
+            return Ok(());
+        }
+        if frame.stream > self.last_stream_id {
+            return Err(Error::Protocol(PROTOCOL_ERROR, "WINDOW_UPDATE on an idle stream"));
+        }
+        let stream = match self.streams.get_mut(&frame.stream) {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        stream.send_window += increment;
+        if increment == 0 {
+            return self.reset(frame.stream, PROTOCOL_ERROR);
+        }
+        if stream.send_window > MAX_WINDOW_SIZE {
+            return self.reset(frame.stream, FLOW_CONTROL_ERROR);
+        }
+        Ok(())
+    }
+
+    /// Ends a single stream, leaving the rest of the connection as it is.
+    fn reset(&mut self, id: u32, code: u32) -> Result<(), Error> {
+        self.remove_stream(id);
+        self.write_frame(RST_STREAM, 0, id, &code.to_be_bytes())
+    }
+
+    /// Forgets a stream, giving the body it held back to the connection window.
+    fn remove_stream(&mut self, id: u32) -> Option<Stream> {
+        let stream = self.streams.remove(&id)?;
+        self.released += stream.held;
+        Some(stream)
+    }
+
+    /// Tells the client no streams above the last one it opened will be served.
+    fn go_away(&mut self, code: u32, debug: &[u8]) -> Result<(), Error> {
+        let last = self.last_stream_id;
+        self.going_away = Some(last);
+        let mut payload = Vec::with_capacity(8 + debug.len());
+        payload.extend_from_slice(&last.to_be_bytes());
+        payload.extend_from_slice(&code.to_be_bytes());
+        payload.extend_from_slice(debug);
+        self.write_frame(GOAWAY, 0, 0, &payload)
+    }
+
+    fn write_window_update(&mut self, id: u32, increment: usize) -> Result<(), Error> {
+        self.write_frame(WINDOW_UPDATE, 0, id, &(increment as u32).to_be_bytes())
+    }
+
+    fn write_frame(&mut self, kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Result<(), Error> {
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        frame.push(kind);
+        frame.push(flags);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.transport.write_all(&frame)?;
+        Ok(())
+    }
+
+    /// Takes the next frame out of what has been received, reading once if none is
+    /// complete yet. Returns `None` if `timeout` passes first.
+    fn read_frame(&mut self, timeout: Duration) -> Result<Option<Frame>, Error> {
+        if let Some(frame) = self.next_frame()? {
+            return Ok(Some(frame));
+        }
+        //Bodies that have been served or discarded make room for more
+        if self.released > 0 {
+            let increment = std::mem::take(&mut self.released);
+            self.recv_window += increment as i64;
+            self.write_window_update(0, increment)?;
+        }
+        //Whatever has been written goes out before waiting on the client
+        self.transport.flush()?;
+        self.transport.set_read_timeout(Some(timeout))?;
+        let mut buffer = [0; 16384];
+        match self.transport.read(&mut buffer) {
+            Ok(0) => return Err(Error::Io),
+            Ok(read_size) => self.received.extend_from_slice(&buffer[..read_size]),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => (),
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(_) => return Err(Error::Io),
+        }
+        self.next_frame()
+    }
+
+    fn next_frame(&mut self) -> Result<Option<Frame>, Error> {
+        if self.received.len() < FRAME_HEADER_SIZE {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes([0, self.received[0], self.received[1], self.received[2]]) as usize;
+        if length > DEFAULT_MAX_FRAME_SIZE {
+            return Err(Error::Protocol(FRAME_SIZE_ERROR, "frame larger than SETTINGS_MAX_FRAME_SIZE"));
+        }
+        if self.received.len() < FRAME_HEADER_SIZE + length {
+            return Ok(None);
+        }
+        let stream = u32::from_be_bytes([self.received[5], self.received[6], self.received[7], self.received[8]]) & 0x7fff_ffff;
+        let frame = Frame {
+            kind: self.received[3],
+            flags: self.received[4],
+            stream,
+            payload: self.received[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + length].to_vec(),
+        };
+        self.received.drain(..FRAME_HEADER_SIZE + length);
+        Ok(Some(frame))
+    }
+}
+
+/// The payload of a DATA or HEADERS frame without its padding and the first `skip` bytes.
+fn unpadded(frame: &Frame, skip: usize) -> Result<&[u8], Error> {
+    let payload = &frame.payload[..];
+    let (padding, start) = if frame.flags & PADDED != 0 {
+        match payload.first() {
+            Some(padding) => (*padding as usize, 1),
+            None => return Err(Error::Protocol(FRAME_SIZE_ERROR, "padded frame without a pad length")),
+        }
+    } else {
+        (0, 0)
+    };
+    match payload.len().checked_sub(padding + start + skip) {
+        Some(length) => Ok(&payload[start + skip..start + skip + length]),
+        None => Err(Error::Protocol(PROTOCOL_ERROR, "padding longer than the frame")),
+    }
+}
+
+impl Head {
+    /// Checks the decoded fields of a request (RFC 9113 section 8.3). A malformed request
+    /// resets its stream.
+    fn parse(fields: Vec<hpack::Field>) -> Result<Head, &'static str> {
+        let mut method = None;
+        let mut scheme = None;
+        let mut path = None;
+        let mut authority = None;
+        let mut cookies: Vec<String> = Vec::new();
+        let mut regular = false;
+        let mut head = Head { method: String::new(), path: String::new(), authority: None, fields: Vec::new(), content_length: None };
+
+        for (name, value) in fields {
+            if value.iter().any(|b| *b == b'\r' || *b == b'\n' || *b == 0) {
+                return Err("line break in a header value");
+            }
+            let value = String::from_utf8_lossy(&value).into_owned();
+            if let Some(pseudo) = name.strip_prefix(b":") {
+                if regular {
+                    return Err("pseudo-header after a regular header");
+                }
+                let slot = match pseudo {
+                    b"method" => &mut method,
+                    b"scheme" => &mut scheme,
+                    b"path" => &mut path,
+                    b"authority" => &mut authority,
+                    _ => return Err("unknown pseudo-header"),
+                };
+                if slot.replace(value).is_some() {
+                    return Err("repeated pseudo-header");
+                }
+                continue;
+            }
+
+            regular = true;
+            let name = String::from_utf8_lossy(&name).into_owned();
+            if name.is_empty() || !name.bytes().all(|b| b.is_ascii_graphic() && b != b':' && !b.is_ascii_uppercase()) {
+                return Err("invalid header name");
+            }
+            if CONNECTION_HEADERS.contains(&name.as_str()) || (name == "te" && value != "trailers") {
+                return Err("connection-specific header");
+            }
+            match name.as_str() {
+                "content-length" => {
+                    let length = content_length_value(&value).ok_or("invalid content-length")?;
+                    if head.content_length.replace(length).is_some_and(|previous| previous != length) {
+                        return Err("conflicting content-length");
+                    }
+                },
+                //Split up for better compression, and joined again for HTTP/1.1 (section 8.2.3)
+                "cookie" => cookies.push(value),
+                _ => head.fields.push((name, value)),
+            }
+        }
+        if !cookies.is_empty() {
+            head.fields.push((String::from("cookie"), cookies.join("; ")));
+        }
+
+        head.method = method.ok_or("missing :method")?;
+        scheme.ok_or("missing :scheme")?;
+        head.path = path.filter(|path| !path.is_empty()).ok_or("missing :path")?;
+        head.authority = authority;
+        Ok(head)
+    }
+
+    /// The request as HTTP/1.1 text up to the body, with header names capitalised as
+    /// HTTP/1.1 clients send them.
+    fn to_http1(&self, body_length: usize) -> String {
+        let mut text = format!("{} {} HTTP/1.1\r\n", self.method, self.path);
+        if let Some(authority) = &self.authority {
+            text.push_str(format!("Host: {}\r\n", authority).as_str());
+        }
+        for (name, value) in &self.fields {
+            if name == "host" && self.authority.is_some() {
+                continue;
+            }
+            text.push_str(format!("{}: {}\r\n", capitalise(name), value).as_str());
+        }
+        text.push_str(format!("Content-Length: {}\r\n\r\n", body_length).as_str());
+        text
+    }
+}
+
+/// `content-type` becomes `Content-Type`.
+fn capitalise(name: &str) -> String {
+    let mut capitalised = String::with_capacity(name.len());
+    let mut upper = true;
+    for c in name.chars() {
+        capitalised.push(if upper { c.to_ascii_uppercase() } else { c });
+        upper = c == '-';
+    }
+    capitalised
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryTransport;
+    use crate::event_stream::EventStream;
+
+    fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.push(kind);
+        frame.push(flags);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// A header block of literal fields, as a client without compression would send it.
+    fn block(fields: &[(&str, &str)]) -> Vec<u8> {
+        let mut block = Vec::new();
+        for (name, value) in fields {
+            block.push(0x00);
+            block.push(name.len() as u8);
+            block.extend_from_slice(name.as_bytes());
+            block.push(value.len() as u8);
+            block.extend_from_slice(value.as_bytes());
+        }
+        block
+    }
+
+    /// Splits what the server wrote into frames.
+    fn frames(mut data: &[u8]) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        while data.len() >= FRAME_HEADER_SIZE {
+            let length = u32::from_be_bytes([0, data[0], data[1], data[2]]) as usize;
+            let stream = u32::from_be_bytes([data[5], data[6], data[7], data[8]]);
+            frames.push(Frame { kind: data[3], flags: data[4], stream, payload: data[9..9 + length].to_vec() });
+            data = &data[9 + length..];
+        }
+        frames
+    }
+
+    /// The status, headers and body the server sent on `stream`.
+    fn response(frames: &[Frame], stream: u32) -> (String, Vec<(String, String)>, Vec<u8>) {
+        let mut decoder = hpack::Decoder::new();
+        let mut fields = Vec::new();
+        let mut body = Vec::new();
+        for frame in frames.iter().filter(|frame| frame.stream == stream) {
+            match frame.kind {
+                HEADERS => {
+                    for (name, value) in decoder.decode(&frame.payload, 65536).unwrap().unwrap() {
+                        fields.push((String::from_utf8(name).unwrap(), String::from_utf8(value).unwrap()));
+                    }
+                },
+                DATA => body.extend_from_slice(&frame.payload),
+                _ => (),
+            }
+        }
+        let status = fields.remove(0).1;
+        (status, fields, body)
+    }
+
+    fn serve(input: &[u8], configure: fn(&mut HttpListener)) -> Vec<Frame> {
+        let mut listener = HttpListener::new();
+        listener.http2(true);
+        configure(&mut listener);
+        let (transport, output) = MemoryTransport::new(input);
+        listener.serve_transport(Box::new(transport));
+        let output = output.lock().unwrap();
+        frames(&output)
+    }
+
+    fn describe(context: &Context) -> Response {
+        let body = format!("{} {} {} {} {} {}", context.request.version.as_str(), context.request.protocol, context.request.path, context.request.querystring,
+            context.request.header_value("Cookie").unwrap_or(""), context.request.post.get("a").map_or("", String::as_str));
+        Response::ok_text(&body)
+    }
+    fn routes(listener: &mut HttpListener) {
+        listener.route("^/", describe);
+    }
+
+    #[test]
+    fn test_prior_knowledge_requests() {
+        let mut input = PREFACE.to_vec();
+        input.extend(frame(SETTINGS, 0, 0, &[]));
+        input.extend(frame(HEADERS, END_STREAM | END_HEADERS, 1, &block(&[
+            (":method", "GET"), (":scheme", "http"), (":path", "/items?page=2"), (":authority", "example.com"),
+            ("cookie", "a=1"), ("cookie", "b=2"),
+        ])));
+        input.extend(frame(HEADERS, END_HEADERS, 3, &block(&[
+            (":method", "POST"), (":scheme", "http"), (":path", "/form"), ("content-type", "application/x-www-form-urlencoded"),
+        ])));
+        input.extend(frame(DATA, END_STREAM, 3, b"a=42"));
+        input.extend(frame(PING, 0, 0, b"12345678"));
+        let frames = serve(&input, routes);
+
+        assert_eq!(frames[0].kind, SETTINGS);
+        assert!(frames.iter().any(|frame| frame.kind == SETTINGS && frame.flags == ACK));
+        assert!(frames.iter().any(|frame| frame.kind == PING && frame.flags == ACK && frame.payload == b"12345678"));
+
+        let (status, headers, body) = response(&frames, 1);
+        assert_eq!(status, "200");
+        assert!(headers.contains(&(String::from("content-length"), String::from("35"))));
+        assert!(!headers.iter().any(|(name, _)| name == "connection"));
+        assert_eq!(String::from_utf8(body).unwrap(), "HTTP/2 http /items page=2 a=1; b=2 ");
+
+        let (status, _, body) = response(&frames, 3);
+        assert_eq!(status, "200");
+        assert_eq!(String::from_utf8(body).unwrap(), "HTTP/2 http /form   42");
+    }
+
+    #[test]
+    fn test_response_respects_flow_control() {
+        fn large(_context: &Context) -> Response {
+            Response::ok_bytes(vec![b'x'; 5000], "application/octet-stream")
+        }
+        let mut input = PREFACE.to_vec();
+        //A stream window of 1000 bytes, opened by another 10000 once the response starts
+        input.extend(frame(SETTINGS, 0, 0, &[0, 4, 0, 0, 0x03, 0xe8]));
+        input.extend(frame(HEADERS, END_STREAM | END_HEADERS, 1, &block(&[(":method", "GET"), (":scheme", "http"), (":path", "/large")])));
+        input.extend(frame(WINDOW_UPDATE, 0, 1, &10000u32.to_be_bytes()));
+        let frames = serve(&input, |listener| listener.route("^/large$", large));
+
+        let data: Vec<&Frame> = frames.iter().filter(|frame| frame.kind == DATA).collect();
+        assert_eq!(data[0].payload.len(), 1000);
+        assert_eq!(data.iter().map(|frame| frame.payload.len()).sum::<usize>(), 5000);
+        assert_eq!(data.last().unwrap().flags, END_STREAM);
+    }
+
+    #[test]
+    fn test_request_bodies_are_flow_controlled() {
+        let post = |stream: u32| frame(HEADERS, END_HEADERS, stream, &block(&[(":method", "POST"), (":scheme", "http"), (":path", "/")]));
+        let mut input = PREFACE.to_vec();
+        input.extend(post(1));
+        input.extend(frame(DATA, END_STREAM, 1, b"a=42"));
+        let frames = serve(&input, routes);
+
+        //The connection window is opened up front, and given back once the body is served
+        let updates: Vec<&Frame> = frames.iter().filter(|frame| frame.kind == WINDOW_UPDATE && frame.stream == 0).collect();
+        assert_eq!(updates[0].payload, ((CONNECTION_WINDOW - DEFAULT_WINDOW_SIZE) as u32).to_be_bytes());
+        assert_eq!(updates[1].payload, 4u32.to_be_bytes());
+        let served = frames.iter().position(|frame| frame.kind == DATA && frame.stream == 1).unwrap();
+        let returned = frames.iter().position(|frame| frame.kind == WINDOW_UPDATE && frame.stream == 0 && frame.payload == 4u32.to_be_bytes()).unwrap();
+        assert!(returned > served);
+
+        //Two unfinished bodies use up the window, so more data breaks it
+        let chunk = vec![b'x'; DEFAULT_MAX_FRAME_SIZE];
+        let mut input = PREFACE.to_vec();
+        for stream in &[1, 3] {
+            input.extend(post(*stream));
+            for _ in 0..MAX_BODY_SIZE / chunk.len() {
+                input.extend(frame(DATA, 0, *stream, &chunk));
+            }
+        }
+        input.extend(post(5));
+        input.extend(frame(DATA, 0, 5, b"x"));
+        let frames = serve(&input, routes);
+        let goaway = frames.iter().find(|frame| frame.kind == GOAWAY).unwrap();
+        assert_eq!(goaway.payload[4..8], FLOW_CONTROL_ERROR.to_be_bytes());
+    }
+
+    #[test]
+    fn test_streams_needing_http1_and_bad_requests() {
+        fn events(_request: &Request, _stream: &mut EventStream) {}
+        let mut input = PREFACE.to_vec();
+        input.extend(frame(HEADERS, END_STREAM | END_HEADERS, 1, &block(&[(":method", "GET"), (":scheme", "http"), (":path", "/events")])));
+        input.extend(frame(HEADERS, END_STREAM | END_HEADERS, 3, &block(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), ("Upper", "x")])));
+        input.extend(frame(HEADERS, END_STREAM | END_HEADERS, 5, &block(&[(":method", "GET"), (":scheme", "http"), (":path", "no-slash")])));
+        input.extend(frame(HEADERS, END_HEADERS, 7, &block(&[(":method", "POST"), (":scheme", "http"), (":path", "/"), ("content-length", "+4")])));
+        let frames = serve(&input, |listener| {
+            listener.route("^/", describe);
+            listener.event_stream("^/events$", events);
+        });
+
+        let reset = |stream: u32| frames.iter().find(|frame| frame.kind == RST_STREAM && frame.stream == stream).map(|frame| frame.payload.clone());
+        assert_eq!(reset(1), Some(HTTP_1_1_REQUIRED.to_be_bytes().to_vec()));
+        assert_eq!(reset(3), Some(PROTOCOL_ERROR.to_be_bytes().to_vec()));
+        assert_eq!(response(&frames, 5).0, "400");
+        assert_eq!(reset(7), Some(PROTOCOL_ERROR.to_be_bytes().to_vec()));
+    }
+
+    #[test]
+    fn test_preface_detection() {
+        assert_eq!(preface(b"PRI * HTTP/2.0\r\n"), Preface::Partial);
+        assert_eq!(preface(PREFACE), Preface::Complete);
+        assert_eq!(preface(b"GET / HTTP/1.1\r\n\r\n"), Preface::Absent);
+        assert_eq!(preface(b"PRI * HTTP/2.0\r\n\r\nXX\r\n\r\n"), Preface::Absent);
+    }
+
+    #[test]
+    fn test_http1_still_served() {
+        let mut listener = HttpListener::new();
+        listener.http2(true);
+        routes(&mut listener);
+        let (transport, output) = MemoryTransport::new(b"GET /plain HTTP/1.1\r\nConnection: close\r\n\r\n");
+        listener.serve_transport(Box::new(transport));
+        assert!(String::from_utf8_lossy(&output.lock().unwrap()).starts_with("HTTP/1.1 200 OK"));
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+
+/// Dynamic table size a decoder starts with, and the most this server accepts.
+pub(crate) const DEFAULT_TABLE_SIZE: usize = 4096;
+
+/// Overhead counted for every field in a table or header list besides its name and value.
+const FIELD_OVERHEAD: usize = 32;
+
+/// The static table (RFC 7541 Appendix A), indexed from 1.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""), (":method", "GET"), (":method", "POST"), (":path", "/"),
+    (":path", "/index.html"), (":scheme", "http"), (":scheme", "https"), (":status", "200"),
+    (":status", "204"), (":status", "206"), (":status", "304"), (":status", "400"),
+    (":status", "404"), (":status", "500"), ("accept-charset", ""), ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""), ("accept-ranges", ""), ("accept", ""), ("access-control-allow-origin", ""),
+    ("age", ""), ("allow", ""), ("authorization", ""), ("cache-control", ""),
+    ("content-disposition", ""), ("content-encoding", ""), ("content-language", ""), ("content-length", ""),
+    ("content-location", ""), ("content-range", ""), ("content-type", ""), ("cookie", ""),
+    ("date", ""), ("etag", ""), ("expect", ""), ("expires", ""),
+    ("from", ""), ("host", ""), ("if-match", ""), ("if-modified-since", ""),
+    ("if-none-match", ""), ("if-range", ""), ("if-unmodified-since", ""), ("last-modified", ""),
+    ("link", ""), ("location", ""), ("max-forwards", ""), ("proxy-authenticate", ""),
+    ("proxy-authorization", ""), ("range", ""), ("referer", ""), ("refresh", ""),
+    ("retry-after", ""), ("server", ""), ("set-cookie", ""), ("strict-transport-security", ""),
+    ("transfer-encoding", ""), ("user-agent", ""), ("vary", ""), ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Static table index of the `:status` name, used for statuses without an entry of their own.
+const STATUS_NAME_INDEX: usize = 8;
+
+/// The Huffman code (RFC 7541 Appendix B) as `(code, length in bits)` for every octet
+/// and, last, the end-of-string symbol.
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28), (0xfffffe4, 28), (0xfffffe5, 28),
+    (0xfffffe6, 28), (0xfffffe7, 28), (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28),
+    (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28), (0xfffffed, 28), (0xfffffee, 28),
+    (0xfffffef, 28), (0xffffff0, 28), (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28), (0xffffff8, 28), (0xffffff9, 28),
+    (0xffffffa, 28), (0xffffffb, 28), (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11), (0x3fa, 10), (0x3fb, 10),
+    (0xf9, 8), (0x7fb, 11), (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6),
+    (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6), (0x1a, 6), (0x1b, 6),
+    (0x1c, 6), (0x1d, 6), (0x1e, 6), (0x1f, 6), (0x5c, 7), (0xfb, 8),
+    (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10), (0x1ffa, 13), (0x21, 6),
+    (0x5d, 7), (0x5e, 7), (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7),
+    (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7), (0x67, 7), (0x68, 7),
+    (0x69, 7), (0x6a, 7), (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7),
+    (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7), (0xfc, 8), (0x73, 7),
+    (0xfd, 8), (0x1ffb, 13), (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5), (0x24, 6), (0x5, 5),
+    (0x25, 6), (0x26, 6), (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7),
+    (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5), (0x2b, 6), (0x76, 7),
+    (0x2c, 6), (0x8, 5), (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7),
+    (0x79, 7), (0x7a, 7), (0x7b, 7), (0x7ffe, 15), (0x7fc, 11), (0x3ffd, 14),
+    (0x1ffd, 13), (0xffffffc, 28), (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20),
+    (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23), (0x3fffd6, 22), (0x7fffda, 23),
+    (0x7fffdb, 23), (0x7fffdc, 23), (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23),
+    (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23), (0xffffee, 24), (0x7fffe1, 23),
+    (0x7fffe2, 23), (0x7fffe3, 23), (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23),
+    (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24), (0x3fffda, 22), (0x1fffdd, 21),
+    (0xfffe9, 20), (0x3fffdb, 22), (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21),
+    (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24), (0x1fffdf, 21), (0x3fffdf, 22),
+    (0x7fffeb, 23), (0x7fffec, 23), (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21),
+    (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23), (0xfffea, 20), (0x3fffe2, 22),
+    (0x3fffe3, 22), (0x3fffe4, 22), (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19), (0x3fffe7, 22), (0x7ffff2, 23),
+    (0x3fffe8, 22), (0x1ffffec, 25), (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27),
+    (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25), (0x7fff2, 19), (0x1fffe3, 21),
+    (0x3ffffe6, 26), (0x7ffffe0, 27), (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26), (0xffffffd, 28), (0x7ffffe3, 27),
+    (0x7ffffe4, 27), (0x7ffffe5, 27), (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21),
+    (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23), (0x3fffea, 22), (0x3fffeb, 22),
+    (0x1ffffee, 25), (0x1ffffef, 25), (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26), (0x7ffffe7, 27), (0x7ffffe8, 27),
+    (0x7ffffe9, 27), (0x7ffffea, 27), (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27),
+    (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26), (0x3fffffff, 30),
+];
+const EOS: usize = 256;
+
+/// A header block that cannot be decoded. The dynamic table may be out of step with the
+/// peer's afterwards, so the connection has to end (COMPRESSION_ERROR).
+#[derive(Debug, PartialEq)]
+pub(crate) struct DecodeError;
+
+/// A header field as decoded, before anything checks that it is valid HTTP.
+pub(crate) type Field = (Vec<u8>, Vec<u8>);
+
+/// Decodes the header blocks of one connection, in the order they were sent.
+pub(crate) struct Decoder {
+    /// Newest entry first, which is the order indexes count in.
+    table: VecDeque<Field>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Decoder {
+    pub(crate) fn new() -> Decoder {
+        Decoder { table: VecDeque::new(), size: 0, max_size: DEFAULT_TABLE_SIZE }
+    }
+
+    /// Decodes a complete header block. Returns `None` when the fields add up to more than
+    /// `max_list_size`; the block is still decoded in full so the table stays in step.
+    pub(crate) fn decode(&mut self, block: &[u8], max_list_size: usize) -> Result<Option<Vec<Field>>, DecodeError> {
+        let mut fields = Vec::new();
+        let mut list_size = 0;
+        let mut position = 0;
+        let mut seen_field = false;
+        while position < block.len() {
+            let first = block[position];
+            if first & 0x80 != 0 {
+                let index = decode_integer(block, &mut position, 7)?;
+                let (name, value) = self.entry(index)?;
+                list_size += name.len() + value.len() + FIELD_OVERHEAD;
+                if list_size <= max_list_size {
+                    fields.push((name.to_vec(), value.to_vec()));
+                }
+            } else if first & 0xe0 == 0x20 {
+                //Table size updates are only allowed before the first field
+                if seen_field {
+                    return Err(DecodeError);
+                }
+                let size = decode_integer(block, &mut position, 5)?;
+                if size > DEFAULT_TABLE_SIZE {
+                    return Err(DecodeError);
+                }
+                self.max_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                let (prefix, indexing) = if first & 0xc0 == 0x40 { (6, true) } else { (4, false) };
+                let index = decode_integer(block, &mut position, prefix)?;
+                let name = match index {
+                    0 => decode_string(block, &mut position)?,
+                    index => self.entry(index)?.0.to_vec(),
+                };
+                let value = decode_string(block, &mut position)?;
+                list_size += name.len() + value.len() + FIELD_OVERHEAD;
+                if indexing {
+                    self.insert(name.clone(), value.clone());
+                }
+                if list_size <= max_list_size {
+                    fields.push((name, value));
+                }
+            }
+            seen_field = true;
+        }
+        Ok(if list_size <= max_list_size { Some(fields) } else { None })
+    }
+
+    fn entry(&self, index: usize) -> Result<(&[u8], &[u8]), DecodeError> {
+        match index {
+            0 => Err(DecodeError),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.as_bytes(), value.as_bytes()))
+            },
+            _ => match self.table.get(index - 62) {
+                Some((name, value)) => Ok((name, value)),
+                None => Err(DecodeError),
+            },
+        }
+    }
+
+    fn insert(&mut self, name: Vec<u8>, value: Vec<u8>) {
+        let size = name.len() + value.len() + FIELD_OVERHEAD;
+        //An entry larger than the whole table just empties it
+        self.evict(size);
+        if size <= self.max_size {
+            self.table.push_front((name, value));
+            self.size += size;
+        }
+    }
+
+    /// Drops the oldest entries until `room` more bytes fit.
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + FIELD_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+}
+
+/// Encodes a response header block. Every field is a literal without indexing and without
+/// Huffman coding, so encoding needs no state and the peer's table size never matters.
+/// Header names must already be lowercase.
+pub(crate) fn encode(status: u16, headers: &[(String, String)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    let status_index = (8..=14).find(|index| STATIC_TABLE[index - 1].1 == status.to_string());
+    match status_index {
+        Some(index) => encode_integer(&mut block, 0x80, 7, index),
+        None => {
+            encode_integer(&mut block, 0x00, 4, STATUS_NAME_INDEX);
+            encode_string(&mut block, status.to_string().as_bytes());
+        },
+    }
+    for (name, value) in headers {
+        block.push(0x00);
+        encode_string(&mut block, name.as_bytes());
+        encode_string(&mut block, value.as_bytes());
+    }
+    block
+}
+
+/// Decodes an integer whose first octet keeps the high bits for flags (RFC 7541 section 5.1).
+fn decode_integer(block: &[u8], position: &mut usize, prefix: u32) -> Result<usize, DecodeError> {
+    let max_prefix = (1usize << prefix) - 1;
+    let mut value = (*block.get(*position).ok_or(DecodeError)? as usize) & max_prefix;
+    *position += 1;
+    if value < max_prefix {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let byte = *block.get(*position).ok_or(DecodeError)?;
+        *position += 1;
+        //Anything this large is an attack rather than a header
+        if shift > 21 {
+            return Err(DecodeError);
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn encode_integer(block: &mut Vec<u8>, flags: u8, prefix: u32, value: usize) {
+    let max_prefix = (1usize << prefix) - 1;
+    if value < max_prefix {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max_prefix as u8);
+    let mut rest = value - max_prefix;
+    while rest >= 0x80 {
+        block.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    block.push(rest as u8);
+}
+
+fn decode_string(block: &[u8], position: &mut usize) -> Result<Vec<u8>, DecodeError> {
+    let huffman = block.get(*position).ok_or(DecodeError)? & 0x80 != 0;
+    let length = decode_integer(block, position, 7)?;
+    let end = position.checked_add(length).filter(|end| *end <= block.len()).ok_or(DecodeError)?;
+    let data = &block[*position..end];
+    *position = end;
+    if huffman { huffman_decode(data) } else { Ok(data.to_vec()) }
+}
+
+fn encode_string(block: &mut Vec<u8>, data: &[u8]) {
+    encode_integer(block, 0x00, 7, data.len());
+    block.extend_from_slice(data);
+}
+
+/// The Huffman code as a binary tree: each node holds its two children, where a positive
+/// number is another node and a negative one is `-(symbol + 1)`.
+fn huffman_tree() -> &'static Vec<[i32; 2]> {
+    static TREE: OnceLock<Vec<[i32; 2]>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut tree = vec![[0, 0]];
+        for (symbol, (code, length)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for bit in (0..*length).rev() {
+                let branch = ((code >> bit) & 1) as usize;
+                if bit == 0 {
+                    tree[node][branch] = -(symbol as i32 + 1);
+                } else {
+                    if tree[node][branch] == 0 {
+                        tree.push([0, 0]);
+                        tree[node][branch] = (tree.len() - 1) as i32;
+                    }
+                    node = tree[node][branch] as usize;
+                }
+            }
+        }
+        tree
+    })
+}
+
+fn huffman_decode(data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let tree = huffman_tree();
+    let mut decoded = Vec::with_capacity(data.len() * 8 / 5);
+    let mut node = 0;
+    //Bits since the last symbol, which must be a short run of ones padding the last octet
+    let mut pending = 0;
+    let mut all_ones = true;
+    for byte in data {
+        for bit in (0..8).rev() {
+            let branch = ((byte >> bit) & 1) as usize;
+            let next = tree[node][branch];
+            if next < 0 {
+                let symbol = (-next - 1) as usize;
+                if symbol == EOS {
+                    return Err(DecodeError);
+                }
+                decoded.push(symbol as u8);
+                node = 0;
+                pending = 0;
+                all_ones = true;
+            } else {
+                node = next as usize;
+                pending += 1;
+                all_ones &= branch == 1;
+            }
+        }
+    }
+    if pending > 7 || !all_ones {
+        return Err(DecodeError);
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(data: &str) -> Vec<u8> {
+        let digits: Vec<char> = data.chars().filter(|c| !c.is_whitespace()).collect();
+        digits.chunks(2).map(|pair| u8::from_str_radix(&pair.iter().collect::<String>(), 16).unwrap()).collect()
+    }
+    fn text(fields: Vec<Field>) -> Vec<(String, String)> {
+        fields.into_iter().map(|(name, value)| (String::from_utf8(name).unwrap(), String::from_utf8(value).unwrap())).collect()
+    }
+    fn pairs(fields: &[(&str, &str)]) -> Vec<(String, String)> {
+        fields.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn test_decode_rfc_examples() {
+        //RFC 7541 C.4.1 and C.4.2: Huffman coded requests sharing the dynamic table
+        let mut decoder = Decoder::new();
+        let first = decoder.decode(&hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"), 4096).unwrap().unwrap();
+        assert_eq!(text(first), pairs(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")]));
+        let second = decoder.decode(&hex("8286 84be 5886 a8eb 1064 9cbf"), 4096).unwrap().unwrap();
+        assert_eq!(text(second), pairs(&[(":method", "GET"), (":scheme", "http"), (":path", "/"),
+            (":authority", "www.example.com"), ("cache-control", "no-cache")]));
+
+        //C.1.3: an integer continued over several octets
+        let mut position = 0;
+        assert_eq!(decode_integer(&[0x1f, 0x9a, 0x0a], &mut position, 5), Ok(1337));
+    }
+
+    #[test]
+    fn test_decode_rejects_bad_blocks() {
+        assert_eq!(Decoder::new().decode(&[0x80], 4096), Err(DecodeError));
+        assert_eq!(Decoder::new().decode(&[0xbe], 4096), Err(DecodeError));
+        //Huffman padding that is not all ones
+        assert_eq!(Decoder::new().decode(&hex("0081 0081 00"), 4096), Err(DecodeError));
+        //A list over the limit is reported, not truncated
+        assert_eq!(Decoder::new().decode(&hex("8286"), 40), Ok(None));
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let headers = pairs(&[("content-type", "text/html"), ("x-long", &"a".repeat(300))]);
+        for status in &[200, 404, 308] {
+            let fields = text(Decoder::new().decode(&encode(*status, &headers), 4096).unwrap().unwrap());
+            assert_eq!(fields[0], (String::from(":status"), status.to_string()));
+            assert_eq!(&fields[1..], &headers[..]);
+        }
+        assert_eq!(encode(200, &[]), vec![0x88]);
+    }
+}
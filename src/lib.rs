@@ -1,41 +1,262 @@
 
 pub mod context; //Include context.rs
 pub mod threadpool;
-use std::net::TcpListener;
-use std::net::TcpStream;
+pub mod server;
+pub mod builder;
+pub mod executor;
+#[cfg(feature = "tokio")]
+pub mod async_server;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "http2")]
+mod http2;
+mod connection;
+mod listen;
+mod redirect;
+mod expect;
+mod proxy;
+mod routes;
+pub mod transport;
+pub mod websocket;
+pub mod event_stream;
 use std::collections::HashMap;
-use regex::Regex;
 use std::io::prelude::*;
 use std::fs::File;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
-use mime_guess;
 
  // Expose Context, Response and Request from context in this mod
-pub use crate::context::{Context, Response, Request, HttpResponseType};
+pub use crate::context::{Context, Response, Request, HttpResponseType, HttpVersion};
+pub use crate::context::request::ParseError;
+pub use crate::server::{ServerHandle, ServerError, OverloadPolicy};
+use crate::server::ConnectionSlots;
+use crate::threadpool::ThreadPool;
+use crate::listen::{Listener, UnixOptions};
+use crate::routes::Routes;
+pub use crate::builder::HttpListenerBuilder;
+pub use crate::threadpool::PoolMetrics;
+pub use crate::executor::{Executor, ScheduledTask};
+pub use crate::transport::Transport;
+#[cfg(feature = "tokio")]
+pub use crate::async_server::AsyncHandler;
+#[cfg(feature = "tls")]
+pub use crate::tls::TlsConfig;
+pub use crate::websocket::{WebSocket, WebSocketHandler};
+pub use crate::event_stream::{Event, EventStream, EventStreamHandler};
+pub use crate::expect::ExpectHandler;
+
+/// First delay after a failed accept; doubled on each further failure.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+/// Longest delay between accept retries.
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
 
 
 pub struct HttpListener {
     routing_table: HashMap<String,  fn(&Context)->Response >,
+    #[cfg(feature = "tokio")]
+    async_routes: HashMap<String, AsyncHandler>,
+    websocket_routes: HashMap<String, WebSocketHandler>,
+    websocket_max_message_size: usize,
+    event_stream_routes: HashMap<String, EventStreamHandler>,
+    event_stream_keep_alive: Duration,
+    expect_routes: HashMap<String, ExpectHandler>,
     cache: HashMap<String, (Vec<u8>,Option<mime_guess::Mime>)>,
     pub webroot : String,
-    thread_count : usize,
+    min_threads : usize,
+    max_threads : usize,
+    thread_idle_timeout : Duration,
+    background_threads : usize,
+    keep_alive_timeout : Duration,
+    header_timeout : Duration,
+    body_timeout : Duration,
+    write_timeout : Duration,
+    max_requests : usize,
+    max_connections : usize,
+    queue_size : usize,
+    overload : OverloadPolicy,
+    unix_options : UnixOptions,
+    #[cfg(feature = "tls")]
+    tls : Option<TlsConfig>,
+    hsts : Option<String>,
+    #[cfg(feature = "http2")]
+    http2 : bool,
+    proxy_protocol : bool,
+    trusted_proxies : Vec<proxy::Network>,
+    handle : ServerHandle,
+}
+impl Default for HttpListener {
+    fn default() -> Self {
+        HttpListener::new()
+    }
 }
 impl HttpListener {
     pub fn new() -> HttpListener {
         HttpListener {
             routing_table: HashMap::new(),
+            #[cfg(feature = "tokio")]
+            async_routes: HashMap::new(),
+            websocket_routes: HashMap::new(),
+            websocket_max_message_size: websocket::DEFAULT_MAX_MESSAGE_SIZE,
+            event_stream_routes: HashMap::new(),
+            event_stream_keep_alive: event_stream::DEFAULT_KEEP_ALIVE,
+            expect_routes: HashMap::new(),
             cache: HashMap::new(),
             webroot: String::new(),
-            thread_count : 4,
+            min_threads : 4,
+            max_threads : 4,
+            thread_idle_timeout : threadpool::DEFAULT_IDLE_TIMEOUT,
+            background_threads : 2,
+            keep_alive_timeout : Duration::from_secs(5),
+            header_timeout : Duration::from_secs(10),
+            body_timeout : Duration::from_secs(30),
+            write_timeout : Duration::from_secs(30),
+            max_requests : 100,
+            max_connections : 1024,
+            queue_size : 128,
+            overload : OverloadPolicy::Block,
+            unix_options : UnixOptions::default(),
+            #[cfg(feature = "tls")]
+            tls : None,
+            hsts : None,
+            #[cfg(feature = "http2")]
+            http2 : false,
+            proxy_protocol : false,
+            trusted_proxies : Vec::new(),
+            handle : ServerHandle::new(),
         }
     }
-    pub fn start(&self, uri: &str, thread_count: usize) {
-        let uri = String::from(uri);
+    /// Configures a listener, validating the configuration as a whole when it is built.
+    pub fn builder() -> HttpListenerBuilder {
+        HttpListenerBuilder::new()
+    }
+    /// A handle for shutting down the server started by `start` from another thread.
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+    /// Serves connections until the server is shut down through its `ServerHandle`,
+    /// then returns that handle. Fails straight away if `uri` cannot be bound.
+    ///
+    /// Errors accepting a single connection (such as running out of file descriptors)
+    /// are logged and retried with a growing delay instead of stopping the server.
+    pub fn start(&self, uri: &str) -> Result<ServerHandle, ServerError> {
+        self.start_all(&[uri])
+    }
 
-        let listener = TcpListener::bind(&uri).unwrap();
-        let pool = crate::threadpool::ThreadPool::new(thread_count);
-        
+    /// Like `start`, serving every address in `uris` with the same routes and workers.
+    /// Nothing is served unless all of them can be bound.
+    ///
+    /// `[::]:port` accepts IPv4 clients too, unless `0.0.0.0:port` is also listed.
+    /// An address such as `unix:/run/rweblet.sock` listens on a Unix domain socket, which
+    /// is removed again on shutdown; see `unix_socket_mode` and `remove_stale_socket`.
+    pub fn start_all(&self, uris: &[&str]) -> Result<ServerHandle, ServerError> {
+        let (id, listeners) = self.bind(uris)?;
+        let settings = self.server_settings();
+        let pool = HttpListener::worker_pool(id, &settings);
+        HttpListener::serve_listeners(id, listeners, pool, settings);
+        Ok(self.handle())
+    }
+
+    /// Binds `uri` and serves it from a background thread, returning as soon as the
+    /// listener is accepting. The bound address (useful with port 0) is available from
+    /// `ServerHandle::local_addr`, and `ServerHandle::shutdown` stops the server.
+    ///
+    /// The routes and settings are copied, so later changes to this listener do not
+    /// affect the running server.
+    pub fn spawn(&self, uri: &str) -> Result<ServerHandle, ServerError> {
+        self.spawn_all(&[uri])
+    }
+
+    /// Like `spawn`, serving every address in `uris` as `start_all` does.
+    pub fn spawn_all(&self, uris: &[&str]) -> Result<ServerHandle, ServerError> {
+        let (id, listeners) = self.bind(uris)?;
+        self.spawn_listeners(id, listeners, self.server_settings())
+    }
+
+    /// Binds `uri` and answers every request on it from a background thread with a
+    /// redirect to the same path and query string over HTTPS on `https_port`, as the
+    /// plain HTTP companion of this listener's HTTPS one.
+    ///
+    /// GET requests are redirected with 301 and others with 308, which keeps their method.
+    /// The redirect listener shares this listener's handle, timeouts and limits, so it is
+    /// shut down along with it.
+    ///
+    /// ```no_run
+    /// # use rweblet::HttpListener;
+    /// # let listener = HttpListener::new();
+    /// listener.spawn_https_redirect("0.0.0.0:80", 443).unwrap();
+    /// listener.start("0.0.0.0:443").unwrap();
+    /// ```
+    pub fn spawn_https_redirect(&self, uri: &str, https_port: u16) -> Result<ServerHandle, ServerError> {
+        let (id, listeners) = self.bind(&[uri])?;
+        let mut settings = self.settings();
+        settings.routing_table.clear();
+        settings.websocket_routes.clear();
+        settings.event_stream_routes.clear();
+        settings.expect_routes.clear();
+        settings.https_redirect = Some(https_port);
+        #[cfg(feature = "tls")]
+        {
+            settings.tls = None;
+        }
+        self.spawn_listeners(id, listeners, Arc::new(settings))
+    }
+
+    fn spawn_listeners(&self, id: usize, listeners: Vec<Listener>, settings: Arc<Settings>) -> Result<ServerHandle, ServerError> {
+        let pool = HttpListener::worker_pool(id, &settings);
+        let spawned = thread::Builder::new()
+            .name(String::from("rweblet-accept"))
+            .spawn(move || HttpListener::serve_listeners(id, listeners, pool, settings));
+        if let Err(e) = spawned {
+            self.handle.stopped(id);
+            return Err(ServerError::Io(e));
+        }
+        Ok(self.handle())
+    }
+
+    /// Serves every request sent on `transport` on the calling thread, returning once the
+    /// client closes it or a timeout or the request limit ends it.
+    ///
+    /// This plugs in connections rweblet does not accept itself, such as ones handed over
+    /// by another server, and lets tests drive the routes through a `MemoryTransport`.
+    /// Handlers run without an executor, so `Context::executor` returns `None`. A listener
+    /// configured for TLS terminates it on top of `transport`, after reading the PROXY
+    /// protocol header if that is enabled too.
+    pub fn serve_transport(&self, transport: Box<dyn Transport>) {
+        connection::serve(transport, Arc::new(self.settings()));
+    }
+
+    /// Makes this listener part of the server controlled by `handle`, so listeners with
+    /// different routes (such as a public site and an internal admin port) are shut
+    /// down and waited for together.
+    pub fn share_handle(&mut self, handle: &ServerHandle) {
+        self.handle = handle.clone();
+    }
+
+    fn bind(&self, uris: &[&str]) -> Result<(usize, Vec<Listener>), ServerError> {
+        self.check_routes()?;
+        let listeners = listen::bind_all(uris, self.unix_options)?;
+        let mut addrs = Vec::with_capacity(listeners.len());
+        for listener in &listeners {
+            addrs.push(listener.local_addr().map_err(ServerError::Io)?);
+        }
+        Ok((self.handle.started(addrs), listeners))
+    }
+
+    /// Refuses a route pattern that is not a valid regular expression, before any server
+    /// is started with it.
+    pub(crate) fn check_routes(&self) -> Result<(), ServerError> {
+        routes::check_patterns(self.routing_table.keys())?;
+        #[cfg(feature = "tokio")]
+        routes::check_patterns(self.async_routes.keys())?;
+        routes::check_patterns(self.websocket_routes.keys())?;
+        routes::check_patterns(self.event_stream_routes.keys())?;
+        routes::check_patterns(self.expect_routes.keys())
+    }
+
+    /// Copies the routes and configuration shared by every connection of a server.
+    fn settings(&self) -> Settings {
         //Creating a copy of the routing table
         let mut routing: HashMap<String, fn(&Context) -> Response> = HashMap::new();
 
@@ -44,90 +265,183 @@ impl HttpListener {
             routing.insert(p, *func);
         }
         
-        let settings = Settings::new(&self.webroot, routing);
-        let arc_settings = Arc::new(settings);
-        
-        for stream in listener.incoming()
+        let mut settings = Settings::new(&self.webroot, routing);
+        settings.keep_alive_timeout = self.keep_alive_timeout;
+        settings.header_timeout = self.header_timeout;
+        settings.body_timeout = self.body_timeout;
+        settings.write_timeout = self.write_timeout;
+        settings.max_requests = self.max_requests;
+        settings.min_threads = self.min_threads;
+        settings.max_threads = self.max_threads;
+        settings.thread_idle_timeout = self.thread_idle_timeout;
+        #[cfg(feature = "tokio")]
+        {
+            settings.async_routes = Routes::new(&self.async_routes);
+        }
+        settings.max_connections = self.max_connections;
+        settings.queue_size = self.queue_size;
+        settings.overload = self.overload;
+        #[cfg(feature = "tls")]
         {
-            let stream = stream.unwrap();
+            settings.tls = self.tls.clone();
+        }
+        settings.hsts = self.hsts.clone();
+        #[cfg(feature = "http2")]
+        {
+            settings.http2 = self.http2;
+        }
+        settings.websocket_routes = Routes::new(&self.websocket_routes);
+        settings.websocket_max_message_size = self.websocket_max_message_size;
+        settings.event_stream_routes = Routes::new(&self.event_stream_routes);
+        settings.event_stream_keep_alive = self.event_stream_keep_alive;
+        settings.expect_routes = Routes::new(&self.expect_routes);
+        settings.proxy_protocol = self.proxy_protocol;
+        settings.trusted_proxies = self.trusted_proxies.clone();
+        settings.server = self.handle.clone();
+        settings
+    }
+
+    /// Settings for a server of its own, with the executor its handlers' background jobs run on.
+    pub(crate) fn server_settings(&self) -> Arc<Settings> {
+        let mut settings = self.settings();
+        settings.executor = Some(Executor::new(self.background_threads));
+        Arc::new(settings)
+    }
+
+    /// Starts the workers, registering them with the server handle for `pool_metrics`.
+    fn worker_pool(id: usize, settings: &Settings) -> ThreadPool {
+        let pool = ThreadPool::elastic(
+            settings.min_threads, settings.max_threads, settings.thread_idle_timeout, settings.queue_size);
+        settings.server.set_pool(id, pool.monitor());
+        pool
+    }
+
+    /// Accepts on every listener, one thread each, until shutdown, then waits for the
+    /// workers and background jobs before reporting the listeners stopped.
+    fn serve_listeners(id: usize, listeners: Vec<Listener>, pool: ThreadPool, settings: Arc<Settings>) {
+        let slots = ConnectionSlots::new(settings.max_connections);
+        let mut listeners = listeners.into_iter();
+        let first = listeners.next();
 
-            //Prevents the tcp read from blocking more than 5 msecs
-            loop {
-                let result = stream.set_read_timeout(Some(Duration::from_millis(5)));
-                if result.is_ok() {
-                    break;
+        thread::scope(|scope| {
+            for listener in listeners {
+                let spawned = thread::Builder::new()
+                    .name(String::from("rweblet-accept"))
+                    .spawn_scoped(scope, || HttpListener::accept_loop(listener, &pool, &slots, &settings));
+                if let Err(e) = spawned {
+                    HttpListener::log(format!("Failed to start accept thread: {}", e).as_str());
                 }
             }
-            
-            let settings = Arc::clone(&arc_settings);
-           
-            if thread_count >= 1 {
-                pool.execute(move || {
-                    HttpListener::process_header(stream, settings);
-                    
-                });
+            if let Some(listener) = first {
+                HttpListener::accept_loop(listener, &pool, &slots, &settings);
             }
+        });
+
+        //The ports are closed by now, so dropping the pool waits for the workers to drain
+        drop(pool);
+        if let Some(executor) = &settings.executor {
+            executor.shutdown();
         }
+        settings.server.stopped(id);
     }
 
-    fn process_header(mut stream: TcpStream, settings: Arc<Settings>) {
-        let mut buffer = [0; 8192];
-        let mut idle_threshold = 5;
+    fn accept_loop(listener: Listener, pool: &ThreadPool, slots: &Arc<ConnectionSlots>, settings: &Arc<Settings>) {
+        let handle = &settings.server;
+        let mut backoff = ACCEPT_BACKOFF_MIN;
+        
         loop {
-            let read_result  = stream.read(&mut buffer);
-            match read_result {
-                Err(_) => { println!("Failed to read from stream"); break; },
-                Ok(header_size) => {
-                    //println!("Read {} bytes", header_size);
-                    if header_size > 0 { 
-                        let request_header: String = String::from_utf8_lossy(&buffer[0..header_size]).to_string();
-                
-                        Request::handle_request(&request_header, stream.try_clone().unwrap(), Arc::clone(&settings));
-                    } else {
-                        if idle_threshold > 0 {
-                            idle_threshold -= 1;
-                        }
-                        else {
-                            //stream.shutdown(Shutdown::Both);
-                            //println!("Terminated connection");
-                            break;
+            //A shutdown may have been requested before this listener started
+            if handle.is_shutting_down() {
+                break;
+            }
+            //When blocking, wait for room before accepting so new clients stay in the backlog
+            let reserved = match settings.overload {
+                OverloadPolicy::Block => match ConnectionSlots::acquire(slots, handle) {
+                    Some(slot) => Some(slot),
+                    None => break,
+                },
+                OverloadPolicy::Reject(_) => None,
+            };
+            let stream = listener.accept();
+            if handle.is_shutting_down() {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                //Nobody connected within ACCEPT_WAIT; look for a shutdown again
+                Err(ref e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => continue,
+                Err(e) => {
+                    HttpListener::log(format!("Failed to accept connection: {}", e).as_str());
+                    thread::sleep(backoff);
+                    backoff = std::cmp::min(backoff * 2, ACCEPT_BACKOFF_MAX);
+                    continue;
+                }
+            };
+            backoff = ACCEPT_BACKOFF_MIN;
 
+            let slot = match (reserved, settings.overload) {
+                (Some(slot), _) => slot,
+                (None, OverloadPolicy::Reject(retry_after)) => {
+                    let slot = if pool.queued() < settings.queue_size { ConnectionSlots::try_acquire(slots) } else { None };
+                    match slot {
+                        Some(slot) => slot,
+                        None => {
+                            HttpListener::log("Server overloaded, rejecting connection");
+                            //A TLS handshake would hold up the accept thread, so such clients are just closed
+                            if !settings.is_tls() {
+                                connection::reject(stream, Response::service_unavailable(retry_after));
+                            }
+                            continue;
                         }
-                        //println!("Timeout: {}",timeout);
                     }
                 },
-            }
+                (None, OverloadPolicy::Block) => continue,
+            };
+
+            let settings = Arc::clone(settings);
+            pool.execute(move || {
+                connection::serve(stream, settings);
+                drop(slot);
+            });
         }
     }
 
     //fn process(stream: TcpStream, settings: Arc<Settings>) {
     fn process(context: &mut Context, settings: Arc<Settings>, counter: usize) {
-        for (pattern,func) in &settings.routing_table {
-            let re = Regex::new(pattern.as_str()).unwrap();
-            if re.is_match(context.request.path.as_str()) {
-
-                let response = func(&context);
-                match response.http_type {
-                    HttpResponseType::None => {
-                        &mut context.write_cache(String::from_utf8_lossy(&response.data).into_owned().as_str()); return; 
-                    },
-                    _ => { &mut context.write_response(response); println!("Wrote response number {}",counter); return; }
+        if let Some(port) = settings.https_redirect {
+            let response = redirect::to_https(&context.request, port);
+            context.write_response(response);
+            return;
+        }
+        if let Some(func) = settings.routing_table.find(&context.request.path) {
+            //A panicking handler answers 500 instead of taking the connection's worker with it
+            let response = match panic::catch_unwind(AssertUnwindSafe(|| func(context))) {
+                Ok(response) => response,
+                Err(payload) => {
+                    HttpListener::log_error(format!("Handler for {} panicked: {}", context.request.path, threadpool::panic_message(&payload)).as_str());
+                    context.keep_alive = false;
+                    Response::internal_error()
                 }
+            };
+            match response.http_type {
+                HttpResponseType::None => {
+                    context.write_cache(String::from_utf8_lossy(&response.data).into_owned().as_str()); return; 
+                },
+                _ => { context.write_response(response); HttpListener::log(format!("Wrote response number {}",counter).as_str()); return; }
             }
         }
         //Check if file exists
         let uri = str::replace(&context.request.path,"../", "");
 
         let file = File::open(format!("{}/{}",settings.webroot,uri));
-        if file.is_ok() {
+        if let Ok(mut file) = file {
             let mime = mime_guess::from_path(uri).first_or_octet_stream();
             
-            let mut file = file.unwrap();
             let mut buf: Vec<u8> = Vec::new();
             let read_result = file.read_to_end(&mut buf);
             if read_result.is_err() {
-                &mut context.write_response(Response::internal_error());
-                
+                context.write_response(Response::internal_error());
+                return;
             }
 
             match mime.type_() {
@@ -139,19 +453,209 @@ impl HttpListener {
                 }
             }
         } else {
-            &mut context.write_response(Response::notfound());
+            context.write_response(Response::notfound());
         }
     }
+    /// Number of worker threads serving connections.
     pub fn threads(&mut self, thread_count: usize) {
-        assert!(thread_count > 0);
-        self.thread_count = thread_count;
+        self.thread_range(thread_count, thread_count);
+    }
+    /// Lets the number of worker threads grow from `min` up to `max` while every worker
+    /// is busy. Workers above `min` exit after `thread_idle_timeout` without work.
+    pub fn thread_range(&mut self, min: usize, max: usize) {
+        assert!(max > 0 && min <= max);
+        self.min_threads = min;
+        self.max_threads = max;
+    }
+    /// Number of threads running jobs handlers hand to `Context::executor`.
+    pub fn background_threads(&mut self, count: usize) {
+        assert!(count > 0);
+        self.background_threads = count;
+    }
+    /// How long a worker above the minimum waits for a connection before it exits.
+    pub fn thread_idle_timeout(&mut self, timeout: Duration) {
+        self.thread_idle_timeout = timeout;
+    }
+    /// How long an idle connection is kept open waiting for the next request.
+    /// A zero timeout disables keep-alive, closing each connection after one response.
+    pub fn keep_alive(&mut self, timeout: Duration) {
+        self.keep_alive_timeout = timeout;
+    }
+    /// How long a client has to send a complete request line and headers, counted from
+    /// the first byte (or from connecting, for the first request).
+    pub fn header_timeout(&mut self, timeout: Duration) {
+        assert!(timeout.as_millis() > 0);
+        self.header_timeout = timeout;
+    }
+    /// How long a client has to send a request body once its headers are complete.
+    pub fn body_timeout(&mut self, timeout: Duration) {
+        assert!(timeout.as_millis() > 0);
+        self.body_timeout = timeout;
+    }
+    /// How long a single write to the client may block. A zero timeout never gives up.
+    pub fn write_timeout(&mut self, timeout: Duration) {
+        self.write_timeout = timeout;
+    }
+    /// The most connections open at once, counting those waiting for a worker.
+    pub fn max_connections(&mut self, count: usize) {
+        assert!(count > 0);
+        self.max_connections = count;
+    }
+    /// How many accepted connections may wait for a free worker.
+    pub fn queue_size(&mut self, size: usize) {
+        assert!(size > 0);
+        self.queue_size = size;
+    }
+    /// What to do with new connections when `max_connections` or the queue is full.
+    pub fn overload(&mut self, policy: OverloadPolicy) {
+        self.overload = policy;
+    }
+    /// Permission bits given to Unix domain socket files, e.g. `0o660` so that a proxy
+    /// in the same group can connect. By default the process umask decides. The socket
+    /// has these bits from the moment it appears at its path; binding needs a directory
+    /// beside it can be created in.
+    #[cfg(unix)]
+    pub fn unix_socket_mode(&mut self, mode: u32) {
+        self.unix_options.mode = Some(mode);
+    }
+    /// Whether binding a Unix domain socket first removes a socket file left behind by a
+    /// server that is no longer running. On by default; a socket that still accepts
+    /// connections is never removed.
+    #[cfg(unix)]
+    pub fn remove_stale_socket(&mut self, remove: bool) {
+        self.unix_options.remove_stale = remove;
+    }
+    /// Serves HTTPS with these certificates instead of plain HTTP, on every address the
+    /// listener binds. Requests then have `https` as their `protocol`.
+    #[cfg(feature = "tls")]
+    pub fn tls(&mut self, config: TlsConfig) {
+        self.tls = Some(config);
+    }
+    /// Tells browsers to use only HTTPS for this host for `max_age`, with a
+    /// Strict-Transport-Security header on every response sent over TLS. Plain HTTP
+    /// responses never carry it, as browsers ignore it there.
+    pub fn hsts(&mut self, max_age: Duration, include_subdomains: bool) {
+        let mut value = format!("max-age={}", max_age.as_secs());
+        if include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        self.hsts = Some(value);
+    }
+    /// Serves HTTP/2 as well as HTTP/1.1: over TLS to clients that choose it with ALPN,
+    /// and in cleartext (h2c) to clients that start the connection with the HTTP/2 preface,
+    /// as internal services using prior knowledge do. Cleartext upgrades from HTTP/1.1
+    /// are not offered.
+    ///
+    /// Requests reach the same routes and handlers as HTTP/1.1 ones. The streams of one
+    /// connection are served one after another, in the order their requests complete, so
+    /// a slow handler holds up the other requests its client sent on that connection.
+    /// A client may have 16 streams open and 2 MiB of request bodies unserved at once;
+    /// flow control makes it wait for earlier requests to be served before sending more.
+    /// WebSocket and event stream routes are only served over HTTP/1.1; HTTP/2 requests for
+    /// them are refused in a way that makes browsers retry over HTTP/1.1.
+    #[cfg(feature = "http2")]
+    pub fn http2(&mut self, enabled: bool) {
+        self.http2 = enabled;
+    }
+    /// Expects every connection to start with a PROXY protocol header (version 1 or 2),
+    /// as HAProxy and cloud load balancers send when configured to, and takes the client
+    /// address from it: `Context::peer_addr` is then the real client rather than the
+    /// load balancer. The header comes before TLS, so it works for HTTPS listeners too.
+    ///
+    /// Connections without a valid header are closed, so only enable this when every
+    /// connection comes through such a proxy. The address in the header is only believed
+    /// from peers listed in `trusted_proxies` and over Unix sockets; anyone else keeps
+    /// their own address.
+    pub fn proxy_protocol(&mut self, enabled: bool) {
+        self.proxy_protocol = enabled;
+    }
+    /// Proxies whose Forwarded, X-Forwarded-For and X-Forwarded-Proto headers are
+    /// believed, as addresses or CIDR networks such as `10.0.0.0/8` or `2001:db8::/32`.
+    ///
+    /// A request from one of them gets its `client_ip` from the nearest address in those
+    /// headers that is not itself a trusted proxy, and its `protocol` from the scheme the
+    /// client used. Requests from anyone else keep their peer address and scheme however
+    /// the headers read, so clients cannot pose as someone else. No proxy is trusted by
+    /// default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an entry is not an address or network.
+    pub fn trusted_proxies(&mut self, proxies: &[&str]) {
+        self.trusted_proxies = proxies.iter()
+            .map(|proxy| proxy::Network::parse(proxy).unwrap_or_else(|| panic!("{} is not an address or network", proxy)))
+            .collect();
+    }
+    /// The most requests served on one connection before it is closed.
+    pub fn max_requests(&mut self, count: usize) {
+        assert!(count > 0);
+        self.max_requests = count;
     }
     pub fn route(&mut self, pattern: &str, callback: fn(request: &Context) -> Response) {
-        &self.routing_table.insert(format!("{}",pattern), callback);
+        self.routing_table.insert(String::from(pattern), callback);
+    }
+    /// Serves WebSocket connections on paths matching the regular expression `pattern`.
+    /// Upgrade requests are answered with the RFC 6455 handshake and `handler` then runs
+    /// on the connection's worker until it returns, which closes the socket; other
+    /// requests to these paths get `426 Upgrade Required`.
+    ///
+    /// Open sockets are closed with status 1001 when the server shuts down.
+    pub fn websocket(&mut self, pattern: &str, handler: WebSocketHandler) {
+        self.websocket_routes.insert(String::from(pattern), handler);
+    }
+    /// Largest WebSocket message accepted, after reassembling fragments; a larger one
+    /// closes the socket with status 1009. Defaults to 1 MiB.
+    pub fn websocket_max_message_size(&mut self, bytes: usize) {
+        assert!(bytes > 0);
+        self.websocket_max_message_size = bytes;
+    }
+    /// Serves Server-Sent Events on paths matching the regular expression `pattern`.
+    /// The response is a `text/event-stream` that `handler` writes events to until it
+    /// returns, which ends the stream; see `EventStream`.
+    ///
+    /// Each open stream holds a worker, so size `thread_range` for the expected number
+    /// of subscribers. Streams end when the server shuts down.
+    pub fn event_stream(&mut self, pattern: &str, handler: EventStreamHandler) {
+        self.event_stream_routes.insert(String::from(pattern), handler);
+    }
+    /// How long an event stream may be silent before a keep-alive comment is sent while
+    /// its handler waits. Defaults to 15 seconds.
+    pub fn event_stream_keep_alive(&mut self, interval: Duration) {
+        assert!(interval.as_millis() > 0);
+        self.event_stream_keep_alive = interval;
+    }
+    /// Checks uploads to paths matching the regular expression `pattern` before their
+    /// body is read, for clients that send `Expect: 100-continue` and wait for the go-ahead.
+    /// `check` sees the request line and headers; `Ok` sends `100 Continue` and the request
+    /// is then read and routed as usual, while `Err` answers with its response (such as
+    /// 413 for an oversized Content-Length) instead of routing the request. A client whose
+    /// body has not arrived yet is answered without reading it and the connection closed.
+    ///
+    /// Requests carrying the header are checked before routing even when they arrive
+    /// whole, but requests without it never reach `check`. It saves clients from sending
+    /// a body that would be refused and is no substitute for the handler's own checks.
+    ///
+    /// Clients asking for it on other paths are always told to continue, and any other
+    /// expectation gets `417 Expectation Failed`. Only HTTP/1.1 connections are checked.
+    ///
+    /// ```no_run
+    /// # use rweblet::{HttpListener, HttpResponseType, Request, Response};
+    /// fn small_uploads(request: &Request) -> Result<(), Response> {
+    ///     match request.header_value("Content-Length").and_then(|length| length.parse::<usize>().ok()) {
+    ///         Some(length) if length <= 64 * 1024 => Ok(()),
+    ///         _ => Err(Response::error(HttpResponseType::PayloadTooLarge)),
+    ///     }
+    /// }
+    ///
+    /// let mut listener = HttpListener::new();
+    /// listener.expect_continue("^/upload", small_uploads);
+    /// ```
+    pub fn expect_continue(&mut self, pattern: &str, check: ExpectHandler) {
+        self.expect_routes.insert(String::from(pattern), check);
     }
 
     pub fn set_cache(&mut self, key: &str, value: Vec<u8>, mime: Option<mime_guess::Mime>) {
-        &self.cache.insert(String::from(key), (value, mime));
+        self.cache.insert(String::from(key), (value, mime));
     }
 
     pub fn get_cache(&self, key: &str) -> Result<&(Vec<u8>, Option<mime_guess::Mime>), &str>{
@@ -162,13 +666,13 @@ impl HttpListener {
     }
 
     pub fn cache_file(&mut self, filename: &str) {
-        let path = format!("{}",filename);
-        let mut file = File::open(&path).expect(format!("Missing file {}",&path).as_str());
+        let path = String::from(filename);
+        let mut file = File::open(&path).unwrap_or_else(|_| panic!("Missing file {}",&path));
         let mut contents: Vec<u8> = Vec::new();
         let mime = mime_guess::from_path(&path).first();
         
-        file.read_to_end(&mut contents).expect(format!("Unable to read file {}", &path).as_str());
-        &self.set_cache(filename, contents, mime);
+        file.read_to_end(&mut contents).unwrap_or_else(|_| panic!("Unable to read file {}", &path));
+        self.set_cache(filename, contents, mime);
     }
     pub fn log(message: &str) {
         let debug = false;
@@ -176,18 +680,119 @@ impl HttpListener {
             println!("{}",message);
         }
     }
+    /// Reports a failure that must not go unnoticed, such as a panicking handler, on
+    /// standard error regardless of whether debug logging is on.
+    pub(crate) fn log_error(message: &str) {
+        eprintln!("rweblet: {}", message);
+    }
 }
 
 pub struct Settings {
     //routing_table: Box<HashMap<String, fn(&Context)->Response >>,
-    routing_table: HashMap<String, fn(&Context)->Response >,
+    routing_table: Routes<fn(&Context) -> Response>,
+    #[cfg(feature = "tokio")]
+    async_routes: Routes<AsyncHandler>,
+    websocket_routes: Routes<WebSocketHandler>,
+    websocket_max_message_size: usize,
+    event_stream_routes: Routes<EventStreamHandler>,
+    event_stream_keep_alive: Duration,
+    expect_routes: Routes<ExpectHandler>,
     webroot: String,
+    keep_alive_timeout: Duration,
+    header_timeout: Duration,
+    body_timeout: Duration,
+    write_timeout: Duration,
+    max_requests: usize,
+    min_threads: usize,
+    max_threads: usize,
+    thread_idle_timeout: Duration,
+    executor: Option<Executor>,
+    max_connections: usize,
+    queue_size: usize,
+    overload: OverloadPolicy,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+    hsts: Option<String>,
+    #[cfg(feature = "http2")]
+    http2: bool,
+    /// Every connection starts with a PROXY protocol header.
+    proxy_protocol: bool,
+    trusted_proxies: Vec<proxy::Network>,
+    /// Redirect every request to HTTPS on this port instead of routing it.
+    https_redirect: Option<u16>,
+    server: ServerHandle,
 }
 impl Settings {
     pub fn new(webroot: &str, routing_table: HashMap<String,  fn(&Context)->Response >) -> Settings {
         let webroot = String::from(webroot);
         //let routing_table = Box::new(routing_table);
-        Settings { routing_table, webroot }
+        Settings {
+            routing_table: Routes::new(&routing_table),
+            #[cfg(feature = "tokio")]
+            async_routes: Routes::default(),
+            websocket_routes: Routes::default(),
+            websocket_max_message_size: websocket::DEFAULT_MAX_MESSAGE_SIZE,
+            event_stream_routes: Routes::default(),
+            event_stream_keep_alive: event_stream::DEFAULT_KEEP_ALIVE,
+            expect_routes: Routes::default(),
+            webroot,
+            keep_alive_timeout: Duration::from_secs(5),
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            max_requests: 100,
+            min_threads: 4,
+            max_threads: 4,
+            thread_idle_timeout: threadpool::DEFAULT_IDLE_TIMEOUT,
+            executor: None,
+            max_connections: 1024,
+            queue_size: 128,
+            overload: OverloadPolicy::Block,
+            #[cfg(feature = "tls")]
+            tls: None,
+            hsts: None,
+            #[cfg(feature = "http2")]
+            http2: false,
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            https_redirect: None,
+            server: ServerHandle::new(),
+        }
+    }
+
+    /// Whether connections are served over TLS.
+    fn is_tls(&self) -> bool {
+        #[cfg(feature = "tls")]
+        {
+            self.tls.is_some()
+        }
+        #[cfg(not(feature = "tls"))]
+        {
+            false
+        }
+    }
+
+    /// Reads the PROXY protocol header and terminates TLS on an accepted connection,
+    /// as far as the listener is configured to.
+    fn accept_transport(&self, transport: Box<dyn Transport>) -> Option<Box<dyn Transport>> {
+        let transport = if self.proxy_protocol { proxy::accept(transport, self)? } else { transport };
+        #[cfg(feature = "tls")]
+        {
+            if let Some(tls) = &self.tls {
+                #[cfg(feature = "http2")]
+                let session = if self.http2 { tls.accept_http2(transport) } else { tls.accept(transport) };
+                #[cfg(not(feature = "http2"))]
+                let session = tls.accept(transport);
+                return match session {
+                    Ok(transport) => Some(transport),
+                    Err(e) => {
+                        HttpListener::log(format!("Failed to start TLS session: {}", e).as_str());
+                        None
+                    }
+                };
+            }
+        }
+        Some(transport)
     }
 }
 
@@ -0,0 +1,319 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::time::Duration;
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(unix)]
+use socket2::SockRef;
+use socket2::{Domain, Protocol, Socket, Type};
+use crate::transport::Transport;
+use crate::ServerError;
+
+/// Connections waiting to be accepted before the OS refuses more, as std uses.
+const BACKLOG: i32 = 128;
+/// Longest a blocked accept waits before its loop looks for a shutdown again, in case
+/// the shutdown cannot wake it with a connection.
+pub(crate) const ACCEPT_WAIT: Duration = Duration::from_secs(1);
+
+/// Prefix of listen addresses that name a Unix domain socket, as in `unix:/run/app.sock`.
+pub(crate) const UNIX_PREFIX: &str = "unix:";
+
+/// How Unix domain sockets are created.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct UnixOptions {
+    /// Permission bits for the socket file, e.g. `0o660` to let a proxy's group connect.
+    pub(crate) mode: Option<u32>,
+    /// Remove a socket file left behind by a server that is no longer running.
+    pub(crate) remove_stale: bool,
+}
+impl Default for UnixOptions {
+    fn default() -> Self {
+        UnixOptions { mode: None, remove_stale: true }
+    }
+}
+
+/// A bound listening socket.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    /// The path is removed again when the listener is dropped.
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+/// Where a listener accepts connections, kept by the server handle to wake it on shutdown.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum BoundAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Listener {
+    /// Waits up to `ACCEPT_WAIT` for a connection, failing with `WouldBlock` or
+    /// `TimedOut` if none came.
+    pub(crate) fn accept(&self) -> io::Result<Box<dyn Transport>> {
+        let stream: Box<dyn Transport> = match self {
+            Listener::Tcp(listener) => Box::new(listener.accept()?.0),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => Box::new(listener.accept()?.0),
+        };
+        //Accepted sockets inherit the listener's accept timeout
+        stream.set_read_timeout(None)?;
+        Ok(stream)
+    }
+    pub(crate) fn local_addr(&self) -> io::Result<BoundAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(BoundAddr::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(BoundAddr::Unix(path.clone())),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        {
+            if let Listener::Unix(_, path) = self {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// Binds every address in `uris`, failing without keeping any of them if one cannot be
+/// bound. Addresses starting with `unix:` are Unix domain socket paths.
+///
+/// An IPv6 wildcard address such as `[::]:80` is dual-stack, accepting IPv4 clients as
+/// well, unless an IPv4 address on the same port is bound alongside it. Then each family
+/// gets its own socket, so `0.0.0.0:80` and `[::]:80` can be listed together whatever the
+/// system default is.
+pub(crate) fn bind_all(uris: &[&str], unix: UnixOptions) -> Result<Vec<Listener>, ServerError> {
+    if uris.is_empty() {
+        return Err(ServerError::Config(String::from("no address to bind")));
+    }
+    let mut resolved = Vec::with_capacity(uris.len());
+    for uri in uris {
+        if uri.starts_with(UNIX_PREFIX) {
+            resolved.push((*uri, Vec::new()));
+            continue;
+        }
+        match uri.to_socket_addrs() {
+            Ok(addrs) => resolved.push((*uri, addrs.collect::<Vec<SocketAddr>>())),
+            Err(e) => return Err(ServerError::Bind(String::from(*uri), e)),
+        }
+    }
+    let ipv4_ports: Vec<u16> = resolved.iter()
+        .flat_map(|(_, addrs)| addrs.iter())
+        .filter(|addr| addr.is_ipv4() && addr.port() != 0)
+        .map(|addr| addr.port())
+        .collect();
+
+    let mut listeners = Vec::with_capacity(resolved.len());
+    for (uri, addrs) in resolved {
+        if let Some(path) = uri.strip_prefix(UNIX_PREFIX) {
+            match bind_unix(path, unix) {
+                Ok(listener) => listeners.push(listener),
+                Err(e) => return Err(ServerError::Bind(String::from(uri), e)),
+            }
+            continue;
+        }
+
+        //Like TcpListener::bind, use the first address the name resolves to that binds
+        let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses");
+        let mut bound = None;
+        for addr in addrs {
+            match bind(addr, !ipv4_ports.contains(&addr.port())) {
+                Ok(listener) => {
+                    bound = Some(listener);
+                    break;
+                },
+                Err(e) => last_error = e,
+            }
+        }
+        match bound {
+            Some(listener) => listeners.push(Listener::Tcp(listener)),
+            None => return Err(ServerError::Bind(String::from(uri), last_error)),
+        }
+    }
+    Ok(listeners)
+}
+
+fn bind(addr: SocketAddr, dual_stack: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    //Matches TcpListener::bind, so a restarted server can rebind a port in TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    socket.set_read_timeout(Some(ACCEPT_WAIT))?;
+    Ok(socket.into())
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &str, options: UnixOptions) -> io::Result<Listener> {
+    let path = PathBuf::from(path);
+    if options.remove_stale {
+        remove_stale_socket(&path)?;
+    }
+    let listener = match options.mode {
+        Some(mode) => bind_unix_with_mode(&path, mode)?,
+        None => UnixListener::bind(&path)?,
+    };
+    //From here on dropping the listener removes the file, including on the error below
+    let listener = Listener::Unix(listener, path);
+    if let Listener::Unix(socket, _) = &listener {
+        SockRef::from(socket).set_read_timeout(Some(ACCEPT_WAIT))?;
+    }
+    Ok(listener)
+}
+
+/// Binds the socket in a directory only this process can enter, gives it `mode` there
+/// and then links it into place, so it is never reachable with the umask's permissions.
+/// Linking fails rather than replace a file already at `path`.
+#[cfg(unix)]
+fn bind_unix_with_mode(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    static STAGED: AtomicUsize = AtomicUsize::new(0);
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let private = parent.join(format!(".rweblet-{}-{}", std::process::id(), STAGED.fetch_add(1, Ordering::SeqCst)));
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let staged = private.join("s");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(mode))?;
+        std::fs::hard_link(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&staged);
+    let _ = std::fs::remove_dir(&private);
+    bound
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &str, _options: UnixOptions) -> io::Result<Listener> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Unix domain sockets are not supported on this platform"))
+}
+
+/// Removes the socket file at `path` if nothing is listening on it any more. A socket
+/// another server still accepts on, or a file that is not a socket, is left alone.
+#[cfg(unix)]
+fn remove_stale_socket(path: &PathBuf) -> io::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Ok(());
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse, "another server is listening on this socket")),
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+        Err(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+
+    fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn test_bind_all_or_nothing() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken = taken.local_addr().unwrap().to_string();
+        match bind_all(&["127.0.0.1:0", taken.as_str()], UnixOptions::default()) {
+            Err(ServerError::Bind(uri, _)) => assert_eq!(uri, taken),
+            _ => panic!("expected a bind error"),
+        }
+        assert!(matches!(bind_all(&[], UnixOptions::default()), Err(ServerError::Config(_))));
+    }
+    #[test]
+    fn test_ipv6_wildcard_is_dual_stack() {
+        let listeners = bind_all(&["[::]:0"], UnixOptions::default()).unwrap();
+        let port = match listeners[0].local_addr().unwrap() {
+            BoundAddr::Tcp(addr) => addr.port(),
+            other => panic!("unexpected address {:?}", other),
+        };
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_ok());
+    }
+    #[test]
+    fn test_ipv4_and_ipv6_on_the_same_port() {
+        let port = free_port();
+        let v4 = format!("0.0.0.0:{}", port);
+        let v6 = format!("[::]:{}", port);
+        let listeners = bind_all(&[v4.as_str(), v6.as_str()], UnixOptions::default()).unwrap();
+        assert_eq!(listeners.len(), 2);
+        assert!(matches!(listeners[1].local_addr().unwrap(), BoundAddr::Tcp(addr) if addr.is_ipv6()));
+    }
+    #[test]
+    fn test_accept_gives_up_without_clients() {
+        let listeners = bind_all(&["127.0.0.1:0"], UnixOptions::default()).unwrap();
+        match listeners[0].accept() {
+            Err(e) => assert!(matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)),
+            Ok(_) => panic!("accepted a connection nobody made"),
+        }
+
+        let addr = match listeners[0].local_addr().unwrap() {
+            BoundAddr::Tcp(addr) => addr,
+            #[cfg(unix)]
+            BoundAddr::Unix(_) => unreachable!(),
+        };
+        let _client = TcpStream::connect(addr).unwrap();
+        assert!(listeners[0].accept().is_ok());
+    }
+    #[cfg(unix)]
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rweblet-{}-{}.sock", name, std::process::id()))
+    }
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_mode_and_cleanup() {
+        let path = socket_path("mode");
+        let uri = format!("{}{}", UNIX_PREFIX, path.display());
+        let listeners = bind_all(&[uri.as_str()], UnixOptions { mode: Some(0o600), remove_stale: true }).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        //The socket was bound elsewhere, but clients reach it at its path
+        let _client = UnixStream::connect(&path).unwrap();
+        assert!(listeners[0].accept().is_ok());
+
+        //A live socket is not mistaken for a stale one
+        assert!(matches!(bind_all(&[uri.as_str()], UnixOptions::default()), Err(ServerError::Bind(_, _))));
+        let keep = UnixOptions { mode: Some(0o600), remove_stale: false };
+        assert!(matches!(bind_all(&[uri.as_str()], keep), Err(ServerError::Bind(_, _))));
+        assert!(UnixStream::connect(&path).is_ok());
+        drop(listeners);
+        assert!(!path.exists());
+    }
+    #[cfg(unix)]
+    #[test]
+    fn test_stale_unix_socket_is_replaced() {
+        let path = socket_path("stale");
+        let uri = format!("{}{}", UNIX_PREFIX, path.display());
+        //A socket file whose server is gone
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let keep = UnixOptions { mode: None, remove_stale: false };
+        assert!(matches!(bind_all(&[uri.as_str()], keep), Err(ServerError::Bind(_, _))));
+        let listeners = bind_all(&[uri.as_str()], UnixOptions::default()).unwrap();
+        assert!(UnixStream::connect(&path).is_ok());
+        drop(listeners);
+    }
+}
@@ -0,0 +1,524 @@
+use std::fmt;
+use std::io::{self, Cursor, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use crate::context::Request;
+use crate::transport::{Closer, Transport};
+use crate::{HttpListener, Settings};
+
+/// Starts every PROXY protocol version 2 header.
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest version 1 header, line break included.
+const V1_MAX_LENGTH: usize = 107;
+
+/// A network trusted proxies connect from, given as an address or in CIDR notation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    /// Parses `10.0.0.1`, `10.0.0.0/8` or `2001:db8::/32`.
+    pub(crate) fn parse(network: &str) -> Option<Network> {
+        let (addr, prefix) = match network.find('/') {
+            Some(idx) => (&network[..idx], Some(&network[idx+1..])),
+            None => (network, None),
+        };
+        let addr = addr.trim().parse::<IpAddr>().ok()?.to_canonical();
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>().ok().filter(|prefix| *prefix <= bits)?,
+            None => bits,
+        };
+        Some(Network { addr, prefix })
+    }
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => prefix_matches(&network.octets(), &ip.octets(), self.prefix),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => prefix_matches(&network.octets(), &ip.octets(), self.prefix),
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let (bytes, bits) = (prefix as usize / 8, prefix % 8);
+    network[..bytes] == ip[..bytes] && (bits == 0 || (network[bytes] ^ ip[bytes]) >> (8 - bits) == 0)
+}
+
+/// Why a connection's PROXY protocol header was refused.
+#[derive(Debug, PartialEq)]
+enum HeaderError {
+    /// The connection did not start with a PROXY protocol header.
+    Missing,
+    Malformed(&'static str),
+    /// The connection closed or failed before the header was complete.
+    Closed,
+    TimedOut,
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::Missing => write!(f, "Connection did not start with a PROXY protocol header"),
+            HeaderError::Malformed(reason) => write!(f, "Malformed PROXY protocol header: {}", reason),
+            HeaderError::Closed => write!(f, "Connection closed during the PROXY protocol header"),
+            HeaderError::TimedOut => write!(f, "Timed out reading the PROXY protocol header"),
+        }
+    }
+}
+
+/// Reads the PROXY protocol header a load balancer sends ahead of everything else,
+/// TLS included, and returns the connection reporting the client it names as its peer.
+/// A connection without a valid header is closed, since its client cannot be known.
+///
+/// Anyone able to connect can send a header, so the client it names is only believed
+/// when the connection comes from a trusted proxy, or over a Unix socket, which has
+/// no peer address to check. Otherwise the connection keeps its own peer.
+pub(crate) fn accept(mut transport: Box<dyn Transport>, settings: &Settings) -> Option<Box<dyn Transport>> {
+    let (source, buffered) = match read_header(transport.as_mut(), settings.header_timeout) {
+        Ok(header) => header,
+        Err(e) => {
+            HttpListener::log(format!("{}", e).as_str());
+            return None;
+        }
+    };
+    let source = match transport.peer_addr() {
+        Some(peer) if !is_trusted(&settings.trusted_proxies, peer.ip()) => {
+            HttpListener::log(format!("Ignoring PROXY protocol header from untrusted peer {}", peer).as_str());
+            None
+        },
+        _ => source,
+    };
+    Some(Box::new(ProxiedTransport {
+        inner: transport,
+        buffered: Cursor::new(buffered),
+        source,
+    }))
+}
+
+/// Reads until a complete header has arrived, returning the client address and whatever
+/// followed the header.
+fn read_header(transport: &mut dyn Transport, timeout: Duration) -> Result<(Option<SocketAddr>, Vec<u8>), HeaderError> {
+    let started = Instant::now();
+    let mut data = Vec::new();
+    let mut buffer = [0; 512];
+    loop {
+        if let Some((source, length)) = parse_header(&data)? {
+            data.drain(..length);
+            return Ok((source, data));
+        }
+        let remaining = match timeout.checked_sub(started.elapsed()) {
+            Some(remaining) if remaining.as_millis() > 0 => remaining,
+            _ => return Err(HeaderError::TimedOut),
+        };
+        if transport.set_read_timeout(Some(remaining)).is_err() {
+            return Err(HeaderError::Closed);
+        }
+        match transport.read(&mut buffer) {
+            Ok(0) => return Err(HeaderError::Closed),
+            Ok(read_size) => data.extend_from_slice(&buffer[..read_size]),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => (),
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(_) => return Err(HeaderError::Closed),
+        }
+    }
+}
+
+/// Parses the header at the start of `data` into the client address it names and its
+/// length, or `None` while it is incomplete. Health checks (`LOCAL`) and unknown
+/// protocols name no client, leaving the proxy's own address as the peer.
+fn parse_header(data: &[u8]) -> Result<Option<(Option<SocketAddr>, usize)>, HeaderError> {
+    let known = data.len().min(V2_SIGNATURE.len());
+    if data[..known] == V2_SIGNATURE[..known] {
+        return parse_v2(data);
+    }
+    let known = data.len().min(6);
+    if data[..known] == b"PROXY "[..known] {
+        return parse_v1(data);
+    }
+    Err(HeaderError::Missing)
+}
+
+/// `PROXY TCP4 <source> <destination> <source port> <destination port>\r\n`
+fn parse_v1(data: &[u8]) -> Result<Option<(Option<SocketAddr>, usize)>, HeaderError> {
+    let end = match data.windows(2).position(|pair| pair == b"\r\n") {
+        Some(end) if end + 2 <= V1_MAX_LENGTH => end,
+        None if data.len() < V1_MAX_LENGTH => return Ok(None),
+        _ => return Err(HeaderError::Malformed("version 1 header is too long")),
+    };
+    let line = std::str::from_utf8(&data[..end]).map_err(|_| HeaderError::Malformed("version 1 header is not text"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let source = match fields.get(1) {
+        Some(&"UNKNOWN") => None,
+        Some(&family) if (family == "TCP4" || family == "TCP6") && fields.len() == 6 => {
+            let source = fields[2].parse::<IpAddr>().ok();
+            let destination = fields[3].parse::<IpAddr>().ok();
+            let ports = (fields[4].parse::<u16>(), fields[5].parse::<u16>());
+            match (source, destination, ports) {
+                (Some(source), Some(destination), (Ok(port), Ok(_)))
+                    if source.is_ipv4() == (family == "TCP4") && destination.is_ipv4() == source.is_ipv4() => Some(SocketAddr::new(source, port)),
+                _ => return Err(HeaderError::Malformed("invalid version 1 address")),
+            }
+        },
+        _ => return Err(HeaderError::Malformed("unknown version 1 protocol")),
+    };
+    Ok(Some((source, end + 2)))
+}
+
+/// The binary header: signature, version and command, address family, address length.
+fn parse_v2(data: &[u8]) -> Result<Option<(Option<SocketAddr>, usize)>, HeaderError> {
+    if data.len() < 16 {
+        return Ok(None);
+    }
+    let length = 16 + u16::from_be_bytes([data[14], data[15]]) as usize;
+    if data.len() < length {
+        return Ok(None);
+    }
+    if data[12] >> 4 != 2 {
+        return Err(HeaderError::Malformed("unsupported version"));
+    }
+    let addresses = &data[16..length];
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    let source = match (data[12] & 0x0f, data[13] >> 4) {
+        //LOCAL: the proxy's own connection, e.g. a health check
+        (0, _) => None,
+        (1, 1) if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            Some(SocketAddr::new(IpAddr::V4(ip), port(8)))
+        },
+        (1, 2) if addresses.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port(32)))
+        },
+        //Unspecified or Unix socket addresses
+        (1, 0) | (1, 3) => None,
+        (1, _) => return Err(HeaderError::Malformed("invalid address block")),
+        _ => return Err(HeaderError::Malformed("unknown command")),
+    };
+    Ok(Some((source, length)))
+}
+
+/// A connection whose PROXY protocol header has been read.
+struct ProxiedTransport {
+    inner: Box<dyn Transport>,
+    /// What arrived after the header, read before the connection itself.
+    buffered: Cursor<Vec<u8>>,
+    /// The client the proxy is connected to, when the header named one.
+    source: Option<SocketAddr>,
+}
+
+impl Read for ProxiedTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if (self.buffered.position() as usize) < self.buffered.get_ref().len() {
+            return self.buffered.read(buf);
+        }
+        self.inner.read(buf)
+    }
+}
+
+impl Write for ProxiedTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for ProxiedTransport {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.source.or_else(|| self.inner.peer_addr())
+    }
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_addr()
+    }
+    fn secure(&self) -> bool {
+        self.inner.secure()
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+    fn closer(&self) -> Option<Closer> {
+        self.inner.closer()
+    }
+}
+
+/// Sets `client_ip` and `protocol` of a request from the connection peer `peer`.
+///
+/// Only a peer in `trusted` is believed about who it forwards for: its Forwarded header,
+/// or else X-Forwarded-For and X-Forwarded-Proto, is walked from the nearest hop back
+/// to the first address that is not a trusted proxy, which is the client. Anything a
+/// client sends in these headers itself therefore ends up to the left of it and is
+/// ignored. A hop hidden behind an obfuscated or `unknown` node stops the walk.
+pub(crate) fn resolve(request: &mut Request, peer: Option<SocketAddr>, trusted: &[Network]) {
+    let peer = match peer {
+        Some(peer) => peer.ip().to_canonical(),
+        None => return,
+    };
+    request.client_ip = Some(peer);
+    if !is_trusted(trusted, peer) {
+        return;
+    }
+    let (hops, protos) = match request.header_value("Forwarded") {
+        Some(forwarded) => forwarded_elements(forwarded),
+        None => (
+            list(request.header_value("X-Forwarded-For")).map(parse_node).collect(),
+            list(request.header_value("X-Forwarded-Proto")).map(|proto| Some(String::from(proto))).collect(),
+        ),
+    };
+
+    let mut client = None;
+    for (index, hop) in hops.iter().enumerate().rev() {
+        match hop {
+            Some(ip) => {
+                client = Some(index);
+                request.client_ip = Some(*ip);
+                if !is_trusted(trusted, *ip) {
+                    break;
+                }
+            },
+            None => break,
+        }
+    }
+    //Each Forwarded element names the scheme its hop was reached over
+    let proto = match client {
+        Some(index) if protos.len() == hops.len() => protos[index].clone(),
+        _ => protos.last().cloned().flatten(),
+    };
+    if let Some(proto) = proto {
+        if proto.eq_ignore_ascii_case("http") || proto.eq_ignore_ascii_case("https") {
+            let proto = proto.to_ascii_lowercase();
+            //An absolute-form target names the scheme as well
+            if let Some(rest) = request.url.strip_prefix(format!("{}://", request.protocol).as_str()) {
+                request.url = format!("{}://{}", proto, rest);
+            }
+            request.protocol = proto;
+        }
+    }
+}
+
+fn is_trusted(trusted: &[Network], ip: IpAddr) -> bool {
+    trusted.iter().any(|network| network.contains(ip))
+}
+
+fn list(value: Option<&str>) -> impl Iterator<Item = &str> {
+    value.unwrap_or("").split(',').map(str::trim).filter(|item| !item.is_empty())
+}
+
+/// The `for` and `proto` of every element of a Forwarded header (RFC 7239).
+fn forwarded_elements(forwarded: &str) -> (Vec<Option<IpAddr>>, Vec<Option<String>>) {
+    let mut hops = Vec::new();
+    let mut protos = Vec::new();
+    for element in list(Some(forwarded)) {
+        let mut node = None;
+        let mut proto = None;
+        for pair in element.split(';') {
+            let mut parts = pair.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = parts.next().unwrap_or("").trim().trim_matches('"');
+            if key.eq_ignore_ascii_case("for") {
+                node = parse_node(value);
+            } else if key.eq_ignore_ascii_case("proto") {
+                proto = Some(String::from(value));
+            }
+        }
+        hops.push(node);
+        protos.push(proto);
+    }
+    (hops, protos)
+}
+
+/// An address as forwarding headers write it: bare, with a port, or bracketed IPv6.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    let ip = match node.strip_prefix('[') {
+        Some(bracketed) => IpAddr::V6(bracketed.split(']').next()?.parse::<Ipv6Addr>().ok()?),
+        None => node.parse::<IpAddr>().ok().or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))?,
+    };
+    Some(ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+    use std::thread;
+    use crate::transport::MemoryTransport;
+    use crate::{Context, Response};
+
+    fn networks(networks: &[&str]) -> Vec<Network> {
+        networks.iter().map(|network| Network::parse(network).unwrap()).collect()
+    }
+    fn request(headers: &[(&str, &str)]) -> Request {
+        let mut request = Request::empty();
+        request.protocol = String::from("http");
+        for (name, value) in headers {
+            request.header.insert(name.to_string(), value.to_string());
+        }
+        request
+    }
+
+    #[test]
+    fn test_networks() {
+        let trusted = networks(&["10.0.0.0/8", "192.168.1.7", "2001:db8::/32"]);
+        assert!(is_trusted(&trusted, "10.200.1.1".parse().unwrap()));
+        assert!(is_trusted(&trusted, "::ffff:10.0.0.1".parse().unwrap()));
+        assert!(is_trusted(&trusted, "192.168.1.7".parse().unwrap()));
+        assert!(!is_trusted(&trusted, "192.168.1.8".parse().unwrap()));
+        assert!(is_trusted(&trusted, "2001:db8:1::1".parse().unwrap()));
+        assert!(!is_trusted(&trusted, "2001:db9::1".parse().unwrap()));
+        assert!(Network::parse("10.0.0.0/33").is_none());
+        assert!(Network::parse("proxy.local").is_none());
+        assert!(Network::parse("0.0.0.0/0").unwrap().contains("8.8.8.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_v1_headers() {
+        let header = b"PROXY TCP4 203.0.113.9 10.0.0.1 56324 443\r\nGET /";
+        assert_eq!(parse_header(header), Ok(Some((Some("203.0.113.9:56324".parse().unwrap()), 43))));
+        let header = b"PROXY TCP6 2001:db8::9 2001:db8::1 56324 443\r\n";
+        assert_eq!(parse_header(header), Ok(Some((Some("[2001:db8::9]:56324".parse().unwrap()), header.len()))));
+        assert_eq!(parse_header(b"PROXY UNKNOWN\r\n"), Ok(Some((None, 15))));
+        assert_eq!(parse_header(b"PROXY TCP4 203.0.113.9"), Ok(None));
+        assert_eq!(parse_header(b"PRO"), Ok(None));
+        assert!(matches!(parse_header(b"PROXY TCP4 2001:db8::9 10.0.0.1 1 2\r\n"), Err(HeaderError::Malformed(_))));
+        assert!(matches!(parse_header(&[b'P'; 200][..]), Err(HeaderError::Missing)));
+        assert!(matches!(parse_header(format!("PROXY TCP4 {}", "1".repeat(120)).as_bytes()), Err(HeaderError::Malformed(_))));
+        assert_eq!(parse_header(b"GET / HTTP/1.1\r\n"), Err(HeaderError::Missing));
+    }
+
+    #[test]
+    fn test_v2_headers() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 12, 203, 0, 113, 9, 10, 0, 0, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        header.extend_from_slice(b"GET /");
+        assert_eq!(parse_header(&header), Ok(Some((Some("203.0.113.9:56324".parse().unwrap()), 28))));
+        assert_eq!(parse_header(&header[..20]), Ok(None));
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(parse_header(&local), Ok(Some((None, 16))));
+
+        let mut short = V2_SIGNATURE.to_vec();
+        short.extend_from_slice(&[0x21, 0x21, 0, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(matches!(parse_header(&short), Err(HeaderError::Malformed(_))));
+        let mut version = V2_SIGNATURE.to_vec();
+        version.extend_from_slice(&[0x31, 0x11, 0, 0]);
+        assert!(matches!(parse_header(&version), Err(HeaderError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_resolve_forwarded_for() {
+        let trusted = networks(&["10.0.0.0/8"]);
+        let peer = Some("10.0.0.2:40000".parse().unwrap());
+
+        //A client's own X-Forwarded-For entry is left of the one the proxy appended
+        let mut forged = request(&[("X-Forwarded-For", "1.2.3.4, 203.0.113.9, 10.0.0.5"), ("X-Forwarded-Proto", "https")]);
+        resolve(&mut forged, peer, &trusted);
+        assert_eq!(forged.client_ip, Some("203.0.113.9".parse().unwrap()));
+        assert_eq!(forged.protocol, "https");
+
+        //Untrusted peers are taken at their word only for their own address
+        let mut direct = request(&[("X-Forwarded-For", "1.2.3.4"), ("X-Forwarded-Proto", "https")]);
+        resolve(&mut direct, Some("203.0.113.9:40000".parse().unwrap()), &trusted);
+        assert_eq!(direct.client_ip, Some("203.0.113.9".parse().unwrap()));
+        assert_eq!(direct.protocol, "http");
+
+        let mut unknown = request(&[("X-Forwarded-For", "203.0.113.9, unknown")]);
+        resolve(&mut unknown, peer, &trusted);
+        assert_eq!(unknown.client_ip, Some("10.0.0.2".parse().unwrap()));
+
+        let mut proto = request(&[("X-Forwarded-Proto", "gopher")]);
+        resolve(&mut proto, peer, &trusted);
+        assert_eq!(proto.protocol, "http");
+    }
+
+    #[test]
+    fn test_resolve_forwarded() {
+        let trusted = networks(&["10.0.0.0/8", "2001:db8::/32"]);
+        let mut forwarded = request(&[
+            ("Forwarded", "for=198.51.100.1;proto=http, for=\"[2001:db8:cafe::17]:4711\";proto=https, for=10.0.0.5:8080;proto=http"),
+            ("X-Forwarded-For", "1.2.3.4"),
+        ]);
+        resolve(&mut forwarded, Some("10.0.0.2:40000".parse().unwrap()), &trusted);
+        assert_eq!(forwarded.client_ip, Some("198.51.100.1".parse().unwrap()));
+        assert_eq!(forwarded.protocol, "http");
+
+        let mut hidden = request(&[("Forwarded", "for=_hidden;proto=https")]);
+        resolve(&mut hidden, Some("10.0.0.2:40000".parse().unwrap()), &trusted);
+        assert_eq!(hidden.client_ip, Some("10.0.0.2".parse().unwrap()));
+        assert_eq!(hidden.protocol, "https");
+    }
+
+    fn client(context: &Context) -> Response {
+        let peer = context.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+        let client_ip = context.request.client_ip.map(|ip| ip.to_string()).unwrap_or_default();
+        Response::ok_text(format!("{} {} {}", peer, client_ip, context.request.protocol).as_str())
+    }
+
+    #[test]
+    fn test_proxy_protocol_connection() {
+        let mut listener = HttpListener::new();
+        listener.route("^/$", client);
+        listener.proxy_protocol(true);
+        listener.trusted_proxies(&["10.0.0.0/8"]);
+
+        let input = b"PROXY TCP4 10.0.0.5 10.0.0.1 56324 80\r\nGET / HTTP/1.1\r\nX-Forwarded-For: 203.0.113.9\r\nX-Forwarded-Proto: https\r\nConnection: close\r\n\r\n";
+        let (transport, output) = MemoryTransport::new(input);
+        listener.serve_transport(Box::new(transport.with_peer_addr("10.0.0.2:40000".parse().unwrap())));
+        assert!(output.lock().unwrap().ends_with(b"10.0.0.5:56324 203.0.113.9 https"));
+
+        let (transport, output) = MemoryTransport::new(b"GET / HTTP/1.1\r\n\r\n");
+        listener.serve_transport(Box::new(transport));
+        assert!(output.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_untrusted_proxy_protocol_header() {
+        let mut listener = HttpListener::new();
+        listener.route("^/$", client);
+        listener.proxy_protocol(true);
+        listener.trusted_proxies(&["10.0.0.0/8"]);
+
+        let input = b"PROXY TCP4 10.0.0.5 10.0.0.1 56324 80\r\nGET / HTTP/1.1\r\nX-Forwarded-For: 10.0.0.7\r\nConnection: close\r\n\r\n";
+        let (transport, output) = MemoryTransport::new(input);
+        listener.serve_transport(Box::new(transport.with_peer_addr("203.0.113.50:40000".parse().unwrap())));
+        assert!(output.lock().unwrap().ends_with(b"203.0.113.50:40000 203.0.113.50 http"));
+    }
+
+    #[test]
+    fn test_shutdown_closes_stalled_header() {
+        let mut listener = HttpListener::new();
+        listener.proxy_protocol(true);
+        listener.header_timeout(Duration::from_secs(30));
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+        client.write_all(b"PROXY TCP4").unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        handle.shutdown();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(client.read(&mut [0; 16]).unwrap_or(0), 0);
+    }
+
+    #[test]
+    fn test_forwarded_proto_updates_absolute_url() {
+        let trusted = networks(&["10.0.0.0/8"]);
+        let mut absolute = request(&[("X-Forwarded-Proto", "https")]);
+        absolute.url = String::from("http://example.com/items");
+        resolve(&mut absolute, Some("10.0.0.2:40000".parse().unwrap()), &trusted);
+        assert_eq!(absolute.protocol, "https");
+        assert_eq!(absolute.url, "https://example.com/items");
+    }
+}
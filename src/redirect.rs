@@ -0,0 +1,54 @@
+use url::Url;
+use crate::context::{HttpMethod, Request, Response};
+
+/// The port browsers use for `https` URLs without one.
+const HTTPS_PORT: u16 = 443;
+
+/// Sends a plaintext request to the same path on the HTTPS origin at `port`.
+///
+/// GET requests get a 301, which every client follows; anything else gets a 308 so the
+/// method and body are repeated over HTTPS instead of turning into a GET.
+pub(crate) fn to_https(request: &Request, port: u16) -> Response {
+    let location = match location(request, port) {
+        Some(location) => location,
+        None => return Response::bad_request(),
+    };
+    match request.method {
+        HttpMethod::GET => Response::moved_permanently(&location),
+        _ => Response::permanent_redirect(&location),
+    }
+}
+
+/// The `https` URL for the request's host, path and query string.
+fn location(request: &Request, port: u16) -> Option<String> {
+    //The parser has already rejected a Host that is not a bare authority
+    let host = request.header_value("Host").filter(|host| !host.is_empty()).unwrap_or("localhost");
+    let mut url = Url::parse(format!("https://{}/", host).as_str()).ok()?;
+    url.set_port(if port == HTTPS_PORT { None } else { Some(port) }).ok()?;
+    url.set_path(&request.path);
+    if !request.querystring.is_empty() {
+        url.set_query(Some(&request.querystring));
+    }
+    Some(url.into_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location_of(request: &str, port: u16) -> (u16, String) {
+        let response = to_https(&Request::from_request_data(request).unwrap(), port);
+        let location = response.headers.iter().find(|(name, _)| name == "Location").map(|(_, value)| value.clone());
+        (response.http_type.code(), location.unwrap_or_default())
+    }
+
+    #[test]
+    fn test_redirect_keeps_path_and_query() {
+        assert_eq!(location_of("GET /a/b?x=1&y=2 HTTP/1.1\r\nHost: example.com:8080\r\n\r\n", 443),
+            (301, String::from("https://example.com/a/b?x=1&y=2")));
+        assert_eq!(location_of("POST /form HTTP/1.1\r\nHost: example.com\r\nContent-Length: 0\r\n\r\n", 8443),
+            (308, String::from("https://example.com:8443/form")));
+        assert_eq!(location_of("GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n", 443),
+            (301, String::from("https://[::1]/")));
+    }
+}
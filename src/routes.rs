@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+use regex::Regex;
+use crate::ServerError;
+
+/// Handlers keyed by the regular expression a request path must match, compiled once
+/// when a server's settings are made instead of on every request.
+pub(crate) struct Routes<H> {
+    routes: Vec<(Regex, H)>,
+}
+
+impl<H: Clone> Routes<H> {
+    /// Compiles the patterns registered on a listener. A pattern that is not a valid
+    /// regular expression never matches; `check_patterns` refuses them before a server
+    /// starts.
+    pub(crate) fn new(patterns: &HashMap<String, H>) -> Routes<H> {
+        let routes = patterns.iter()
+            .filter_map(|(pattern, handler)| Regex::new(pattern).ok().map(|re| (re, handler.clone())))
+            .collect();
+        Routes { routes }
+    }
+}
+
+impl<H> Routes<H> {
+    /// The handler of a pattern matching `path`, if any.
+    pub(crate) fn find(&self, path: &str) -> Option<&H> {
+        self.routes.iter()
+            .find(|(re, _)| re.is_match(path))
+            .map(|(_, handler)| handler)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.routes.clear();
+    }
+}
+
+/// Refuses the first pattern that is not a valid regular expression.
+pub(crate) fn check_patterns<'a>(patterns: impl IntoIterator<Item = &'a String>) -> Result<(), ServerError> {
+    for pattern in patterns {
+        if let Err(e) = Regex::new(pattern) {
+            return Err(ServerError::Config(format!("route {} is not a valid pattern: {}", pattern, e)));
+        }
+    }
+    Ok(())
+}
+
+impl<H> Default for Routes<H> {
+    fn default() -> Routes<H> {
+        Routes { routes: Vec::new() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let mut patterns = HashMap::new();
+        patterns.insert(String::from("^/chat$"), 1);
+        patterns.insert(String::from("^/feed/"), 2);
+        patterns.insert(String::from("(unclosed"), 3);
+        let routes = Routes::new(&patterns);
+        assert_eq!(routes.find("/chat"), Some(&1));
+        assert_eq!(routes.find("/feed/news"), Some(&2));
+        assert_eq!(routes.find("/chat/room"), None);
+        assert_eq!(routes.find("(unclosed"), None);
+    }
+}
@@ -0,0 +1,655 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::io;
+use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use crate::HttpListener;
+use crate::listen::BoundAddr;
+use crate::transport::{Closer, Transport};
+use crate::threadpool::{PoolMetrics, PoolMonitor};
+
+/// What the server does with a new connection while it is at `max_connections` or its
+/// queue of connections waiting for a worker is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverloadPolicy {
+    /// Stop accepting until a connection closes, leaving new clients in the OS backlog.
+    Block,
+    /// Accept and immediately answer `503 Service Unavailable` with this `Retry-After`.
+    Reject(Duration),
+}
+
+/// Reasons a server could not be started.
+#[derive(Debug)]
+pub enum ServerError {
+    /// The configuration given to `HttpListenerBuilder` is invalid.
+    Config(String),
+    /// The listen address could not be bound, e.g. because it is in use.
+    Bind(String, io::Error),
+    /// A TLS certificate or private key file could not be loaded.
+    Certificate(PathBuf, String),
+    Io(io::Error),
+}
+impl Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerError::Config(message) => write!(f, "Invalid configuration: {}", message),
+            ServerError::Bind(addr, e) => write!(f, "Unable to bind {}: {}", addr, e),
+            ServerError::Certificate(path, message) => write!(f, "Unable to load {}: {}", path.display(), message),
+            ServerError::Io(e) => write!(f, "Server error: {}", e),
+        }
+    }
+}
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServerError::Config(_) | ServerError::Certificate(_, _) => None,
+            ServerError::Bind(_, e) | ServerError::Io(e) => Some(e),
+        }
+    }
+}
+
+/// Controls a running server from another thread.
+///
+/// Returned by `HttpListener::spawn`, or taken from `HttpListener::handle` before calling
+/// `start`, and freely cloned. Shutting
+/// down stops the listener accepting, lets requests already being handled finish, closes
+/// idle keep-alive connections and joins the worker threads, after which `start` returns.
+#[derive(Clone)]
+pub struct ServerHandle {
+    state: Arc<ServerState>,
+}
+
+struct ServerState {
+    shutting_down: AtomicBool,
+    next_id: AtomicUsize,
+    status: Mutex<Status>,
+    changed: Condvar,
+}
+
+struct Status {
+    /// Listeners that are accepting, in the order they were started.
+    listeners: BTreeMap<usize, Bound>,
+    connections: HashMap<usize, Tracked>,
+}
+
+impl Status {
+    fn running(&self) -> bool {
+        !self.listeners.is_empty()
+    }
+}
+
+/// The addresses one `HttpListener` is serving and the pool its connections run on.
+struct Bound {
+    addrs: Vec<BoundAddr>,
+    pool: Option<PoolMonitor>,
+}
+
+/// A live connection, kept so shutdown can close it from outside the worker serving it.
+struct Tracked {
+    /// Transports that cannot be closed from outside are left to finish on their own.
+    closer: Option<Closer>,
+    idle: bool,
+}
+
+impl Tracked {
+    fn close(&self) {
+        if let Some(close) = &self.closer {
+            close();
+        }
+    }
+}
+
+impl Default for ServerHandle {
+    fn default() -> Self {
+        ServerHandle::new()
+    }
+}
+
+impl ServerHandle {
+    pub fn new() -> ServerHandle {
+        ServerHandle {
+            state: Arc::new(ServerState {
+                shutting_down: AtomicBool::new(false),
+                next_id: AtomicUsize::new(0),
+                status: Mutex::new(Status {
+                    listeners: BTreeMap::new(),
+                    connections: HashMap::new(),
+                }),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Blocks until the server has been shut down and its workers joined.
+    pub fn wait(&self) {
+        let mut status = self.status();
+        while status.running() {
+            status = self.wait_changed(status);
+        }
+    }
+
+    /// Stops the server and waits for every in-flight request to finish.
+    pub fn shutdown(&self) {
+        self.begin_shutdown();
+        let mut status = self.status();
+        while status.running() {
+            status = self.wait_changed(status);
+        }
+    }
+
+    /// Stops the server, giving in-flight requests up to `timeout` to finish before their
+    /// connections are closed. Returns false if any connection had to be cut off.
+    ///
+    /// A handler that is still running when the timeout expires cannot be interrupted, so
+    /// this returns once it finishes and the workers have been joined.
+    pub fn shutdown_timeout(&self, timeout: Duration) -> bool {
+        self.begin_shutdown();
+        let deadline = Instant::now() + timeout;
+
+        let mut status = self.status();
+        while status.running() && !status.connections.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            status = match self.state.changed.wait_timeout(status, deadline - now) {
+                Ok((status, _)) => status,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+
+        let drained = status.connections.is_empty();
+        for tracked in status.connections.values() {
+            tracked.close();
+        }
+        while status.running() {
+            status = self.wait_changed(status);
+        }
+        drained
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Shuts the server down gracefully when the process receives SIGTERM or SIGINT.
+    #[cfg(all(unix, feature = "signals"))]
+    pub fn shutdown_on_signal(&self) -> std::io::Result<()> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        use signal_hook::iterator::Signals;
+
+        let mut signals = Signals::new([SIGTERM, SIGINT])?;
+        let handle = self.clone();
+        std::thread::spawn(move || {
+            if signals.forever().next().is_some() {
+                HttpListener::log("Received signal, shutting down");
+                handle.shutdown();
+            }
+        });
+        Ok(())
+    }
+
+    /// The first address the server is listening on, while it is running. This is how to
+    /// find the port picked by the system when binding to port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addrs().first().copied()
+    }
+
+    /// Every TCP address the server is listening on, across all listeners sharing this
+    /// handle. Unix sockets are not included.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.status().listeners.values()
+            .flat_map(|bound| bound.addrs.iter())
+            .filter_map(|addr| match addr {
+                BoundAddr::Tcp(addr) => Some(*addr),
+                #[cfg(unix)]
+                BoundAddr::Unix(_) => None,
+            })
+            .collect()
+    }
+
+    /// What the worker pools serving connections are doing, added up across all
+    /// listeners sharing this handle, while the server is running.
+    pub fn pool_metrics(&self) -> Option<PoolMetrics> {
+        self.status().listeners.values()
+            .filter_map(|bound| bound.pool.as_ref().map(PoolMonitor::metrics))
+            .reduce(PoolMetrics::combine)
+    }
+
+    /// Records a listener accepting on `addrs`, returning the id to report it stopped with.
+    /// A shutdown requested before the listener started stays pending, so its accept
+    /// loop stops straight away.
+    pub(crate) fn started(&self, addrs: Vec<BoundAddr>) -> usize {
+        let mut status = self.status();
+        let id = self.state.next_id.fetch_add(1, Ordering::SeqCst);
+        status.listeners.insert(id, Bound { addrs, pool: None });
+        id
+    }
+
+    pub(crate) fn stopped(&self, id: usize) {
+        let mut status = self.status();
+        status.listeners.remove(&id);
+        //The shutdown is complete, so the handle can be started again
+        if !status.running() {
+            self.state.shutting_down.store(false, Ordering::SeqCst);
+        }
+        self.state.changed.notify_all();
+    }
+
+    pub(crate) fn set_pool(&self, id: usize, pool: PoolMonitor) {
+        if let Some(bound) = self.status().listeners.get_mut(&id) {
+            bound.pool = Some(pool);
+        }
+    }
+
+    /// Tracks a newly accepted connection until the returned guard is dropped.
+    /// Returns `None` once the server is shutting down.
+    pub(crate) fn register(&self, transport: &dyn Transport) -> Option<ConnectionGuard> {
+        let closer = transport.closer();
+        let mut status = self.status();
+        if self.is_shutting_down() {
+            return None;
+        }
+        let id = self.state.next_id.fetch_add(1, Ordering::SeqCst);
+        status.connections.insert(id, Tracked { closer, idle: false });
+        Some(ConnectionGuard { handle: self.clone(), id })
+    }
+
+    fn begin_shutdown(&self) {
+        let status = self.status();
+        if self.state.shutting_down.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        for tracked in status.connections.values() {
+            if tracked.idle {
+                tracked.close();
+            }
+        }
+        let addrs: Vec<BoundAddr> = status.listeners.values().flat_map(|bound| bound.addrs.iter().cloned()).collect();
+        drop(status);
+
+        //Each accept loop is blocked waiting for a connection, so give it one; if that
+        //fails the loop still notices within ACCEPT_WAIT
+        for addr in addrs {
+            let woken = match addr {
+                BoundAddr::Tcp(addr) => {
+                    let ip = match addr.ip() {
+                        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                        ip => ip,
+                    };
+                    TcpStream::connect_timeout(&SocketAddr::new(ip, addr.port()), Duration::from_secs(1)).is_ok()
+                },
+                #[cfg(unix)]
+                BoundAddr::Unix(path) => UnixStream::connect(path).is_ok(),
+            };
+            if !woken {
+                HttpListener::log("Unable to wake the listener for shutdown, waiting for its accept to time out");
+            }
+        }
+    }
+
+    fn status(&self) -> MutexGuard<'_, Status> {
+        match self.state.status.lock() {
+            Ok(status) => status,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn wait_changed<'a>(&self, status: MutexGuard<'a, Status>) -> MutexGuard<'a, Status> {
+        match self.state.changed.wait(status) {
+            Ok(status) => status,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// Counts connections from the moment they are accepted until they close.
+pub(crate) struct ConnectionSlots {
+    used: Mutex<usize>,
+    freed: Condvar,
+    max: usize,
+}
+
+/// One of the `max_connections` slots, released when dropped.
+pub(crate) struct ConnectionSlot {
+    slots: Arc<ConnectionSlots>,
+}
+
+impl ConnectionSlots {
+    pub(crate) fn new(max: usize) -> Arc<ConnectionSlots> {
+        Arc::new(ConnectionSlots { used: Mutex::new(0), freed: Condvar::new(), max })
+    }
+
+    pub(crate) fn try_acquire(slots: &Arc<ConnectionSlots>) -> Option<ConnectionSlot> {
+        let mut used = slots.used.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if *used >= slots.max {
+            return None;
+        }
+        *used += 1;
+        Some(ConnectionSlot { slots: Arc::clone(slots) })
+    }
+
+    /// Waits for a free slot, giving up with `None` once the server is shutting down.
+    pub(crate) fn acquire(slots: &Arc<ConnectionSlots>, handle: &ServerHandle) -> Option<ConnectionSlot> {
+        let mut used = slots.used.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        while *used >= slots.max {
+            if handle.is_shutting_down() {
+                return None;
+            }
+            used = match slots.freed.wait_timeout(used, Duration::from_millis(100)) {
+                Ok((used, _)) => used,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+        *used += 1;
+        Some(ConnectionSlot { slots: Arc::clone(slots) })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut used = self.slots.used.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *used -= 1;
+        self.slots.freed.notify_one();
+    }
+}
+
+/// Keeps a connection registered with its server while it is being served.
+pub(crate) struct ConnectionGuard {
+    handle: ServerHandle,
+    id: usize,
+}
+
+impl ConnectionGuard {
+    /// Marks the connection as waiting for a new request, where shutdown may close it.
+    /// Returns false if the server is shutting down and the connection should close now.
+    pub(crate) fn set_idle(&self, idle: bool) -> bool {
+        let mut status = self.handle.status();
+        if idle && self.handle.is_shutting_down() {
+            return false;
+        }
+        if let Some(tracked) = status.connections.get_mut(&self.id) {
+            tracked.idle = idle;
+        }
+        true
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut status = self.handle.status();
+        status.connections.remove(&self.id);
+        self.handle.state.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::prelude::*;
+    use std::thread;
+    use crate::{Context, Response};
+
+    fn slow(_context: &Context) -> Response {
+        thread::sleep(Duration::from_millis(300));
+        Response::ok_text("slow")
+    }
+    fn fast(_context: &Context) -> Response {
+        Response::ok_text("fast")
+    }
+    fn broken(_context: &Context) -> Response {
+        panic!("handler bug");
+    }
+    static AUDITED: AtomicUsize = AtomicUsize::new(0);
+    fn audit(context: &Context) -> Response {
+        context.executor().unwrap().spawn(|| {
+            thread::sleep(Duration::from_millis(200));
+            AUDITED.fetch_add(1, Ordering::SeqCst);
+        });
+        Response::ok_text("audit")
+    }
+
+    fn start() -> (ServerHandle, SocketAddr) {
+        let mut listener = HttpListener::new();
+        listener.route("^/slow", slow);
+        listener.route("^/fast", fast);
+        listener.route("^/broken", broken);
+        listener.route("^/audit", audit);
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+        let addr = handle.local_addr().unwrap();
+        (handle, addr)
+    }
+
+    #[test]
+    fn test_shutdown_drains_in_flight_requests() {
+        let (handle, addr) = start();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        handle.shutdown();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Connection: close"));
+        assert!(response.ends_with("slow"));
+        assert!(TcpStream::connect(addr).is_err());
+    }
+    #[test]
+    fn test_shutdown_closes_idle_keep_alive_connections() {
+        let (handle, addr) = start();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /fast HTTP/1.1\r\n\r\n").unwrap();
+        let mut buffer = [0; 1024];
+        let mut response = Vec::new();
+        while !response.ends_with(b"fast") {
+            let read_size = client.read(&mut buffer).unwrap();
+            assert!(read_size > 0);
+            response.extend_from_slice(&buffer[..read_size]);
+        }
+
+        let started = Instant::now();
+        assert!(handle.shutdown_timeout(Duration::from_secs(3)));
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(client.read(&mut buffer).unwrap(), 0);
+    }
+    #[test]
+    fn test_start_reports_bind_errors() {
+        let (handle, addr) = start();
+
+        let result = HttpListener::new().start(addr.to_string().as_str());
+        assert!(matches!(result, Err(ServerError::Bind(_, _))));
+
+        handle.shutdown();
+    }
+    #[test]
+    fn test_start_blocks_until_shutdown() {
+        let listener = HttpListener::new();
+        let handle = listener.handle();
+        let server = thread::spawn(move || listener.start("127.0.0.1:0"));
+        while handle.local_addr().is_none() {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let mut client = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+        client.write_all(b"GET /missing HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 404"));
+
+        handle.shutdown();
+        assert!(server.join().unwrap().is_ok());
+    }
+    #[test]
+    fn test_shutdown_before_start_is_kept() {
+        let listener = HttpListener::new();
+        let handle = listener.handle();
+        handle.shutdown();
+
+        let started = Instant::now();
+        assert!(listener.start("127.0.0.1:0").is_ok());
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        //Once that shutdown is done the handle serves again
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+        assert!(TcpStream::connect(handle.local_addr().unwrap()).is_ok());
+        handle.shutdown();
+    }
+    #[test]
+    fn test_panicking_handler_answers_500() {
+        let (handle, addr) = start();
+        for _ in 0..3 {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(b"GET /broken HTTP/1.1\r\n\r\n").unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 500"));
+            assert!(response.contains("Connection: close"));
+        }
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /fast HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("fast"));
+        handle.shutdown();
+    }
+    fn overloaded(policy: OverloadPolicy) -> (ServerHandle, TcpStream) {
+        let handle = HttpListener::builder()
+            .threads(1)
+            .max_connections(1)
+            .overload(policy)
+            .route("^/fast", fast)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        //An idle keep-alive connection holds the only slot
+        let mut first = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+        first.write_all(b"GET /fast HTTP/1.1\r\n\r\n").unwrap();
+        let mut buffer = [0; 1024];
+        assert!(first.read(&mut buffer).unwrap() > 0);
+        (handle, first)
+    }
+    #[test]
+    fn test_overload_reject_answers_503() {
+        let (handle, _first) = overloaded(OverloadPolicy::Reject(Duration::from_secs(7)));
+
+        let mut second = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+        second.write_all(b"GET /fast HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        second.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"));
+        assert!(response.contains("Retry-After: 7\r\n"));
+        handle.shutdown();
+    }
+    #[test]
+    fn test_overload_block_waits_for_a_slot() {
+        let (handle, first) = overloaded(OverloadPolicy::Block);
+
+        let mut second = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+        second.write_all(b"GET /fast HTTP/1.0\r\n\r\n").unwrap();
+        second.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let mut buffer = [0; 1024];
+        assert!(second.read(&mut buffer).is_err(), "the second connection should wait");
+
+        drop(first);
+        second.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut response = String::new();
+        second.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("fast"));
+        handle.shutdown();
+    }
+    #[test]
+    fn test_shutdown_drains_background_jobs() {
+        let (handle, addr) = start();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /audit HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("audit"));
+        assert_eq!(AUDITED.load(Ordering::SeqCst), 0);
+
+        handle.shutdown();
+        assert_eq!(AUDITED.load(Ordering::SeqCst), 1);
+    }
+    #[test]
+    fn test_listeners_share_one_handle() {
+        //A public listener on two addresses and an admin listener with its own routes
+        let handle = HttpListener::builder()
+            .route("^/fast", fast)
+            .bind_all(&["127.0.0.1:0", "[::1]:0"])
+            .unwrap();
+        let admin = HttpListener::builder()
+            .handle(&handle)
+            .route("^/broken", broken)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let addrs = handle.local_addrs();
+        assert_eq!(addrs.len(), 3);
+        assert_eq!(admin.local_addrs(), addrs);
+
+        for addr in &addrs[..2] {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(b"GET /fast HTTP/1.0\r\n\r\n").unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.ends_with("fast"));
+        }
+        let mut client = TcpStream::connect(addrs[2]).unwrap();
+        client.write_all(b"GET /fast HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 404"));
+
+        handle.shutdown();
+        assert!(admin.local_addrs().is_empty());
+        for addr in addrs {
+            assert!(TcpStream::connect(addr).is_err());
+        }
+    }
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_listener() {
+        let path = std::env::temp_dir().join(format!("rweblet-server-{}.sock", std::process::id()));
+        let uri = format!("unix:{}", path.display());
+        let handle = HttpListener::builder()
+            .route("^/fast", fast)
+            .unix_socket_mode(0o660)
+            .bind(&uri)
+            .unwrap();
+        assert!(handle.local_addrs().is_empty());
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"GET /fast HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("fast"));
+
+        handle.shutdown();
+        assert!(!path.exists());
+    }
+    #[test]
+    fn test_https_redirect_listener() {
+        let mut listener = HttpListener::new();
+        listener.route("^/fast", fast);
+        listener.hsts(Duration::from_secs(3600), false);
+        let handle = listener.spawn_https_redirect("127.0.0.1:0", 8443).unwrap();
+
+        let mut client = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+        client.write_all(b"GET /fast?page=2 HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 301"));
+        assert!(response.contains("Location: https://example.com:8443/fast?page=2\r\n"));
+        //HSTS is only sent over HTTPS
+        assert!(!response.contains("Strict-Transport-Security"));
+
+        handle.shutdown();
+    }
+}
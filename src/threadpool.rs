@@ -1,15 +1,96 @@
+use std::convert::TryFrom;
 use std::thread;
-use std::sync::mpsc;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use crate::HttpListener;
 
+/// How long a worker above the minimum waits for a job before it exits.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A pool of worker threads running queued jobs.
+///
+/// The pool keeps between `min` and `max` workers: it starts `min`, adds one whenever a
+/// job is queued while every worker is busy, and lets workers above `min` exit after
+/// `idle_timeout` without work. Workers are named `rweblet-worker-N`.
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender : mpsc::Sender<Job>,
+    shared: Arc<Shared>,
+    sender : Option<JobSender>,
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+/// A job together with the time it was queued, to measure how long it waited.
+struct Task {
+    job: Job,
+    queued_at: Instant,
+}
+
+enum JobSender {
+    Unbounded(mpsc::Sender<Task>),
+    Bounded(mpsc::SyncSender<Task>),
+}
+
+/// State shared between the pool and its workers.
+struct Shared {
+    workers: Mutex<Vec<Worker>>,
+    receiver: Mutex<mpsc::Receiver<Task>>,
+    min: usize,
+    max: usize,
+    idle_timeout: Duration,
+    next_id: AtomicUsize,
+    queued: AtomicUsize,
+    busy: AtomicUsize,
+    completed: AtomicU64,
+    wait_nanos: AtomicU64,
+    run_nanos: AtomicU64,
+}
+
+/// A snapshot of what a `ThreadPool` is doing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolMetrics {
+    /// Worker threads currently alive.
+    pub workers: usize,
+    /// Workers running a job.
+    pub busy: usize,
+    /// Workers waiting for a job.
+    pub idle: usize,
+    /// Jobs waiting for a free worker.
+    pub queued: usize,
+    /// Jobs finished since the pool was created, including ones that panicked.
+    pub completed: u64,
+    /// Mean time a finished job spent queued before a worker picked it up.
+    pub average_wait: Duration,
+    /// Mean time a finished job spent running.
+    pub average_run: Duration,
+}
+
+impl PoolMetrics {
+    /// Adds up the metrics of two pools.
+    pub(crate) fn combine(self, other: PoolMetrics) -> PoolMetrics {
+        let completed = self.completed + other.completed;
+        let average = |a: Duration, b: Duration| match completed {
+            0 => Duration::from_secs(0),
+            n => {
+                let total = a.as_nanos() * u128::from(self.completed) + b.as_nanos() * u128::from(other.completed);
+                Duration::from_nanos(u64::try_from(total / u128::from(n)).unwrap_or(u64::MAX))
+            },
+        };
+        PoolMetrics {
+            workers: self.workers + other.workers,
+            busy: self.busy + other.busy,
+            idle: self.idle + other.idle,
+            queued: self.queued + other.queued,
+            completed,
+            average_wait: average(self.average_wait, other.average_wait),
+            average_run: average(self.average_run, other.average_run),
+        }
+    }
+}
+
 impl ThreadPool {
     /// Create a new ThreadPool.
     ///
@@ -18,42 +99,412 @@ impl ThreadPool {
     /// # Panics
     ///
     /// The `new` function will panic if the size is zero.
-    
     pub fn new(count: usize) -> ThreadPool {
-        assert!(count > 0);
         let (sender, receiver) = mpsc::channel();
+        ThreadPool::create(count, count, DEFAULT_IDLE_TIMEOUT, JobSender::Unbounded(sender), receiver)
+    }
+    /// Create a new ThreadPool whose queue holds at most `queue_size` waiting jobs.
+    /// `execute` blocks while the queue is full.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the size is zero.
+    pub fn with_queue(count: usize, queue_size: usize) -> ThreadPool {
+        ThreadPool::elastic(count, count, DEFAULT_IDLE_TIMEOUT, queue_size)
+    }
+    /// Create a ThreadPool that grows from `min` to `max` workers under load and shrinks
+    /// back after `idle_timeout`, with a queue of at most `queue_size` waiting jobs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero or smaller than `min`.
+    pub fn elastic(min: usize, max: usize, idle_timeout: Duration, queue_size: usize) -> ThreadPool {
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        ThreadPool::create(min, max, idle_timeout, JobSender::Bounded(sender), receiver)
+    }
+    fn create(min: usize, max: usize, idle_timeout: Duration, sender: JobSender, receiver: mpsc::Receiver<Task>) -> ThreadPool {
+        assert!(max > 0 && min <= max);
+        let shared = Arc::new(Shared {
+            workers: Mutex::new(Vec::with_capacity(max)),
+            receiver: Mutex::new(receiver),
+            min,
+            max,
+            idle_timeout,
+            next_id: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            busy: AtomicUsize::new(0),
+            completed: AtomicU64::new(0),
+            wait_nanos: AtomicU64::new(0),
+            run_nanos: AtomicU64::new(0),
+        });
 
-        let receiver = Arc::new(Mutex::new(receiver));
-
-        let mut workers = Vec::with_capacity(count);
-
-        for id in 0..count {
-            workers.push( Worker::new(id, Arc::clone(&receiver)) );
+        {
+            let mut workers = shared.workers();
+            for _ in 0..min {
+                if let Some(worker) = Worker::spawn(&shared) {
+                    workers.push(worker);
+                }
+            }
         }
 
-        ThreadPool { workers, sender }
+        ThreadPool { shared, sender: Some(sender) }
     }
+    /// Queues `f` to run on the next free worker.
+    ///
+    /// A job that panics does not take its worker down, and a worker thread that dies
+    /// anyway starts its own replacement, so the pool keeps its size.
     pub fn execute<F>(&self, f: F) 
     where 
         F: FnOnce() + Send + 'static {
-        let job = Box::new(f);
+        let task = Task { job: Box::new(f), queued_at: Instant::now() };
+
+        let queued = self.shared.queued.fetch_add(1, Ordering::SeqCst) + 1;
+        self.adjust_workers(queued);
+        let sent = match &self.sender {
+            Some(JobSender::Unbounded(sender)) => sender.send(task).is_ok(),
+            Some(JobSender::Bounded(sender)) => sender.send(task).is_ok(),
+            None => false,
+        };
+        if !sent {
+            self.shared.queued.fetch_sub(1, Ordering::SeqCst);
+            HttpListener::log("No workers left to run job");
+        }
+    }
+    /// Number of jobs waiting for a free worker.
+    pub fn queued(&self) -> usize {
+        self.shared.queued.load(Ordering::SeqCst)
+    }
+    /// Number of worker threads currently alive.
+    pub fn size(&self) -> usize {
+        self.shared.alive()
+    }
+    pub fn metrics(&self) -> PoolMetrics {
+        self.shared.metrics()
+    }
+    pub(crate) fn monitor(&self) -> PoolMonitor {
+        PoolMonitor { shared: Arc::clone(&self.shared) }
+    }
+    /// Adds a worker if every worker is busy with `queued` jobs waiting.
+    fn adjust_workers(&self, queued: usize) {
+        let mut workers = self.shared.workers();
+        let busy = self.shared.busy.load(Ordering::SeqCst);
+        let wanted = if busy + queued > workers.len() { workers.len() + 1 } else { workers.len() };
+        let wanted = wanted.max(self.shared.min).min(self.shared.max);
+        while workers.len() < wanted {
+            match Worker::spawn(&self.shared) {
+                Some(worker) => workers.push(worker),
+                None => break,
+            }
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Lets the workers finish the queued jobs, then joins them.
+    fn drop(&mut self) {
+        //Closing the channel ends each worker's loop once the queue is empty
+        drop(self.sender.take());
 
-        self.sender.send(job).unwrap();
+        let workers: Vec<Worker> = self.shared.workers().drain(..).collect();
+        for mut worker in workers {
+            if let Some(thread) = worker.thread.take() {
+                if thread.join().is_err() {
+                    HttpListener::log_error(format!("Worker {} panicked", worker.id).as_str());
+                }
+            }
+        }
+    }
+}
+
+/// Reads the metrics of a pool from outside the thread that owns it.
+#[derive(Clone)]
+pub(crate) struct PoolMonitor {
+    shared: Arc<Shared>,
+}
+impl PoolMonitor {
+    pub(crate) fn metrics(&self) -> PoolMetrics {
+        self.shared.metrics()
+    }
+}
+
+impl Shared {
+    fn workers(&self) -> std::sync::MutexGuard<'_, Vec<Worker>> {
+        self.workers.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    fn alive(&self) -> usize {
+        self.workers().iter().filter(|worker| worker.is_alive()).count()
+    }
+    fn metrics(&self) -> PoolMetrics {
+        let workers = self.alive();
+        let busy = self.busy.load(Ordering::SeqCst).min(workers);
+        let completed = self.completed.load(Ordering::SeqCst);
+        let average = |total: &AtomicU64| match completed {
+            0 => Duration::from_secs(0),
+            n => Duration::from_nanos(total.load(Ordering::SeqCst) / n),
+        };
+        PoolMetrics {
+            workers,
+            busy,
+            idle: workers - busy,
+            queued: self.queued.load(Ordering::SeqCst),
+            completed,
+            average_wait: average(&self.wait_nanos),
+            average_run: average(&self.run_nanos),
+        }
+    }
+    /// Removes the calling worker from the pool if there are more than `min` and no job
+    /// is waiting. `execute` counts a job as queued before it looks at the workers, so
+    /// under the workers lock either it sees this worker gone and starts another, or
+    /// this worker sees the job and stays to run it.
+    fn retire(&self, id: usize) -> bool {
+        let mut workers = self.workers();
+        let alive = workers.iter().filter(|worker| worker.is_alive()).count();
+        if alive <= self.min || self.queued.load(Ordering::SeqCst) > 0 {
+            return false;
+        }
+        //Dropping our own handle detaches the thread, which is about to return anyway
+        workers.retain(|worker| worker.id != id);
+        true
     }
 }
 
 struct Worker {
     id: usize,
-    thread: thread::JoinHandle<()>,
+    thread: Option<thread::JoinHandle<()>>,
 }
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let job = receiver.lock().unwrap().recv().unwrap();
-            job();
-        });
-        
-        Worker { id, thread }
+    fn spawn(shared: &Arc<Shared>) -> Option<Worker> {
+        let id = shared.next_id.fetch_add(1, Ordering::SeqCst);
+        let shared = Arc::clone(shared);
+        let thread = thread::Builder::new()
+            .name(format!("rweblet-worker-{}", id))
+            .spawn(move || {
+                let mut sentinel = Sentinel { id, shared: &shared, busy: false };
+                Worker::run(&mut sentinel);
+            });
+        match thread {
+            Ok(thread) => Some(Worker { id, thread: Some(thread) }),
+            Err(e) => {
+                HttpListener::log(format!("Failed to start worker {}: {}", id, e).as_str());
+                None
+            }
+        }
+    }
+    fn run(sentinel: &mut Sentinel) {
+        let (id, shared) = (sentinel.id, sentinel.shared);
+        let mut idle_since = Instant::now();
+        let mut may_retire = shared.min < shared.max;
+        loop {
+            //Workers queue up on the receiver lock; each waits out the rest of its own
+            //idle time, so ones left idle together retire together
+            //A poisoned lock only means another worker panicked; the receiver is still sound
+            let receiver = shared.receiver.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let message = if may_retire {
+                receiver.recv_timeout(shared.idle_timeout.saturating_sub(idle_since.elapsed()))
+            } else {
+                //The pool only grows when a job is sent, which wakes this worker up
+                receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            };
+            drop(receiver);
+            let task = match message {
+                Ok(task) => task,
+                Err(RecvTimeoutError::Timeout) => {
+                    if shared.retire(id) {
+                        return;
+                    }
+                    may_retire = false;
+                    continue;
+                },
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+
+            shared.busy.fetch_add(1, Ordering::SeqCst);
+            sentinel.busy = true;
+            shared.queued.fetch_sub(1, Ordering::SeqCst);
+            let started = Instant::now();
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(task.job)) {
+                HttpListener::log_error(format!("Worker {} job panicked: {}", id, panic_message(&payload)).as_str());
+            }
+            shared.wait_nanos.fetch_add(nanos(started - task.queued_at), Ordering::SeqCst);
+            shared.run_nanos.fetch_add(nanos(started.elapsed()), Ordering::SeqCst);
+            shared.completed.fetch_add(1, Ordering::SeqCst);
+            shared.busy.fetch_sub(1, Ordering::SeqCst);
+            sentinel.busy = false;
+            idle_since = Instant::now();
+            may_retire = shared.min < shared.max;
+        }
+    }
+    fn is_alive(&self) -> bool {
+        match &self.thread {
+            Some(thread) => !thread.is_finished(),
+            None => false,
+        }
+    }
+}
+
+/// Replaces its worker when the worker's thread unwinds, e.g. from a panic payload that
+/// panics again when dropped, which `catch_unwind` cannot hold back.
+struct Sentinel<'a> {
+    id: usize,
+    shared: &'a Arc<Shared>,
+    busy: bool,
+}
+impl Drop for Sentinel<'_> {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            return;
+        }
+        HttpListener::log_error(format!("Worker {} died, respawning it", self.id).as_str());
+        if self.busy {
+            self.shared.busy.fetch_sub(1, Ordering::SeqCst);
+        }
+        let mut workers = self.shared.workers();
+        //Dropping our own handle detaches the thread, which is about to end anyway
+        workers.retain(|worker| worker.id != self.id);
+        if let Some(worker) = Worker::spawn(self.shared) {
+            workers.push(worker);
+        }
+    }
+}
+
+fn nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+/// The message a panic was raised with, when it was a string.
+pub(crate) fn panic_message(payload: &Box<dyn std::any::Any + Send>) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.as_str()
+    } else {
+        "unknown panic"
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_panicking_job_does_not_shrink_pool() {
+        let pool = ThreadPool::new(2);
+        let done = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..4 {
+            pool.execute(|| panic!("job failed"));
+        }
+        for _ in 0..10 {
+            let done = Arc::clone(&done);
+            pool.execute(move || { done.fetch_add(1, Ordering::SeqCst); });
+        }
+        drop(pool);
+        assert_eq!(done.load(Ordering::SeqCst), 10);
+    }
+    #[test]
+    fn test_bounded_queue() {
+        let pool = ThreadPool::with_queue(1, 2);
+        let (release, blocked) = mpsc::channel::<()>();
+        let blocked = Mutex::new(blocked);
+        pool.execute(move || { let _ = blocked.lock().unwrap().recv(); });
+        while pool.queued() > 0 {
+            thread::yield_now();
+        }
+
+        pool.execute(|| ());
+        pool.execute(|| ());
+        assert_eq!(pool.queued(), 2);
+        release.send(()).unwrap();
+        drop(pool);
+    }
+    #[test]
+    fn test_elastic_pool_grows_and_shrinks() {
+        let pool = ThreadPool::elastic(1, 3, Duration::from_millis(100), 16);
+        assert_eq!(pool.size(), 1);
+
+        let (release, blocked) = mpsc::channel::<()>();
+        let blocked = Arc::new(Mutex::new(blocked));
+        for _ in 0..5 {
+            let blocked = Arc::clone(&blocked);
+            pool.execute(move || { let _ = blocked.lock().unwrap().recv(); });
+        }
+        assert_eq!(pool.size(), 3);
+        let metrics = pool.metrics();
+        assert_eq!(metrics.workers, 3);
+        assert_eq!(metrics.busy + metrics.idle, 3);
+
+        for _ in 0..5 {
+            release.send(()).unwrap();
+        }
+        let started = Instant::now();
+        while pool.size() > 1 && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(20));
+        }
+        let metrics = pool.metrics();
+        assert_eq!(metrics.workers, 1);
+        assert_eq!(metrics.completed, 5);
+        assert_eq!(metrics.queued, 0);
+    }
+    #[test]
+    fn test_idle_workers_retire_together() {
+        let pool = ThreadPool::elastic(0, 8, Duration::from_millis(300), 16);
+        let (release, blocked) = mpsc::channel::<()>();
+        let blocked = Arc::new(Mutex::new(blocked));
+        for _ in 0..8 {
+            let blocked = Arc::clone(&blocked);
+            pool.execute(move || { let _ = blocked.lock().unwrap().recv(); });
+        }
+        assert_eq!(pool.size(), 8);
+        for _ in 0..8 {
+            release.send(()).unwrap();
+        }
+
+        //One after another they would take 8 idle timeouts
+        let started = Instant::now();
+        while pool.size() > 0 && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(pool.size(), 0);
+        assert!(started.elapsed() < Duration::from_millis(1500));
+
+        //With no worker left, a new job still gets one
+        let (sender, ran) = mpsc::channel();
+        pool.execute(move || { sender.send(()).unwrap(); });
+        assert!(ran.recv_timeout(Duration::from_secs(2)).is_ok());
+    }
+    #[test]
+    fn test_workers_are_named() {
+        let pool = ThreadPool::new(1);
+        let (sender, name) = mpsc::channel();
+        pool.execute(move || { sender.send(thread::current().name().map(String::from)).unwrap(); });
+        assert_eq!(name.recv().unwrap().unwrap(), "rweblet-worker-0");
+    }
+    #[test]
+    fn test_dead_worker_is_respawned() {
+        //A payload that panics when dropped unwinds past the worker's catch_unwind
+        struct Bomb;
+        impl Drop for Bomb {
+            fn drop(&mut self) {
+                panic!("payload dropped");
+            }
+        }
+        let pool = ThreadPool::new(2);
+        pool.execute(|| panic::panic_any(Bomb));
+        let started = Instant::now();
+        //The replacement is the third worker ever started
+        while pool.shared.next_id.load(Ordering::SeqCst) < 3 && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let metrics = pool.metrics();
+        assert_eq!((metrics.workers, metrics.busy), (2, 0));
+
+        let (sender, ran) = mpsc::channel();
+        for _ in 0..2 {
+            let sender = sender.clone();
+            pool.execute(move || { sender.send(()).unwrap(); });
+        }
+        assert!(ran.recv_timeout(Duration::from_secs(2)).is_ok());
+        assert!(ran.recv_timeout(Duration::from_secs(2)).is_ok());
+    }
+}
@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use crate::transport::{Closer, Transport};
+use crate::ServerError;
+
+/// Certificates for serving HTTPS, loaded from PEM files.
+///
+/// The first certificate is served to clients that ask for a name no other certificate
+/// covers, or for none at all; `add_certificate` adds more, picked by the name the
+/// client asks for (SNI). Clones share the certificates, so a clone kept after handing
+/// the config to `HttpListener::tls` can `reload` them while the server runs.
+///
+/// ```no_run
+/// # use rweblet::HttpListener;
+/// # use rweblet::tls::TlsConfig;
+/// let tls = TlsConfig::from_pem("/etc/rweblet/cert.pem", "/etc/rweblet/key.pem").unwrap();
+/// tls.add_certificate("api.example.com", "/etc/rweblet/api.pem", "/etc/rweblet/api-key.pem").unwrap();
+///
+/// let mut listener = HttpListener::new();
+/// listener.tls(tls.clone());
+/// let handle = listener.spawn("0.0.0.0:443").unwrap();
+///
+/// //After the certificate files have been renewed
+/// tls.reload().unwrap();
+/// # handle.shutdown();
+/// ```
+#[derive(Clone)]
+pub struct TlsConfig {
+    resolver: Arc<CertResolver>,
+    config: Arc<ServerConfig>,
+    /// The same certificates, also offering HTTP/2 to clients that support it.
+    #[cfg(feature = "http2")]
+    http2_config: Arc<ServerConfig>,
+}
+
+/// The files a certificate was loaded from, kept to reload it.
+#[derive(Clone)]
+struct CertificateFiles {
+    /// `None` for the default certificate.
+    server_name: Option<String>,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+/// The certificates currently served, swapped as a whole by a reload.
+struct Certificates {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    files: Vec<CertificateFiles>,
+}
+
+struct CertResolver {
+    certificates: RwLock<Certificates>,
+}
+
+impl TlsConfig {
+    /// Loads the default certificate chain from `cert` and its private key from `key`.
+    pub fn from_pem<P: AsRef<Path>, K: AsRef<Path>>(cert: P, key: K) -> Result<TlsConfig, ServerError> {
+        let files = CertificateFiles { server_name: None, cert: cert.as_ref().to_path_buf(), key: key.as_ref().to_path_buf() };
+        let certificates = Certificates {
+            default: files.load()?,
+            by_name: HashMap::new(),
+            files: vec![files],
+        };
+        let resolver = Arc::new(CertResolver { certificates: RwLock::new(certificates) });
+
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| ServerError::Config(e.to_string()))?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::clone(&resolver) as Arc<dyn ResolvesServerCert>);
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        #[cfg(feature = "http2")]
+        let http2_config = {
+            let mut config = config.clone();
+            config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+            Arc::new(config)
+        };
+        Ok(TlsConfig {
+            resolver,
+            config: Arc::new(config),
+            #[cfg(feature = "http2")]
+            http2_config,
+        })
+    }
+
+    /// Serves the certificate in `cert` to clients asking for `server_name`. A name
+    /// starting with `*.` covers every name one label below it, as in a wildcard
+    /// certificate. Adding a name again replaces its certificate.
+    pub fn add_certificate<P: AsRef<Path>, K: AsRef<Path>>(&self, server_name: &str, cert: P, key: K) -> Result<(), ServerError> {
+        let server_name = server_name.to_ascii_lowercase();
+        let files = CertificateFiles { server_name: Some(server_name.clone()), cert: cert.as_ref().to_path_buf(), key: key.as_ref().to_path_buf() };
+        let key = files.load()?;
+
+        let mut certificates = self.resolver.write();
+        certificates.files.retain(|existing| existing.server_name.as_ref() != Some(&server_name));
+        certificates.files.push(files);
+        certificates.by_name.insert(server_name, key);
+        Ok(())
+    }
+
+    /// Loads every certificate again from its files, for renewed certificates to take
+    /// effect without a restart. Connections made from now on get the new certificates;
+    /// established ones keep theirs.
+    ///
+    /// Nothing changes unless all of them load, so a renewal caught halfway through
+    /// writing its files is reported and the previous certificates stay in use.
+    pub fn reload(&self) -> Result<(), ServerError> {
+        let files = self.resolver.read().files.clone();
+        let mut default = None;
+        let mut by_name = HashMap::new();
+        for file in &files {
+            let key = file.load()?;
+            match &file.server_name {
+                Some(name) => { by_name.insert(name.clone(), key); },
+                None => default = Some(key),
+            }
+        }
+        let mut certificates = self.resolver.write();
+        if let Some(default) = default {
+            certificates.default = default;
+        }
+        certificates.by_name = by_name;
+        Ok(())
+    }
+
+    /// Starts a TLS session on an accepted connection. The handshake happens on the
+    /// first read or write, on the worker serving it.
+    pub(crate) fn accept(&self, transport: Box<dyn Transport>) -> io::Result<Box<dyn Transport>> {
+        TlsConfig::start(&self.config, transport)
+    }
+
+    /// Like `accept`, letting the client choose HTTP/2 with ALPN.
+    #[cfg(feature = "http2")]
+    pub(crate) fn accept_http2(&self, transport: Box<dyn Transport>) -> io::Result<Box<dyn Transport>> {
+        TlsConfig::start(&self.http2_config, transport)
+    }
+
+    fn start(config: &Arc<ServerConfig>, transport: Box<dyn Transport>) -> io::Result<Box<dyn Transport>> {
+        let session = ServerConnection::new(Arc::clone(config)).map_err(io::Error::other)?;
+        Ok(Box::new(TlsStream { stream: StreamOwned::new(session, transport) }))
+    }
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConfig").field("resolver", &self.resolver).finish()
+    }
+}
+
+impl CertificateFiles {
+    fn load(&self) -> Result<Arc<CertifiedKey>, ServerError> {
+        let error = |path: &PathBuf, message: String| ServerError::Certificate(path.clone(), message);
+
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<CertificateDer<'static>>, _>>())
+            .map_err(|e| error(&self.cert, e.to_string()))?;
+        if certs.is_empty() {
+            return Err(error(&self.cert, String::from("no certificate found")));
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key).map_err(|e| error(&self.key, e.to_string()))?;
+        let key = ring::sign::any_supported_type(&key).map_err(|e| error(&self.key, e.to_string()))?;
+
+        let certified = CertifiedKey::new(certs, key);
+        //A key that does not belong to the certificate would only show up as failing handshakes
+        certified.keys_match().map_err(|e| error(&self.key, e.to_string()))?;
+        Ok(Arc::new(certified))
+    }
+}
+
+impl CertResolver {
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Certificates> {
+        self.certificates.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Certificates> {
+        self.certificates.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certificates = self.read();
+        let name = match client_hello.server_name() {
+            Some(name) => name.to_ascii_lowercase(),
+            None => return Some(Arc::clone(&certificates.default)),
+        };
+        if let Some(key) = certificates.by_name.get(&name) {
+            return Some(Arc::clone(key));
+        }
+        let wildcard = name.find('.').map(|dot| format!("*{}", &name[dot..]));
+        match wildcard.and_then(|wildcard| certificates.by_name.get(&wildcard)) {
+            Some(key) => Some(Arc::clone(key)),
+            None => Some(Arc::clone(&certificates.default)),
+        }
+    }
+}
+
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let certificates = self.read();
+        let mut names: Vec<&String> = certificates.by_name.keys().collect();
+        names.sort();
+        f.debug_struct("CertResolver").field("server_names", &names).finish()
+    }
+}
+
+/// A connection with TLS terminated on top of another transport.
+struct TlsStream {
+    stream: StreamOwned<ServerConnection, Box<dyn Transport>>,
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Transport for TlsStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.stream.sock.peer_addr()
+    }
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.stream.sock.local_addr()
+    }
+    fn secure(&self) -> bool {
+        true
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.sock.set_read_timeout(timeout)
+    }
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.sock.set_write_timeout(timeout)
+    }
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.stream.sock.set_nonblocking(nonblocking)
+    }
+    fn closer(&self) -> Option<Closer> {
+        self.stream.sock.closer()
+    }
+}
+
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        //Tell the client the response is complete rather than cut off
+        if !self.stream.conn.is_handshaking() {
+            self.stream.conn.send_close_notify();
+            let _ = self.stream.conn.complete_io(&mut self.stream.sock);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::net::TcpStream;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use rustls::pki_types::ServerName;
+    use crate::{Context, HttpListener, Response};
+
+    struct TestCertificate {
+        cert: PathBuf,
+        key: PathBuf,
+        der: CertificateDer<'static>,
+    }
+
+    fn certificate(file_name: &str, names: &[&str]) -> TestCertificate {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let generated = rcgen::generate_simple_self_signed(names).unwrap();
+        let dir = std::env::temp_dir();
+        let cert = dir.join(format!("rweblet-{}-{}.pem", file_name, std::process::id()));
+        let key = dir.join(format!("rweblet-{}-{}-key.pem", file_name, std::process::id()));
+        std::fs::write(&cert, generated.cert.pem()).unwrap();
+        std::fs::write(&key, generated.key_pair.serialize_pem()).unwrap();
+        TestCertificate { cert, key, der: generated.cert.der().clone() }
+    }
+
+    fn protocol(context: &Context) -> Response {
+        Response::ok_text(context.request.protocol.as_str())
+    }
+
+    /// Makes an HTTPS request for `/` naming `server_name`, trusting only `trusted`.
+    fn get(port: u16, server_name: &str, trusted: &CertificateDer<'static>) -> io::Result<String> {
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted.clone()).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions().unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = ServerName::try_from(server_name.to_string()).unwrap();
+        let session = ClientConnection::new(Arc::new(config), name).unwrap();
+        let socket = TcpStream::connect(("127.0.0.1", port))?;
+        socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut stream = StreamOwned::new(session, socket);
+
+        stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    #[test]
+    fn test_https_request() {
+        let default = certificate("default", &["localhost"]);
+        let tls = TlsConfig::from_pem(&default.cert, &default.key).unwrap();
+        let mut listener = HttpListener::new();
+        listener.route("^/$", protocol);
+        listener.tls(tls);
+        listener.hsts(Duration::from_secs(31536000), true);
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+        let port = handle.local_addr().unwrap().port();
+
+        let response = get(port, "localhost", &default.der).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Strict-Transport-Security: max-age=31536000; includeSubDomains\r\n"));
+        assert!(response.ends_with("https"));
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_sni_and_reload() {
+        let default = certificate("sni-default", &["localhost"]);
+        let api = certificate("sni-api", &["api.example.com"]);
+        let tls = TlsConfig::from_pem(&default.cert, &default.key).unwrap();
+        tls.add_certificate("api.example.com", &api.cert, &api.key).unwrap();
+        let mut listener = HttpListener::new();
+        listener.route("^/$", protocol);
+        listener.tls(tls.clone());
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+        let port = handle.local_addr().unwrap().port();
+
+        assert!(get(port, "api.example.com", &api.der).is_ok());
+        assert!(get(port, "localhost", &default.der).is_ok());
+
+        //Renew the api certificate in place
+        let renewed = certificate("sni-api", &["api.example.com"]);
+        assert!(get(port, "api.example.com", &renewed.der).is_err());
+        tls.reload().unwrap();
+        assert!(get(port, "api.example.com", &renewed.der).is_ok());
+
+        //A broken renewal is reported and the working certificates stay
+        std::fs::write(&renewed.key, "not a key").unwrap();
+        assert!(matches!(tls.reload(), Err(ServerError::Certificate(_, _))));
+        assert!(get(port, "api.example.com", &renewed.der).is_ok());
+        handle.shutdown();
+    }
+
+    #[cfg(feature = "http2")]
+    #[test]
+    fn test_alpn_offers_http2() {
+        let default = certificate("alpn", &["localhost"]);
+        let mut listener = HttpListener::new();
+        listener.route("^/$", protocol);
+        listener.tls(TlsConfig::from_pem(&default.cert, &default.key).unwrap());
+        listener.http2(true);
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+        let port = handle.local_addr().unwrap().port();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(default.der.clone()).unwrap();
+        let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions().unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let session = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+        let socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut stream = StreamOwned::new(session, socket);
+
+        //The preface and an empty SETTINGS frame, answered with the server's SETTINGS
+        stream.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0").unwrap();
+        let mut frame_header = [0; 9];
+        stream.read_exact(&mut frame_header).unwrap();
+        assert_eq!(stream.conn.alpn_protocol(), Some(&b"h2"[..]));
+        assert_eq!(frame_header[3], 0x4);
+
+        //Clients that only speak HTTP/1.1 are still served
+        assert!(get(port, "localhost", &default.der).unwrap().starts_with("HTTP/1.1 200 OK"));
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_mismatched_key_is_rejected() {
+        let first = certificate("mismatch-a", &["localhost"]);
+        let second = certificate("mismatch-b", &["localhost"]);
+        match TlsConfig::from_pem(&first.cert, &second.key) {
+            Err(ServerError::Certificate(path, _)) => assert_eq!(path, second.key),
+            _ => panic!("expected a certificate error"),
+        }
+    }
+}
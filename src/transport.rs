@@ -0,0 +1,191 @@
+use std::io::{self, Cursor, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Closes a connection from another thread, as shutdown does with idle connections.
+pub type Closer = Box<dyn Fn() + Send>;
+
+/// A connection requests are read from and responses written to.
+///
+/// The connection loop and `Context` only use this trait, so a new kind of connection
+/// (TLS, a socket type rweblet does not listen on itself, an in-memory pipe for tests)
+/// is served by implementing it and handing it to `HttpListener::serve_transport`. The
+/// defaults suit a transport without addresses or timeouts.
+pub trait Transport: Read + Write + Send {
+    /// The client's address, when it is an IP connection.
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+    /// The address the client connected to, when it is an IP connection. Requests
+    /// without a Host header are resolved against it.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+    /// Whether the connection is encrypted, which makes its requests `https`.
+    fn secure(&self) -> bool {
+        false
+    }
+    /// Limits how long a read may block; `None` blocks until data arrives.
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+    /// Limits how long a write may block; `None` blocks until the data is sent.
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+    /// While set, reads fail with `WouldBlock` instead of waiting for data; event streams
+    /// use this to notice a client that hung up. The default suits transports whose
+    /// reads never block.
+    fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+        Ok(())
+    }
+    /// A way to close this connection from another thread, if the transport has one.
+    /// Connections without one are left to finish on their own when the server stops.
+    fn closer(&self) -> Option<Closer> {
+        None
+    }
+}
+
+impl Transport for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpStream::local_addr(self).ok()
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+    fn closer(&self) -> Option<Closer> {
+        let stream = self.try_clone().ok()?;
+        Some(Box::new(move || { let _ = stream.shutdown(Shutdown::Both); }))
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+    fn closer(&self) -> Option<Closer> {
+        let stream = self.try_clone().ok()?;
+        Some(Box::new(move || { let _ = stream.shutdown(Shutdown::Both); }))
+    }
+}
+
+/// An in-memory connection that reads a fixed input and then reports the client closed,
+/// for testing handlers and routing without a socket.
+///
+/// ```
+/// # use rweblet::{HttpListener, Context, Response};
+/// # use rweblet::transport::MemoryTransport;
+/// fn index(_context: &Context) -> Response { Response::ok_text("Hello") }
+///
+/// let mut listener = HttpListener::new();
+/// listener.route("^/$", index);
+/// let (transport, output) = MemoryTransport::new(b"GET / HTTP/1.1\r\n\r\n");
+/// listener.serve_transport(Box::new(transport));
+/// assert!(output.lock().unwrap().ends_with(b"Hello"));
+/// ```
+pub struct MemoryTransport {
+    input: Cursor<Vec<u8>>,
+    output: Arc<Mutex<Vec<u8>>>,
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+}
+
+impl MemoryTransport {
+    /// A transport that reads `input`, along with the buffer everything written to it
+    /// is collected in.
+    pub fn new(input: &[u8]) -> (MemoryTransport, Arc<Mutex<Vec<u8>>>) {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let transport = MemoryTransport {
+            input: Cursor::new(input.to_vec()),
+            output: Arc::clone(&output),
+            peer_addr: None,
+            local_addr: None,
+        };
+        (transport, output)
+    }
+    /// Reports the connection as coming from `addr`.
+    pub fn with_peer_addr(mut self, addr: SocketAddr) -> Self {
+        self.peer_addr = Some(addr);
+        self
+    }
+    /// Reports the connection as made to `addr`.
+    pub fn with_local_addr(mut self, addr: SocketAddr) -> Self {
+        self.local_addr = Some(addr);
+        self
+    }
+}
+
+impl Read for MemoryTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for MemoryTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut output = self.output.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Context, HttpListener, Response};
+
+    fn url(context: &Context) -> Response {
+        Response::ok_text(context.request.url.as_str())
+    }
+
+    #[test]
+    fn test_memory_transport_serves_pipelined_requests() {
+        let mut listener = HttpListener::new();
+        listener.route("^/echo", url);
+        let (transport, output) = MemoryTransport::new(b"GET /echo?a=1 HTTP/1.1\r\n\r\nGET /echo?a=2 HTTP/1.1\r\n\r\n");
+        listener.serve_transport(Box::new(transport.with_local_addr("10.0.0.1:8080".parse().unwrap())));
+
+        let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert_eq!(output.matches("HTTP/1.1 200 OK").count(), 2);
+        assert!(output.contains("/echo?a=1"));
+        assert!(output.ends_with("/echo?a=2"));
+    }
+    #[test]
+    fn test_memory_transport_answers_bad_requests() {
+        let listener = HttpListener::new();
+        let (transport, output) = MemoryTransport::new(b"GET /\r\n\r\n");
+        listener.serve_transport(Box::new(transport));
+        assert!(output.lock().unwrap().starts_with(b"HTTP/1.1 400"));
+    }
+}
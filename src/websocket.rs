@@ -0,0 +1,699 @@
+use std::fmt::Display;
+use std::io::{self, ErrorKind};
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+use crate::context::{Context, HttpMethod, HttpResponseType, HttpVersion, Request, Response};
+use crate::transport::Transport;
+use crate::{threadpool, HttpListener, ServerHandle, Settings};
+
+/// Largest message a WebSocket accepts unless configured otherwise.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+/// How often a waiting `recv` checks whether the server is shutting down.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Appended to the client's key to prove the server understood the handshake (RFC 6455 1.3).
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// A WebSocket handler, as registered with `HttpListener::websocket`. It runs on the
+/// connection's worker for as long as the socket is open; returning closes it.
+pub type WebSocketHandler = fn(request: &Request, socket: &mut WebSocket);
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// Close status codes used by the server (RFC 6455 7.4.1).
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_LARGE: u16 = 1009;
+const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+/// A message sent or received on a WebSocket.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The status code and reason, if the closing side gave one.
+    Close(Option<(u16, String)>),
+}
+
+/// Reasons a WebSocket can no longer be used.
+#[derive(Debug)]
+pub enum WebSocketError {
+    /// The connection has been closed, by either side or by the server shutting down.
+    Closed,
+    /// The client sent a message larger than the configured maximum.
+    MessageTooLarge,
+    /// The client broke the protocol, e.g. by sending an unmasked frame.
+    Protocol(&'static str),
+    Io(io::Error),
+}
+impl Display for WebSocketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebSocketError::Closed => write!(f, "WebSocket is closed"),
+            WebSocketError::MessageTooLarge => write!(f, "WebSocket message too large"),
+            WebSocketError::Protocol(message) => write!(f, "WebSocket protocol error: {}", message),
+            WebSocketError::Io(e) => write!(f, "WebSocket error: {}", e),
+        }
+    }
+}
+impl std::error::Error for WebSocketError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WebSocketError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// An open WebSocket connection, handed to a `WebSocketHandler`.
+///
+/// Pings are answered automatically and a close from the client is echoed, as RFC 6455
+/// requires; both are still returned by `recv` for handlers that want to see them.
+/// Fragmented messages are reassembled, so `recv` only returns whole messages.
+///
+/// ```no_run
+/// # use rweblet::HttpListener;
+/// # use rweblet::Request;
+/// # use rweblet::websocket::{Message, WebSocket};
+/// fn echo(_request: &Request, socket: &mut WebSocket) {
+///     while let Ok(message) = socket.recv() {
+///         if let Message::Text(text) = message {
+///             if socket.send_text(&text).is_err() {
+///                 break;
+///             }
+///         }
+///     }
+/// }
+///
+/// let mut listener = HttpListener::new();
+/// listener.websocket("^/echo$", echo);
+/// listener.start("0.0.0.0:8080").unwrap();
+/// ```
+pub struct WebSocket {
+    transport: Box<dyn Transport>,
+    /// Bytes received but not yet parsed into frames.
+    buffer: Vec<u8>,
+    /// Opcode and payload so far of a fragmented message.
+    partial: Option<(u8, Vec<u8>)>,
+    max_message_size: usize,
+    /// A close frame has been sent, so nothing else may be.
+    close_sent: bool,
+    /// The connection is finished and nothing more will be read.
+    closed: bool,
+    server: Option<ServerHandle>,
+}
+
+impl WebSocket {
+    fn new(transport: Box<dyn Transport>, buffered: Vec<u8>, max_message_size: usize, server: Option<ServerHandle>) -> WebSocket {
+        WebSocket {
+            transport,
+            buffer: buffered,
+            partial: None,
+            max_message_size,
+            close_sent: false,
+            closed: false,
+            server,
+        }
+    }
+
+    /// Waits for the next message. Fails with `WebSocketError::Closed` once the
+    /// connection has closed, including when the server shuts down.
+    pub fn recv(&mut self) -> Result<Message, WebSocketError> {
+        loop {
+            if let Some(message) = self.receive(None)? {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Waits up to `timeout` for the next message, returning `None` if none arrived, so
+    /// a handler can push updates between reads.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<Message>, WebSocketError> {
+        self.receive(Some(Instant::now() + timeout))
+    }
+
+    pub fn send(&mut self, message: Message) -> Result<(), WebSocketError> {
+        match message {
+            Message::Text(text) => self.send_frame(OP_TEXT, text.as_bytes()),
+            Message::Binary(data) => self.send_frame(OP_BINARY, &data),
+            Message::Ping(data) => self.send_frame(OP_PING, &data),
+            Message::Pong(data) => self.send_frame(OP_PONG, &data),
+            Message::Close(Some((code, reason))) => self.close(code, &reason),
+            Message::Close(None) => self.close(CLOSE_NORMAL, ""),
+        }
+    }
+    pub fn send_text(&mut self, text: &str) -> Result<(), WebSocketError> {
+        self.send_frame(OP_TEXT, text.as_bytes())
+    }
+    pub fn send_binary(&mut self, data: &[u8]) -> Result<(), WebSocketError> {
+        self.send_frame(OP_BINARY, data)
+    }
+
+    /// Starts closing the connection with a status code and reason. The client's
+    /// answering close is returned by `recv`, after which the socket is closed.
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        let mut payload = code.to_be_bytes().to_vec();
+        //Control frames are limited to 125 bytes, two of which are the code
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+        self.send_frame(OP_CLOSE, &payload)?;
+        self.close_sent = true;
+        Ok(())
+    }
+
+    /// Whether the connection has finished closing.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    fn receive(&mut self, deadline: Option<Instant>) -> Result<Option<Message>, WebSocketError> {
+        let mut read_buffer = [0; 8192];
+        loop {
+            if let Some(message) = self.next_message()? {
+                return Ok(Some(message));
+            }
+            if self.closed {
+                return Err(WebSocketError::Closed);
+            }
+            if self.server.as_ref().is_some_and(|server| server.is_shutting_down()) {
+                return Err(self.fail(CLOSE_GOING_AWAY, WebSocketError::Closed));
+            }
+
+            let wait = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(remaining) if remaining.as_millis() > 0 => remaining.min(POLL_INTERVAL),
+                    _ => return Ok(None),
+                },
+                None => POLL_INTERVAL,
+            };
+            self.transport.set_read_timeout(Some(wait)).map_err(WebSocketError::Io)?;
+            match self.transport.read(&mut read_buffer) {
+                Ok(0) => {
+                    self.closed = true;
+                    return Err(WebSocketError::Closed);
+                },
+                Ok(read_size) => self.buffer.extend_from_slice(&read_buffer[..read_size]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => (),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => {
+                    self.closed = true;
+                    return Err(WebSocketError::Io(e));
+                },
+            }
+        }
+    }
+
+    /// Takes the next message out of the frames received so far.
+    fn next_message(&mut self) -> Result<Option<Message>, WebSocketError> {
+        loop {
+            let (frame, used) = match parse_frame(&self.buffer, self.max_message_size) {
+                Ok(Some(parsed)) => parsed,
+                Ok(None) => return Ok(None),
+                Err(WebSocketError::MessageTooLarge) => return Err(self.fail(CLOSE_TOO_LARGE, WebSocketError::MessageTooLarge)),
+                Err(e) => return Err(self.fail(CLOSE_PROTOCOL_ERROR, e)),
+            };
+            self.buffer.drain(..used);
+
+            match frame.opcode {
+                OP_TEXT | OP_BINARY if self.partial.is_some() => {
+                    return Err(self.fail(CLOSE_PROTOCOL_ERROR, WebSocketError::Protocol("new message before the last one finished")));
+                },
+                OP_TEXT | OP_BINARY if frame.fin => return self.complete(frame.opcode, frame.payload).map(Some),
+                OP_TEXT | OP_BINARY => self.partial = Some((frame.opcode, frame.payload)),
+                OP_CONTINUATION => {
+                    let (opcode, mut payload) = match self.partial.take() {
+                        Some(partial) => partial,
+                        None => return Err(self.fail(CLOSE_PROTOCOL_ERROR, WebSocketError::Protocol("continuation without a message"))),
+                    };
+                    if payload.len() + frame.payload.len() > self.max_message_size {
+                        return Err(self.fail(CLOSE_TOO_LARGE, WebSocketError::MessageTooLarge));
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        return self.complete(opcode, payload).map(Some);
+                    }
+                    self.partial = Some((opcode, payload));
+                },
+                OP_PING => {
+                    //Control frames may arrive between the fragments of a message
+                    self.send_frame(OP_PONG, &frame.payload)?;
+                    return Ok(Some(Message::Ping(frame.payload)));
+                },
+                OP_PONG => return Ok(Some(Message::Pong(frame.payload))),
+                OP_CLOSE => return self.closed_by_client(frame.payload).map(Some),
+                _ => return Err(self.fail(CLOSE_PROTOCOL_ERROR, WebSocketError::Protocol("unknown opcode"))),
+            }
+        }
+    }
+
+    fn complete(&mut self, opcode: u8, payload: Vec<u8>) -> Result<Message, WebSocketError> {
+        if opcode == OP_BINARY {
+            return Ok(Message::Binary(payload));
+        }
+        match String::from_utf8(payload) {
+            Ok(text) => Ok(Message::Text(text)),
+            Err(_) => Err(self.fail(CLOSE_INVALID_DATA, WebSocketError::Protocol("text message is not UTF-8"))),
+        }
+    }
+
+    fn closed_by_client(&mut self, payload: Vec<u8>) -> Result<Message, WebSocketError> {
+        let close = match payload.len() {
+            0 => None,
+            1 => return Err(self.fail(CLOSE_PROTOCOL_ERROR, WebSocketError::Protocol("close frame without a full status code"))),
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                if !valid_close_code(code) {
+                    return Err(self.fail(CLOSE_PROTOCOL_ERROR, WebSocketError::Protocol("invalid close code")));
+                }
+                match String::from_utf8(payload[2..].to_vec()) {
+                    Ok(reason) => Some((code, reason)),
+                    Err(_) => return Err(self.fail(CLOSE_INVALID_DATA, WebSocketError::Protocol("close reason is not UTF-8"))),
+                }
+            },
+        };
+        if !self.close_sent {
+            let code = close.as_ref().map_or(CLOSE_NORMAL, |(code, _)| *code);
+            let _ = self.close(code, "");
+        }
+        self.closed = true;
+        Ok(Message::Close(close))
+    }
+
+    /// Closes the connection after the client broke the protocol, returning `error`.
+    fn fail(&mut self, code: u16, error: WebSocketError) -> WebSocketError {
+        if !self.close_sent && !self.closed {
+            let _ = self.close(code, "");
+        }
+        self.closed = true;
+        error
+    }
+
+    /// Closes a connection the handler is done with, if it is still open.
+    fn finish(&mut self, code: u16) {
+        if !self.close_sent && !self.closed {
+            let _ = self.close(code, "");
+        }
+        self.closed = true;
+    }
+
+    fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), WebSocketError> {
+        if self.close_sent || self.closed {
+            return Err(WebSocketError::Closed);
+        }
+        //Server frames are never masked or fragmented
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(payload);
+        let written = self.transport.write_all(&frame).and_then(|_| self.transport.flush());
+        if let Err(e) = written {
+            self.closed = true;
+            return Err(WebSocketError::Io(e));
+        }
+        Ok(())
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Parses the frame at the start of `buffer`, returning it with its length in bytes, or
+/// `None` until all of it has arrived.
+fn parse_frame(buffer: &[u8], max_payload: usize) -> Result<Option<(Frame, usize)>, WebSocketError> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buffer[0] & 0x80 != 0;
+    if buffer[0] & 0x70 != 0 {
+        return Err(WebSocketError::Protocol("reserved bits set without an extension"));
+    }
+    let opcode = buffer[0] & 0x0F;
+    if buffer[1] & 0x80 == 0 {
+        return Err(WebSocketError::Protocol("client frame is not masked"));
+    }
+
+    let (length, mut offset) = match buffer[1] & 0x7F {
+        126 if buffer.len() >= 4 => (u16::from_be_bytes([buffer[2], buffer[3]]) as u64, 4),
+        127 if buffer.len() >= 10 => {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&buffer[2..10]);
+            (u64::from_be_bytes(bytes), 10)
+        },
+        126 | 127 => return Ok(None),
+        length => (length as u64, 2),
+    };
+    if opcode & 0x08 != 0 && (length > 125 || !fin) {
+        return Err(WebSocketError::Protocol("control frame too long or fragmented"));
+    }
+    if length > max_payload as u64 {
+        return Err(WebSocketError::MessageTooLarge);
+    }
+    let length = length as usize;
+    if buffer.len() < offset + 4 + length {
+        return Ok(None);
+    }
+
+    let mut mask = [0; 4];
+    mask.copy_from_slice(&buffer[offset..offset + 4]);
+    offset += 4;
+    let payload = buffer[offset..offset + length].iter()
+        .enumerate()
+        .map(|(idx, byte)| byte ^ mask[idx % 4])
+        .collect();
+    Ok(Some((Frame { fin, opcode, payload }, offset + length)))
+}
+
+/// Completes the opening handshake for `request` and runs `handler` on the connection
+/// until it returns. `buffered` holds anything the client sent after the request.
+pub(crate) fn serve(mut transport: Box<dyn Transport>, request: Request, buffered: Vec<u8>, handler: WebSocketHandler, settings: &Settings) {
+    let key = match handshake_key(&request) {
+        Ok(key) => key,
+        Err(response) => {
+            let mut context = Context::with_transport(transport, request);
+            context.keep_alive = false;
+            context.write_response(response);
+            return;
+        }
+    };
+    let handshake = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept_key(key));
+    if transport.write_all(handshake.as_bytes()).and_then(|_| transport.flush()).is_err() {
+        HttpListener::log("Failed writing WebSocket handshake");
+        return;
+    }
+
+    let mut socket = WebSocket::new(transport, buffered, settings.websocket_max_message_size, Some(settings.server.clone()));
+    match panic::catch_unwind(AssertUnwindSafe(|| handler(&request, &mut socket))) {
+        Ok(()) => socket.finish(CLOSE_NORMAL),
+        Err(payload) => {
+            HttpListener::log_error(format!("WebSocket handler for {} panicked: {}", request.path, threadpool::panic_message(&payload)).as_str());
+            socket.finish(CLOSE_INTERNAL_ERROR);
+        }
+    }
+}
+
+/// Whether a client may send `code` in a close frame (RFC 6455 section 7.4): the
+/// codes defined for use on the wire, and the ranges for libraries and applications.
+fn valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+/// Checks that `request` asks for a WebSocket this server can speak, returning the
+/// client's key or the response refusing it.
+fn handshake_key(request: &Request) -> Result<&str, Response> {
+    //HTTP/1.0 has no Upgrade, so such a client is told to use HTTP/1.1
+    let is_upgrade = request.version == HttpVersion::Http11
+        && request.header_value("Upgrade").is_some_and(|value| value.trim().eq_ignore_ascii_case("websocket"))
+        && request.header_value("Connection").is_some_and(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case("upgrade")));
+    if !is_upgrade {
+        //The connection closes after this, so it says Connection: close rather than Upgrade
+        return Err(Response::error(HttpResponseType::UpgradeRequired).with_header("Upgrade", "websocket"));
+    }
+    //The opening handshake is always a GET
+    if !matches!(request.method, HttpMethod::GET) {
+        return Err(Response::bad_request());
+    }
+    if request.header_value("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return Err(Response::bad_request().with_header("Sec-WebSocket-Version", "13"));
+    }
+    match request.header_value("Sec-WebSocket-Key").map(str::trim) {
+        Some(key) if !key.is_empty() => Ok(key),
+        _ => Err(Response::bad_request()),
+    }
+}
+
+/// The Sec-WebSocket-Accept value for a client's Sec-WebSocket-Key.
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+/// SHA-1 (FIPS 180-4), needed only for the handshake, where it is not used for security.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (idx, bytes) in block.chunks(4).enumerate() {
+            words[idx] = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for idx in 16..80 {
+            words[idx] = (words[idx - 3] ^ words[idx - 8] ^ words[idx - 14] ^ words[idx - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (idx, word) in words.iter().enumerate() {
+            let (f, k) = match idx {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let next = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = next;
+        }
+        for (value, add) in state.iter_mut().zip([a, b, c, d, e].iter()) {
+            *value = value.wrapping_add(*add);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, value) in digest.chunks_mut(4).zip(state.iter()) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+/// Standard base64 with padding.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for idx in 0..4 {
+            if idx <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * idx) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    #[test]
+    fn test_accept_key() {
+        //The example from RFC 6455 section 1.3
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"a"), "YQ==");
+    }
+
+    /// A client frame, masked as clients must.
+    fn frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![first];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(idx, byte)| byte ^ mask[idx % 4]));
+        frame
+    }
+
+    fn echo(request: &Request, socket: &mut WebSocket) {
+        socket.send_text(&request.path).unwrap();
+        while let Ok(message) = socket.recv() {
+            match message {
+                Message::Text(text) => socket.send_text(&text).unwrap(),
+                Message::Binary(data) => socket.send_binary(&data).unwrap(),
+                _ => (),
+            }
+        }
+    }
+
+    fn connect(max_message_size: usize) -> (crate::ServerHandle, TcpStream) {
+        let mut listener = HttpListener::new();
+        listener.websocket("^/echo", echo);
+        listener.websocket_max_message_size(max_message_size);
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(b"GET /echo HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+
+        let mut head = Vec::new();
+        let mut byte = [0; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            client.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101"));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert_eq!(read_frame(&mut client), (OP_TEXT, b"/echo".to_vec()));
+        (handle, client)
+    }
+
+    /// Reads one unmasked server frame.
+    fn read_frame(client: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        client.read_exact(&mut head).unwrap();
+        assert_eq!(head[1] & 0x80, 0);
+        let length = match head[1] {
+            126 => {
+                let mut length = [0; 2];
+                client.read_exact(&mut length).unwrap();
+                u16::from_be_bytes(length) as usize
+            },
+            length => length as usize,
+        };
+        let mut payload = vec![0; length];
+        client.read_exact(&mut payload).unwrap();
+        (head[0] & 0x0F, payload)
+    }
+
+    #[test]
+    fn test_echo_fragments_and_ping() {
+        let (handle, mut client) = connect(DEFAULT_MAX_MESSAGE_SIZE);
+        client.write_all(&frame(0x81, b"hello")).unwrap();
+        assert_eq!(read_frame(&mut client), (OP_TEXT, b"hello".to_vec()));
+
+        //A fragmented message with a ping between its fragments
+        client.write_all(&frame(0x02, b"ab")).unwrap();
+        client.write_all(&frame(0x89, b"are you there")).unwrap();
+        client.write_all(&frame(0x80, b"cd")).unwrap();
+        assert_eq!(read_frame(&mut client), (OP_PONG, b"are you there".to_vec()));
+        assert_eq!(read_frame(&mut client), (OP_BINARY, b"abcd".to_vec()));
+
+        let long = vec![b'x'; 300];
+        client.write_all(&frame(0x81, &long)).unwrap();
+        assert_eq!(read_frame(&mut client), (OP_TEXT, long));
+
+        client.write_all(&frame(0x88, &1000u16.to_be_bytes())).unwrap();
+        assert_eq!(read_frame(&mut client), (OP_CLOSE, 1000u16.to_be_bytes().to_vec()));
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_message_too_large_closes() {
+        let (handle, mut client) = connect(16);
+        client.write_all(&frame(0x01, b"0123456789")).unwrap();
+        client.write_all(&frame(0x80, b"0123456789")).unwrap();
+        let (opcode, payload) = read_frame(&mut client);
+        assert_eq!(opcode, OP_CLOSE);
+        assert_eq!(payload, CLOSE_TOO_LARGE.to_be_bytes().to_vec());
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_unmasked_frame_is_a_protocol_error() {
+        let (handle, mut client) = connect(DEFAULT_MAX_MESSAGE_SIZE);
+        client.write_all(&[0x81, 0x02, b'h', b'i']).unwrap();
+        assert_eq!(read_frame(&mut client), (OP_CLOSE, CLOSE_PROTOCOL_ERROR.to_be_bytes().to_vec()));
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_invalid_close_frames_fail() {
+        //Reserved codes are never echoed
+        for code in &[999u16, 1005, 1006, 1016, 2999, 5000] {
+            let (handle, mut client) = connect(DEFAULT_MAX_MESSAGE_SIZE);
+            client.write_all(&frame(0x88, &code.to_be_bytes())).unwrap();
+            assert_eq!(read_frame(&mut client), (OP_CLOSE, CLOSE_PROTOCOL_ERROR.to_be_bytes().to_vec()));
+            handle.shutdown();
+        }
+
+        let (handle, mut client) = connect(DEFAULT_MAX_MESSAGE_SIZE);
+        let mut payload = 1000u16.to_be_bytes().to_vec();
+        payload.extend_from_slice(&[0xC3, 0x28]);
+        client.write_all(&frame(0x88, &payload)).unwrap();
+        assert_eq!(read_frame(&mut client), (OP_CLOSE, CLOSE_INVALID_DATA.to_be_bytes().to_vec()));
+        handle.shutdown();
+
+        let (handle, mut client) = connect(DEFAULT_MAX_MESSAGE_SIZE);
+        client.write_all(&frame(0x88, &4000u16.to_be_bytes())).unwrap();
+        assert_eq!(read_frame(&mut client), (OP_CLOSE, 4000u16.to_be_bytes().to_vec()));
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_shutdown_closes_open_sockets() {
+        let (handle, mut client) = connect(DEFAULT_MAX_MESSAGE_SIZE);
+        handle.shutdown();
+        assert_eq!(read_frame(&mut client), (OP_CLOSE, CLOSE_GOING_AWAY.to_be_bytes().to_vec()));
+    }
+
+    #[test]
+    fn test_plain_request_needs_upgrade() {
+        let mut listener = HttpListener::new();
+        listener.websocket("^/echo", echo);
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+        client.write_all(b"GET /echo HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 426"));
+        assert!(response.contains("Upgrade: websocket\r\n"));
+        assert_eq!(response.matches("Connection:").count(), 1);
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_upgrade_requires_get() {
+        let mut listener = HttpListener::new();
+        listener.websocket("^/echo", echo);
+        let handle = listener.spawn("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+        client.write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400"));
+        handle.shutdown();
+    }
+}